@@ -0,0 +1,105 @@
+//! `#[derive(Record)]` for ozone: maps a struct with named fields to a table
+//! whose columns are its fields, in declaration order.
+//!
+//! The table is named after the struct in snake case unless the struct carries
+//! `#[ozone(table = "name")]`. The primary key is the field marked
+//! `#[ozone(primary)]`, or the first field when none is.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr, Result};
+
+#[proc_macro_derive(Record, attributes(ozone))]
+pub fn derive_record(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => return Err(Error::new_spanned(&input.ident, "Record needs a struct with named fields")),
+        },
+        _ => return Err(Error::new_spanned(&input.ident, "Record can only be derived for structs")),
+    };
+    if fields.is_empty() {
+        return Err(Error::new_spanned(&input.ident, "Record needs at least one field"));
+    }
+
+    let mut table = snake_case(&input.ident.to_string());
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("ozone")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `table = \"...\"`"))
+            }
+        })?;
+    }
+
+    let mut primary = None;
+    for field in fields {
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("ozone")) {
+            attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("primary") {
+                    return Err(meta.error("expected `primary`"));
+                }
+                if primary.is_some() {
+                    return Err(meta.error("only one field can be the primary key"));
+                }
+                primary = field.ident.clone();
+                Ok(())
+            })?;
+        }
+    }
+
+    let name = &input.ident;
+    let idents = fields.iter().map(|field| field.ident.clone().unwrap()).collect::<Vec<_>>();
+    let columns = idents.iter().map(|ident| ident.to_string()).collect::<Vec<_>>();
+    let types = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+    let primary = primary.unwrap_or_else(|| idents[0].clone()).to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::ozone::Record for #name #ty_generics #where_clause {
+            fn table_name() -> &'static str {
+                #table
+            }
+
+            fn schema() -> ::ozone::Schema {
+                let mut schema = ::ozone::Schema::new();
+                #(schema.column(#columns, <#types as ::ozone::ColumnValue>::column_type());)*
+                schema.primary(#primary);
+                schema
+            }
+
+            fn to_values(&self) -> ::std::vec::Vec<::ozone::Value> {
+                vec![#(::ozone::ColumnValue::into_value(::std::clone::Clone::clone(&self.#idents))),*]
+            }
+
+            fn from_values(values: ::std::vec::Vec<::ozone::Value>) -> ::std::io::Result<Self> {
+                let mut values = values.into_iter();
+                let mut next = || values.next().ok_or_else(|| {
+                    ::std::io::Error::new(::std::io::ErrorKind::InvalidData, "row has too few values")
+                });
+                Ok(#name {
+                    #(#idents: ::ozone::ColumnValue::from_value(next()?)?,)*
+                })
+            }
+        }
+    })
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
@@ -0,0 +1,41 @@
+
+pub enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+pub struct WriteBatch<K, V> {
+    ops: Vec<Op<K, V>>,
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.ops.push(Op::Insert(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.ops.push(Op::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear()
+    }
+
+    pub fn into_ops(self) -> Vec<Op<K, V>> {
+        self.ops
+    }
+}
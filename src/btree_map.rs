@@ -0,0 +1,542 @@
+use std::io::{Result, Error, ErrorKind};
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::{fs, mem, cmp, fmt};
+
+use buffer::{Buffer, AnonymousBuffer, FileBuffer};
+use table::Page;
+use table::btree::{self, BTree, MIN_ORDER};
+
+const INITIAL_PAGES: usize = 4;
+
+// An ordered map kept in a single B+ tree over the buffer. Blocks are about a page each and are
+// addressed by index, so the buffer doubles in place whenever the tree runs out of them
+pub struct BTreeMap<K, V, B = AnonymousBuffer<Page>>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    buffer: B,
+    phantom_k: PhantomData<K>,
+    phantom_v: PhantomData<V>,
+}
+
+pub enum Entry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    Occupied(OccupiedEntry<'a, K, V, B>),
+    Vacant(VacantEntry<'a, K, V, B>),
+}
+
+pub struct OccupiedEntry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    map: &'a mut BTreeMap<K, V, B>,
+    key: K,
+}
+
+pub struct VacantEntry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    map: &'a mut BTreeMap<K, V, B>,
+    key: K,
+}
+
+impl<'a, K, V, B> Entry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default)
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default())
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry)
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key()
+        }
+    }
+}
+
+impl<'a, K, V, B> OccupiedEntry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        self.map.remove_entry(&self.key).unwrap()
+    }
+
+    pub fn get(&self) -> &V {
+        self.map.get(&self.key).unwrap()
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.get_mut(&self.key).unwrap()
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.get_mut(&self.key).unwrap()
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+}
+
+impl<'a, K, V, B> VacantEntry<'a, K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: 'a + Buffer<Page>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        self.map.insert(self.key, value);
+        self.map.get_mut(&self.key).unwrap()
+    }
+}
+
+impl<K, V> BTreeMap<K, V, AnonymousBuffer<Page>>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    pub fn new() -> Self {
+        Self::try_new().unwrap()
+    }
+
+    pub fn try_new() -> Result<Self> {
+        let buffer = AnonymousBuffer::try_new(INITIAL_PAGES * mem::size_of::<Page>())?;
+        Self::try_create_in(buffer)
+    }
+}
+
+impl<K, V> BTreeMap<K, V, FileBuffer<Page>>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    pub fn new<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        Self::try_new(path).unwrap()
+    }
+
+    // Refuses to replace a file that already holds data; open it instead
+    pub fn try_new<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        if let Ok(ref metadata) = fs::metadata(path.as_ref()) {
+            if metadata.len() > 0 {
+                return Err(Error::new(ErrorKind::AlreadyExists, "refusing to overwrite an existing file, open it instead"));
+            }
+        }
+        let buffer = FileBuffer::try_new(path, INITIAL_PAGES * mem::size_of::<Page>())?;
+        Self::try_create_in(buffer)
+    }
+
+    pub fn open<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        Self::try_open(path).unwrap()
+    }
+
+    pub fn try_open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let len = fs::metadata(path.as_ref())?.len() as usize;
+        let buffer = FileBuffer::try_new(path, len)?;
+        Self::try_open_in(buffer)
+    }
+}
+
+impl<K, V, B> BTreeMap<K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    // Start an empty map over the buffer, discarding whatever it held
+    pub fn try_create_in(mut buffer: B) -> Result<Self> {
+        let order = cmp::max(BTree::<K, V>::max_order::<Page>(), MIN_ORDER);
+        if buffer.len() < INITIAL_PAGES * mem::size_of::<Page>() {
+            buffer.resize(INITIAL_PAGES * mem::size_of::<Page>())?;
+        }
+        let len = buffer.len();
+        BTree::<K, V>::create_region(&mut buffer[0] as *mut Page as *mut u8, len, order);
+        Ok(BTreeMap { buffer: buffer, phantom_k: PhantomData, phantom_v: PhantomData })
+    }
+
+    // Reattach to a map previously written to the buffer, walking every block to check it first
+    pub fn try_open_in(mut buffer: B) -> Result<Self> {
+        if buffer.len() < mem::size_of::<Page>() {
+            return Err(Error::new(ErrorKind::InvalidData, "file is not a b-tree map"));
+        }
+        let len = buffer.len();
+        BTree::<K, V>::load_region(&mut buffer[0] as *mut Page as *mut u8, len)?;
+        let map = BTreeMap { buffer: buffer, phantom_k: PhantomData, phantom_v: PhantomData };
+        if !map.tree().validate() {
+            return Err(Error::new(ErrorKind::InvalidData, "b-tree map is corrupt"));
+        }
+        Ok(map)
+    }
+
+    fn tree(&self) -> &BTree<K, V> {
+        unsafe { &*(&self.buffer[0] as *const Page as *const BTree<K, V>) }
+    }
+
+    fn tree_mut(&mut self) -> &mut BTree<K, V> {
+        unsafe { &mut*(&mut self.buffer[0] as *mut Page as *mut BTree<K, V>) }
+    }
+
+    // Double the buffer and hand the new blocks to the tree
+    fn grow(&mut self) -> Result<()> {
+        let len = self.buffer.len() * 2;
+        self.buffer.resize(len)?;
+        self.tree_mut().grow(len);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.try_insert(key, value).unwrap()
+    }
+
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        if let Some(old) = self.get_mut(&key) {
+            return Ok(Some(mem::replace(old, value)));
+        }
+        while !self.tree_mut().insert(key, value) {
+            self.grow()?;
+        }
+        Ok(None)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.tree().find(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.tree_mut().find_mut(key)
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree().floor(key).filter(|&(k, _)| k == key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    pub fn remove_entry(&mut self, key: &K) -> Option<(K, V)> {
+        let (&key, &value) = self.get_key_value(key)?;
+        self.tree_mut().delete(&key);
+        Some((key, value))
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, B> {
+        if self.contains_key(&key) {
+            Entry::Occupied(OccupiedEntry { map: self, key: key })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key: key })
+        }
+    }
+
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.tree().first()
+    }
+
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.tree().last()
+    }
+
+    // Greatest entry at or below key
+    pub fn floor(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree().floor(key)
+    }
+
+    // Least entry at or above key
+    pub fn ceiling(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree().ceiling(key)
+    }
+
+    // Greatest entry strictly below key
+    pub fn predecessor(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree().predecessor(key)
+    }
+
+    // Least entry strictly above key
+    pub fn successor(&self, key: &K) -> Option<(&K, &V)> {
+        self.tree().successor(key)
+    }
+
+    // Entry at position i in key order, found without walking the entries before it
+    pub fn nth(&self, i: usize) -> Option<(&K, &V)> {
+        self.tree().nth(i)
+    }
+
+    // Number of entries below key
+    pub fn rank(&self, key: &K) -> usize {
+        self.tree().rank(key)
+    }
+
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.tree_mut().pop_front()
+    }
+
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.tree_mut().pop_back()
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V> {
+        Range { range: self.tree().range(range) }
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V> {
+        Range { range: self.tree().iter() }
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { range: self.tree().iter() }
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { range: self.tree().iter() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree().is_empty()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.buffer.flush()
+    }
+}
+
+pub struct Range<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    range: btree::Range<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back()
+    }
+}
+
+pub struct Keys<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    range: btree::Range<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|(key, _)| key)
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back().map(|(key, _)| key)
+    }
+}
+
+pub struct Values<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    range: btree::Range<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator for Values<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|(_, value)| value)
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back().map(|(_, value)| value)
+    }
+}
+
+impl<K, V, B> fmt::Debug for BTreeMap<K, V, B>
+    where K: 'static + Ord + Copy + Sized + fmt::Debug,
+          V: 'static + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[test]
+fn insert_and_get_btreemap() {
+    let mut m: BTreeMap<u64, u64> = BTreeMap::<u64, u64>::new();
+    assert_eq!(None, m.insert(1, 10));
+    assert_eq!(Some(10), m.insert(1, 11));
+    assert_eq!(Some(&11), m.get(&1));
+    assert_eq!(None, m.get(&2));
+    assert_eq!(1, m.len());
+    assert_eq!("{1: 11}", format!("{:?}", m));
+}
+
+#[test]
+fn entry_and_remove_btreemap() {
+    let mut m: BTreeMap<u32, u32> = BTreeMap::<u32, u32>::new();
+    for k in 0..100 {
+        *m.entry(k % 10).or_insert(0) += 1;
+    }
+    m.entry(3).and_modify(|v| *v = 0).or_insert(7);
+    assert_eq!(Some(&0), m.get(&3));
+    if let Entry::Occupied(entry) = m.entry(4) {
+        assert_eq!((4, 10), entry.remove_entry());
+    }
+    assert_eq!(None, m.remove(&4));
+    assert_eq!(Some(10), m.remove(&5));
+    assert_eq!(vec![0, 1, 2, 3, 6, 7, 8, 9], m.keys().cloned().collect::<Vec<_>>());
+}
+
+#[test]
+fn grow_and_compare_btreemap() {
+    use rand::{Rng, StdRng};
+    let mut rng = StdRng::new().unwrap();
+    let mut m: BTreeMap<u64, [u64; 4]> = BTreeMap::<u64, [u64; 4]>::new();
+    let mut expected = ::std::collections::BTreeMap::new();
+    for _ in 0..20000 {
+        let key = rng.gen_range(0, 5000);
+        if rng.gen_range(0, 3) == 0 {
+            assert_eq!(expected.remove(&key), m.remove(&key));
+        } else {
+            assert_eq!(expected.insert(key, [key; 4]), m.insert(key, [key; 4]));
+        }
+    }
+    assert!(m.tree().validate());
+    assert_eq!(expected.len(), m.len());
+    assert!(expected.iter().eq(m.iter()));
+    assert!(expected.range(100..=900).rev().eq(m.range(100..=900).rev()));
+    assert_eq!(expected.iter().next(), m.first_key_value());
+    assert_eq!(expected.iter().next_back(), m.last_key_value());
+    for &key in &[0, 1, 2500, 4999, 5000] {
+        assert_eq!(expected.range(..=key).next_back(), m.floor(&key));
+        assert_eq!(expected.range(key..).next(), m.ceiling(&key));
+        assert_eq!(expected.range(..key).next_back(), m.predecessor(&key));
+        assert_eq!(expected.range(key + 1..).next(), m.successor(&key));
+        assert_eq!(expected.range(..key).count(), m.rank(&key));
+        assert_eq!(expected.iter().nth(key as usize / 2), m.nth(key as usize / 2));
+    }
+    assert_eq!(None, m.nth(m.len()));
+    assert_eq!(expected.pop_first(), m.pop_first());
+    assert_eq!(expected.pop_last(), m.pop_last());
+}
+
+#[test]
+fn reopen_file_btreemap() {
+    {
+        let mut m = BTreeMap::<u64, u64, FileBuffer<Page>>::new("test_btreemap.db");
+        for k in 0..3000 {
+            m.insert(k, k * 2);
+        }
+        m.flush().unwrap();
+    }
+    let m = BTreeMap::<u64, u64, FileBuffer<Page>>::open("test_btreemap.db");
+    assert_eq!(3000, m.len());
+    assert_eq!(Some(&1000), m.get(&500));
+    assert_eq!(vec![5990, 5992, 5994, 5996, 5998], m.range(2995..).map(|(_, v)| *v).collect::<Vec<_>>());
+    assert_eq!(ErrorKind::AlreadyExists, BTreeMap::<u64, u64, FileBuffer<Page>>::try_new("test_btreemap.db").err().unwrap().kind());
+    assert_eq!(3000, BTreeMap::<u64, u64, FileBuffer<Page>>::open("test_btreemap.db").len());
+
+    // Scribble over about one block in the middle of the file, leaving the header intact
+    let mut bytes = fs::read("test_btreemap.db").unwrap();
+    let middle = bytes.len() / 2;
+    for byte in &mut bytes[middle..middle + mem::size_of::<Page>()] {
+        *byte = 0xFF;
+    }
+    fs::write("test_btreemap.db", &bytes).unwrap();
+    let err = BTreeMap::<u64, u64, FileBuffer<Page>>::try_open("test_btreemap.db").err().unwrap();
+    assert_eq!(ErrorKind::InvalidData, err.kind());
+    fs::write("test_btreemap.db", [0u8; 4096]).unwrap();
+    assert!(BTreeMap::<u64, u64, FileBuffer<Page>>::try_open("test_btreemap.db").is_err());
+    fs::remove_file("test_btreemap.db").unwrap();
+}
@@ -0,0 +1,244 @@
+use std::io::Result;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::{fmt};
+
+use btree_map::{self, BTreeMap};
+use buffer::{Buffer, AnonymousBuffer, FileBuffer};
+use table::Page;
+
+pub struct BTreeSet<T, B = AnonymousBuffer<Page>>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    map: BTreeMap<T, (), B>
+}
+
+impl<T> BTreeSet<T, AnonymousBuffer<Page>>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    pub fn new() -> Self {
+        let map = BTreeMap::<T, (), AnonymousBuffer<Page>>::new();
+        Self { map: map }
+    }
+
+    pub fn try_new() -> Result<Self> {
+        let map = BTreeMap::<T, (), AnonymousBuffer<Page>>::try_new()?;
+        Ok(Self { map: map })
+    }
+}
+
+impl<T> BTreeSet<T, FileBuffer<Page>>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    pub fn new<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        let map = BTreeMap::<T, (), FileBuffer<Page>>::new(path);
+        Self { map: map }
+    }
+
+    pub fn try_new<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let map = BTreeMap::<T, (), FileBuffer<Page>>::try_new(path)?;
+        Ok(Self { map: map })
+    }
+
+    pub fn open<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        let map = BTreeMap::<T, (), FileBuffer<Page>>::open(path);
+        Self { map: map }
+    }
+
+    pub fn try_open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let map = BTreeMap::<T, (), FileBuffer<Page>>::try_open(path)?;
+        Ok(Self { map: map })
+    }
+}
+
+impl<T, B> BTreeSet<T, B>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    pub fn try_create_in(buffer: B) -> Result<Self> {
+        let map = BTreeMap::<T, (), B>::try_create_in(buffer)?;
+        Ok(Self { map: map })
+    }
+
+    pub fn try_open_in(buffer: B) -> Result<Self> {
+        let map = BTreeMap::<T, (), B>::try_open_in(buffer)?;
+        Ok(Self { map: map })
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { keys: self.map.keys() }
+    }
+
+    pub fn range<R: RangeBounds<T>>(&self, range: R) -> Range<'_, T> {
+        Range { range: self.map.range(range) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.map.contains_key(value)
+    }
+
+    pub fn get(&self, value: &T) -> Option<&T> {
+        self.map.get_key_value(value).map(|(value, _)| value)
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.map.first_key_value().map(|(value, _)| value)
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.map.last_key_value().map(|(value, _)| value)
+    }
+
+    pub fn floor(&self, value: &T) -> Option<&T> {
+        self.map.floor(value).map(|(value, _)| value)
+    }
+
+    pub fn ceiling(&self, value: &T) -> Option<&T> {
+        self.map.ceiling(value).map(|(value, _)| value)
+    }
+
+    pub fn predecessor(&self, value: &T) -> Option<&T> {
+        self.map.predecessor(value).map(|(value, _)| value)
+    }
+
+    pub fn successor(&self, value: &T) -> Option<&T> {
+        self.map.successor(value).map(|(value, _)| value)
+    }
+
+    pub fn nth(&self, i: usize) -> Option<&T> {
+        self.map.nth(i).map(|(value, _)| value)
+    }
+
+    pub fn rank(&self, value: &T) -> usize {
+        self.map.rank(value)
+    }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        self.map.pop_first().map(|(value, _)| value)
+    }
+
+    pub fn pop_last(&mut self) -> Option<T> {
+        self.map.pop_last().map(|(value, _)| value)
+    }
+
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    pub fn remove(&mut self, value: &T) -> bool {
+        self.map.remove(value).is_some()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.map.flush()
+    }
+}
+
+pub struct Iter<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    keys: btree_map::Keys<'a, T, ()>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.keys.next()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Iter<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.keys.next_back()
+    }
+}
+
+pub struct Range<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    range: btree_map::Range<'a, T, ()>,
+}
+
+impl<'a, T> Iterator for Range<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|(value, _)| value)
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Range<'a, T>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back().map(|(value, _)| value)
+    }
+}
+
+impl<T, B> fmt::Debug for BTreeSet<T, B>
+    where T: 'static + Ord + Copy + Sized + fmt::Debug,
+          B: Buffer<Page>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[test]
+fn insert_remove_and_reopen_btreeset() {
+    use std::fs;
+    {
+        let mut s = BTreeSet::<u32, FileBuffer<Page>>::new("test_btreeset.db");
+        for v in (0..2000).rev() {
+            assert!(s.insert(v * 3));
+        }
+        assert!(!s.insert(300));
+        assert!(s.contains(&300));
+        assert!(!s.contains(&301));
+        assert!(s.remove(&300));
+        assert!(!s.remove(&300));
+        assert!(!s.contains(&300));
+        assert_eq!(vec![297, 303, 306], s.range(296..307).cloned().collect::<Vec<_>>());
+        assert_eq!(vec![306, 303, 297], s.range(296..307).rev().cloned().collect::<Vec<_>>());
+        s.flush().unwrap();
+    }
+    let s = BTreeSet::<u32, FileBuffer<Page>>::open("test_btreeset.db");
+    assert_eq!(1999, s.len());
+    assert!(!s.contains(&300));
+    assert_eq!((Some(&0), Some(&5997)), (s.first(), s.last()));
+    assert_eq!(vec![5991, 5994, 5997], s.range(5990..).cloned().collect::<Vec<_>>());
+    assert_eq!((Some(&297), Some(&303)), (s.floor(&300), s.ceiling(&300)));
+    assert_eq!((Some(&294), Some(&303)), (s.predecessor(&297), s.successor(&297)));
+    assert_eq!((Some(&303), 100), (s.nth(100), s.rank(&303)));
+    fs::remove_file("test_btreeset.db").unwrap();
+
+    let mut small = BTreeSet::<u8>::new();
+    small.insert(6);
+    small.insert(0);
+    small.insert(3);
+    assert_eq!("{0, 3, 6}", format!("{:?}", small));
+}
@@ -11,6 +11,9 @@ pub trait Buffer<T>: Index<usize, Output = T> + IndexMut<usize> + Clone + Sized
 {
     fn new_sized(&self, usize) -> Result<Self>;
     fn resize(&mut self, usize) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+    fn len(&self) -> usize;
+    fn path(&self) -> Option<&Path>;
 }
 
 pub struct AnonymousBuffer<T>
@@ -90,6 +93,18 @@ impl<T> Buffer<T> for AnonymousBuffer<T>
         self.data = Arc::new(Mutex::new(new_map));
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        None
+    }
 }
 
 pub struct FileBuffer<T>
@@ -178,6 +193,18 @@ impl<T> Buffer<T> for FileBuffer<T>
         self.data = Arc::new(Mutex::new(map));
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        self.data.lock().unwrap().flush()
+    }
+
+    fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
 }
 
 #[test]
@@ -0,0 +1,420 @@
+
+use std::io::{Result, Error, ErrorKind};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+use std::sync::{Arc, Mutex};
+use std::{cmp, mem, slice};
+
+use buffer::{Buffer, AnonymousBuffer};
+
+const PAGE_SIZE: usize = 4096; // Unit in which the device writes back
+const MAX_REORDERED_PAGES: usize = 8; // Cap on the subsets enumerated by crash_modes
+
+/* Crash Model
+ * ------------
+ * Writes land in volatile memory. A flush is a sync: every page that differs
+ * from the durable image becomes durable. Between two syncs the device may
+ * write pages back in any order, and the page being written when the power
+ * fails may be torn. The write-ahead log beside the path, if any, is captured
+ * as it stood at the crash; its records only ever reach it through fsync.
+ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Crash {
+    DropUnflushed, // Only what was synced survives
+    KeepUnflushed, // Every write reached the device before the power failed
+    TearPage(usize), // Unflushed pages before n survive, page n is half written
+    Reorder(u64), // Unflushed page i survives if bit i is set
+}
+
+pub struct CrashTestBuffer<T>
+    where T: Sized
+{
+    path: Option<PathBuf>,
+    disk: Arc<Mutex<Disk>>,
+    phantom: PhantomData<T>,
+}
+
+// Shared by every clone of a buffer, so each one sees the others' writes and resizes
+struct Disk {
+    live: AnonymousBuffer<u8>,
+    synced: Vec<u8>,
+    syncs_left: Option<usize>,
+    crashed: Option<Image>,
+}
+
+// Everything the machine held at the moment the power failed
+#[derive(Clone)]
+struct Image {
+    synced: Vec<u8>,
+    written: Vec<u8>,
+    log: Option<Vec<u8>>,
+}
+
+impl<T> CrashTestBuffer<T>
+    where T: Sized
+{
+    pub fn try_new(size: usize) -> Result<Self> {
+        Self::try_create(None, size)
+    }
+
+    // The path only names the write-ahead log; the image itself stays in memory
+    pub fn try_with_path<P>(path: P, size: usize) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        Self::try_create(Some(path.as_ref().to_owned()), size)
+    }
+
+    // Let `syncs` more flushes succeed, then lose power during the next one
+    pub fn crash_after(&self, syncs: usize) {
+        self.disk.lock().unwrap().syncs_left = Some(syncs);
+    }
+
+    // Lose power now
+    pub fn crash(&self) {
+        let mut disk = self.disk.lock().unwrap();
+        if disk.crashed.is_none() {
+            let image = self.capture(&disk);
+            disk.crashed = Some(image);
+        }
+    }
+
+    pub fn has_crashed(&self) -> bool {
+        self.disk.lock().unwrap().crashed.is_some()
+    }
+
+    pub fn unflushed_pages(&self) -> usize {
+        let image = self.image();
+        Self::dirty_pages(&image).len()
+    }
+
+    // Every surviving image worth trying, bounded for large write sets
+    pub fn crash_modes(&self) -> Vec<Crash> {
+        let unflushed = self.unflushed_pages();
+        let mut modes = vec![Crash::DropUnflushed, Crash::KeepUnflushed];
+        modes.extend((0..unflushed).map(Crash::TearPage));
+        let reordered = cmp::min(unflushed, MAX_REORDERED_PAGES);
+        modes.extend((0..(1u64 << reordered)).map(Crash::Reorder));
+        modes
+    }
+
+    // Power back on: a fresh buffer over what the device holds after `crash`
+    pub fn reboot(&self, crash: Crash) -> Result<Self> {
+        let image = self.image();
+        let dirty = Self::dirty_pages(&image);
+        let mut bytes = image.synced.clone();
+        bytes.resize(image.written.len(), 0);
+
+        for (i, &page) in dirty.iter().enumerate() {
+            let start = page * PAGE_SIZE;
+            let end = cmp::min(start + PAGE_SIZE, bytes.len());
+            let end = match crash {
+                Crash::DropUnflushed => continue,
+                Crash::KeepUnflushed => end,
+                Crash::TearPage(n) if i < n => end,
+                Crash::TearPage(n) if i == n => start + (end - start) / 2,
+                Crash::TearPage(_) => break,
+                Crash::Reorder(mask) if i < 64 && mask & (1 << i) != 0 => end,
+                Crash::Reorder(_) => continue,
+            };
+            bytes[start..end].copy_from_slice(&image.written[start..end]);
+        }
+
+        if let (Some(path), Some(log)) = (self.log_path(), image.log) {
+            fs::write(path, log)?;
+        }
+        let buffer = Self::try_create(self.path.clone(), bytes.len())?;
+        {
+            let mut disk = buffer.disk.lock().unwrap();
+            bytes_mut(&mut disk.live).copy_from_slice(&bytes);
+            disk.synced = bytes;
+        }
+        Ok(buffer)
+    }
+
+    fn try_create(path: Option<PathBuf>, size: usize) -> Result<Self> {
+        let disk = Disk { live: AnonymousBuffer::try_new(size)?, synced: vec![0; size], syncs_left: None, crashed: None };
+        Ok(CrashTestBuffer {
+            path: path,
+            disk: Arc::new(Mutex::new(disk)),
+            phantom: PhantomData,
+        })
+    }
+
+    fn image(&self) -> Image {
+        let disk = self.disk.lock().unwrap();
+        match disk.crashed {
+            Some(ref image) => image.clone(),
+            None => self.capture(&disk),
+        }
+    }
+
+    fn capture(&self, disk: &Disk) -> Image {
+        let log = self.log_path().map(|path| fs::read(path).unwrap_or_default());
+        Image { synced: disk.synced.clone(), written: bytes(&disk.live).to_vec(), log: log }
+    }
+
+    fn dirty_pages(image: &Image) -> Vec<usize> {
+        let len = image.written.len();
+        (0..len.div_ceil(PAGE_SIZE)).filter(|&page| {
+            let start = page * PAGE_SIZE;
+            let end = cmp::min(start + PAGE_SIZE, len);
+            (start..end).any(|i| image.synced.get(i).cloned().unwrap_or(0) != image.written[i])
+        }).collect()
+    }
+
+    fn log_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| {
+            let mut log_path = path.as_os_str().to_owned();
+            log_path.push(".wal");
+            PathBuf::from(log_path)
+        })
+    }
+
+    // Like the buffers over a file, an element stays where it is only until the next resize
+    fn element(&self, idx: usize) -> *mut T {
+        let mut disk = self.disk.lock().unwrap();
+        assert!((idx + 1) * mem::size_of::<T>() <= disk.live.len(), "index out of bounds");
+        unsafe { (bytes_mut(&mut disk.live).as_mut_ptr() as *mut T).add(idx) }
+    }
+}
+
+fn bytes(live: &AnonymousBuffer<u8>) -> &[u8] {
+    let len = live.len();
+    if len == 0 {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(&live[0] as *const u8, len) }
+}
+
+fn bytes_mut(live: &mut AnonymousBuffer<u8>) -> &mut [u8] {
+    let len = live.len();
+    if len == 0 {
+        return &mut [];
+    }
+    unsafe { slice::from_raw_parts_mut(&mut live[0] as *mut u8, len) }
+}
+
+impl<T> Index<usize> for CrashTestBuffer<T>
+    where T: Sized
+{
+    type Output = T;
+
+    fn index(&self, idx: usize) -> &Self::Output {
+        unsafe { &*self.element(idx) }
+    }
+}
+
+impl<T> IndexMut<usize> for CrashTestBuffer<T>
+    where T: Sized
+{
+    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
+        unsafe { &mut *self.element(idx) }
+    }
+}
+
+impl<T> Clone for CrashTestBuffer<T>
+    where T: Sized
+{
+    fn clone(&self) -> Self {
+        CrashTestBuffer { path: self.path.clone(), disk: self.disk.clone(), phantom: PhantomData }
+    }
+}
+
+impl<T> Buffer<T> for CrashTestBuffer<T>
+    where T: Sized
+{
+    // Same device, new length, like reopening a file
+    fn new_sized(&self, size: usize) -> Result<Self> {
+        let mut buffer = self.clone();
+        buffer.resize(size)?;
+        Ok(buffer)
+    }
+
+    fn resize(&mut self, size: usize) -> Result<()> {
+        self.disk.lock().unwrap().live.resize(size)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut disk = self.disk.lock().unwrap();
+        if disk.crashed.is_some() {
+            return Err(Error::new(ErrorKind::Other, "simulated crash"));
+        }
+        match disk.syncs_left {
+            Some(0) => {
+                let image = self.capture(&disk);
+                disk.crashed = Some(image);
+                return Err(Error::new(ErrorKind::Other, "simulated crash"));
+            }
+            Some(n) => disk.syncs_left = Some(n - 1),
+            None => {}
+        }
+        disk.synced = bytes(&disk.live).to_vec();
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.disk.lock().unwrap().live.len()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+#[test]
+fn drop_unflushed_writes() {
+    let mut buffer = CrashTestBuffer::<u8>::try_new(2 * PAGE_SIZE).unwrap();
+    buffer[0] = 1;
+    buffer.flush().unwrap();
+    buffer[0] = 2;
+    buffer[PAGE_SIZE] = 3;
+    buffer.crash();
+    assert_eq!(2, buffer.unflushed_pages());
+
+    let image = buffer.reboot(Crash::DropUnflushed).unwrap();
+    assert_eq!((1, 0), (image[0], image[PAGE_SIZE]));
+    let image = buffer.reboot(Crash::KeepUnflushed).unwrap();
+    assert_eq!((2, 3), (image[0], image[PAGE_SIZE]));
+    let image = buffer.reboot(Crash::Reorder(0b10)).unwrap();
+    assert_eq!((1, 3), (image[0], image[PAGE_SIZE]));
+}
+
+#[test]
+fn clones_share_resizes() {
+    let mut buffer = CrashTestBuffer::<u8>::try_new(PAGE_SIZE).unwrap();
+    let clone = buffer.clone();
+    buffer[0] = 1;
+    buffer.resize(2 * PAGE_SIZE).unwrap();
+    buffer[PAGE_SIZE] = 2;
+    assert_eq!((2 * PAGE_SIZE, 1, 2), (clone.len(), clone[0], clone[PAGE_SIZE]));
+
+    let mut grown = clone.new_sized(3 * PAGE_SIZE).unwrap();
+    grown[2 * PAGE_SIZE] = 3;
+    assert_eq!((3 * PAGE_SIZE, 3), (buffer.len(), buffer[2 * PAGE_SIZE]));
+    buffer.crash();
+    assert_eq!(3, buffer.unflushed_pages());
+}
+
+#[test]
+fn tear_page() {
+    let mut buffer = CrashTestBuffer::<u8>::try_new(2 * PAGE_SIZE).unwrap();
+    for i in 0..(2 * PAGE_SIZE) {
+        buffer[i] = 7;
+    }
+    buffer.crash();
+    let image = buffer.reboot(Crash::TearPage(1)).unwrap();
+    assert_eq!(7, image[PAGE_SIZE - 1]);
+    assert_eq!(7, image[PAGE_SIZE + PAGE_SIZE / 2 - 1]);
+    assert_eq!(0, image[PAGE_SIZE + PAGE_SIZE / 2]);
+    assert_eq!(2 + 2 + 4, buffer.crash_modes().len());
+}
+
+#[test]
+fn crash_during_sync() {
+    let mut buffer = CrashTestBuffer::<u8>::try_new(PAGE_SIZE).unwrap();
+    buffer.crash_after(1);
+    buffer[0] = 1;
+    buffer.flush().unwrap();
+    buffer[0] = 2;
+    assert!(buffer.flush().is_err());
+    assert!(buffer.has_crashed());
+    buffer[0] = 3; // Lost: the machine is down
+    assert_eq!(2, buffer.reboot(Crash::KeepUnflushed).unwrap()[0]);
+    assert_eq!(1, buffer.reboot(Crash::DropUnflushed).unwrap()[0]);
+}
+
+#[cfg(test)]
+use map::{HashMap, Elem};
+#[cfg(test)]
+use set::HashSet;
+#[cfg(test)]
+use batch::WriteBatch;
+
+#[test]
+fn hashmap_survives_every_crash() {
+    type Map = HashMap<usize, usize, CrashTestBuffer<Elem<usize, usize>>>;
+    let buffer = CrashTestBuffer::try_with_path("test_crash_map.db", PAGE_SIZE).unwrap();
+    {
+        let mut h = Map::try_create_in(buffer.clone()).unwrap();
+        let mut batch = WriteBatch::new();
+        for k in 0..100 {
+            batch.insert(k, k);
+        }
+        h.apply(batch).unwrap();
+
+        // The second batch grows the table and loses power once it is in the log, before the file is synced
+        let mut batch = WriteBatch::new();
+        for k in 50..300 {
+            batch.insert(k, k * 2);
+        }
+        batch.remove(3);
+        h.apply(batch).unwrap();
+        buffer.crash();
+    }
+    assert!(buffer.has_crashed());
+    assert!(buffer.unflushed_pages() > 0);
+    assert!(buffer.crash_modes().len() > 3);
+    for crash in buffer.crash_modes() {
+        let h = Map::try_open_in(buffer.reboot(crash).unwrap()).unwrap();
+        assert_eq!(299, h.len(), "{:?}", crash);
+        assert_eq!(None, h.get(&3));
+        assert_eq!(Some(&4), h.get(&4));
+        assert_eq!(Some(&598), h.get(&299));
+    }
+    fs::remove_file("test_crash_map.db.wal").unwrap();
+}
+
+#[test]
+fn hashmap_logs_single_writes() {
+    type Map = HashMap<usize, usize, CrashTestBuffer<Elem<usize, usize>>>;
+    let buffer = CrashTestBuffer::try_with_path("test_crash_single.db", PAGE_SIZE).unwrap();
+    {
+        let mut h = Map::try_create_in(buffer.clone()).unwrap();
+        h.insert(1, 10);
+        h.insert(2, 20);
+        assert!(h.remove(&1));
+        buffer.crash();
+    }
+    assert!(buffer.unflushed_pages() > 0);
+    assert!(buffer.crash_modes().len() > 3);
+    for crash in buffer.crash_modes() {
+        let h = Map::try_open_in(buffer.reboot(crash).unwrap()).unwrap();
+        assert_eq!(1, h.len(), "{:?}", crash);
+        assert_eq!(None, h.get(&1));
+        assert_eq!(Some(&20), h.get(&2));
+    }
+    fs::remove_file("test_crash_single.db.wal").unwrap();
+}
+
+#[test]
+fn hashset_survives_every_crash() {
+    type Set = HashSet<usize, CrashTestBuffer<Elem<usize, ()>>>;
+    let buffer = CrashTestBuffer::try_with_path("test_crash_set.db", PAGE_SIZE).unwrap();
+    {
+        let mut s = Set::try_create_in(buffer.clone()).unwrap();
+        let mut batch = WriteBatch::new();
+        for v in 0..100 {
+            batch.insert(v, ());
+        }
+        s.apply(batch).unwrap();
+
+        // Power fails before the second batch reaches the log
+        let mut batch = WriteBatch::new();
+        batch.insert(1000, ());
+        batch.remove(5);
+        buffer.crash();
+        let _ = s.apply(batch); // Lost with the log's state at the crash
+    }
+    assert!(buffer.unflushed_pages() > 0);
+    assert!(buffer.crash_modes().len() > 3);
+    for crash in buffer.crash_modes() {
+        let s = Set::try_open_in(buffer.reboot(crash).unwrap()).unwrap();
+        assert_eq!(100, s.len(), "{:?}", crash);
+        assert!(s.contains(&5));
+        assert!(!s.contains(&1000));
+    }
+    fs::remove_file("test_crash_set.db.wal").unwrap();
+}
@@ -1,18 +1,40 @@
 
 extern crate memmap;
 extern crate rand;
+extern crate ozone_derive;
+#[cfg(test)]
+extern crate self as ozone;
 
 mod set;
 mod map;
+mod btree_set;
+mod btree_map;
+mod batch;
+mod wal;
 mod table;
 mod buffer;
+mod crash;
 
 use set::{HashSet};
 use map::{HashMap, Elem};
+use btree_set::BTreeSet;
+use btree_map::BTreeMap;
 use buffer::{AnonymousBuffer, FileBuffer};
 
+pub use batch::WriteBatch;
+pub use crash::{CrashTestBuffer, Crash};
+pub use table::{Database, Page, Schema, Column, ColumnType, Value, ByteString, ConstraintViolation, operators};
+pub use table::{Table, Table0, Table1, Table2, Table3, RowType, ColumnValue, Record};
+pub use ozone_derive::Record;
+
 pub type SwapBackedHashMap<K, V> = HashMap<K, V, AnonymousBuffer<Elem<K, V>>>;
 pub type FileBackedHashMap<K, V> = HashMap<K, V, FileBuffer<Elem<K, V>>>;
 pub type SwapBackedHashSet<T> = HashSet<T, AnonymousBuffer<Elem<T, ()>>>;
 pub type FileBackedHashSet<T> = HashSet<T, FileBuffer<Elem<T, ()>>>;
+pub type SwapBackedBTreeMap<K, V> = BTreeMap<K, V, AnonymousBuffer<Page>>;
+pub type FileBackedBTreeMap<K, V> = BTreeMap<K, V, FileBuffer<Page>>;
+pub type SwapBackedBTreeSet<T> = BTreeSet<T, AnonymousBuffer<Page>>;
+pub type FileBackedBTreeSet<T> = BTreeSet<T, FileBuffer<Page>>;
+pub type SwapBackedDatabase = Database<AnonymousBuffer<Page>>;
+pub type FileBackedDatabase = Database<FileBuffer<Page>>;
 
@@ -2,15 +2,20 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::cmp::Eq;
-use std::io::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Result, Error, ErrorKind};
 use std::marker::PhantomData;
 use std::path::Path;
-use std::{mem, fmt};
+use std::sync::{Arc, Mutex, Weak};
+use std::{fs, mem, ptr, cmp, slice, fmt};
 
 use buffer::{Buffer, AnonymousBuffer, FileBuffer};
+use batch::{WriteBatch, Op};
+use wal::{self, Wal};
 
 const INITIAL_SIZE: usize = 256usize; // Must be a power of 2
 const LOAD_FACTOR_PERCENT: usize = 90usize;
+const PAGE_SIZE: usize = 4096usize;
 
 pub struct Elem<K, V>
     where K: 'static + Eq + Hash + Sized,
@@ -31,10 +36,58 @@ pub struct HashMap<K, V, B = AnonymousBuffer<Elem<K, V>>>
     capacity: usize,
     resize_threshold: usize,
     mask: u64,
+    snapshots: Vec<Weak<Mutex<Frozen<K, V>>>>,
+    staging: Option<Staging<K, V>>,
+    touched: BTreeSet<usize>, // Pages of a logged map changed in place, logged by the next commit
+    wal: Option<Wal>,
     phantom_k: PhantomData<K>,
     phantom_v: PhantomData<V>,
 }
 
+// Pages of a snapshot that have been copied out before the writer touched them
+struct Frozen<K, V>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized
+{
+    pages: Vec<Option<Vec<Elem<K, V>>>>,
+}
+
+// Pages written by a batch that is being applied, kept off the buffer until commit
+struct Staging<K, V>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized
+{
+    pages: BTreeMap<usize, Vec<Elem<K, V>>>,
+    resized: bool, // Every page is staged and the buffer is reallocated on commit
+    replaced: Vec<V>, // Values overwritten by the batch, dropped once it commits
+}
+
+impl<K, V> Drop for Staging<K, V>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized
+{
+    fn drop(&mut self) {
+        // Staged elements are bitwise copies; the buffer keeps ownership
+        for elems in self.pages.values_mut() {
+            unsafe { elems.set_len(0); }
+        }
+        // Whatever commit has not dropped may still be owned by the buffer
+        unsafe { self.replaced.set_len(0); }
+    }
+}
+
+pub struct Snapshot<K, V, B>
+    where K: 'static + Eq + Hash + Copy + Sized,
+          V: 'static + Copy + Sized,
+          B: Buffer<Elem<K, V>>
+{
+    buffer: B,
+    frozen: Arc<Mutex<Frozen<K, V>>>,
+    num_elems: usize,
+    capacity: usize,
+    mask: u64,
+}
+
 pub enum Entry<'a, K: 'static, V: 'static + 'a>
     where K: 'static + Eq + Hash + Sized,
           V: 'static + Sized,
@@ -159,20 +212,7 @@ impl<K, V> HashMap<K, V, AnonymousBuffer<Elem<K, V>>>
 
     pub fn try_new() -> Result<Self> {
         let buffer = AnonymousBuffer::try_new(INITIAL_SIZE * mem::size_of::<Elem<K, V>>())?;
-        let mut h = HashMap {
-            buffer: buffer,
-            num_elems: 0,
-            capacity: INITIAL_SIZE,
-            resize_threshold: ((INITIAL_SIZE * LOAD_FACTOR_PERCENT) as f64 / 100f64) as usize,
-            mask: INITIAL_SIZE as u64 - 1,
-            phantom_k: PhantomData,
-            phantom_v: PhantomData,
-        };
-        for i in 0..h.capacity {
-            let mut hash = h.elem_hash_mut(i);
-            *hash = 0;
-        }
-        Ok(h)
+        Self::try_create_in(buffer)
     }
 }
 
@@ -190,12 +230,53 @@ impl<K, V> HashMap<K, V, FileBuffer<Elem<K, V>>>
         where P: AsRef<Path> + Clone
     {
         let buffer = FileBuffer::try_new(path, INITIAL_SIZE * mem::size_of::<Elem<K, V>>())?;
+        Self::try_create_in(buffer)
+    }
+
+    pub fn open<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        Self::try_open(path).unwrap()
+    }
+
+    pub fn try_open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let len = fs::metadata(path.as_ref())?.len() as usize;
+        let buffer = FileBuffer::try_new(path, len)?;
+        Self::try_open_in(buffer)
+    }
+}
+
+impl<K, V, B> HashMap<K, V, B>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized,
+          B: Buffer<Elem<K, V>>
+{
+    // Start an empty map over the buffer, discarding whatever it held
+    pub fn try_create_in(mut buffer: B) -> Result<Self> {
+        let wal = match buffer.path() {
+            Some(path) => {
+                let mut wal = Wal::beside(path)?;
+                wal.checkpoint()?;
+                Some(wal)
+            }
+            None => None,
+        };
+        let elem_size = mem::size_of::<Elem<K, V>>();
+        if buffer.len() < INITIAL_SIZE * elem_size {
+            buffer.resize(INITIAL_SIZE * elem_size)?;
+        }
         let mut h = HashMap {
             buffer: buffer,
             num_elems: 0,
             capacity: INITIAL_SIZE,
             resize_threshold: ((INITIAL_SIZE * LOAD_FACTOR_PERCENT) as f64 / 100f64) as usize,
             mask: INITIAL_SIZE as u64 - 1,
+            snapshots: Vec::new(),
+            staging: None,
+            touched: BTreeSet::new(),
+            wal: wal,
             phantom_k: PhantomData,
             phantom_v: PhantomData,
         };
@@ -205,6 +286,73 @@ impl<K, V> HashMap<K, V, FileBuffer<Elem<K, V>>>
         }
         Ok(h)
     }
+
+    // Reattach to a map previously written to the buffer, replaying its log
+    pub fn try_open_in(mut buffer: B) -> Result<Self> {
+        let elem_size = mem::size_of::<Elem<K, V>>();
+        let per_page = Self::elems_per_page();
+        let mut wal = match buffer.path() {
+            Some(path) => Some(Wal::beside(path)?),
+            None => None,
+        };
+        let mut records = Vec::new();
+        if let Some(ref mut wal) = wal {
+            wal.replay(|record| {
+                records.push(wal::decode_images(record)?);
+                Ok(())
+            })?;
+        }
+        let capacity = match records.last() {
+            Some(&(capacity, _)) => capacity,
+            None => buffer.len() / elem_size,
+        };
+        if capacity < INITIAL_SIZE || !capacity.is_power_of_two() {
+            return Err(Error::new(ErrorKind::InvalidData, "file is not a hash map"));
+        }
+        if buffer.len() < capacity * elem_size {
+            buffer.resize(capacity * elem_size)?;
+        }
+
+        let mut h = HashMap {
+            buffer: buffer,
+            num_elems: 0,
+            capacity: capacity,
+            resize_threshold: ((capacity * LOAD_FACTOR_PERCENT) as f64 / 100f64) as usize,
+            mask: capacity as u64 - 1,
+            snapshots: Vec::new(),
+            staging: None,
+            touched: BTreeSet::new(),
+            wal: None,
+            phantom_k: PhantomData,
+            phantom_v: PhantomData,
+        };
+
+        // Redo batches that were logged but may not have reached the file
+        for (record_capacity, pages) in records {
+            for (page, image) in pages {
+                if page * per_page + image.len() / elem_size > record_capacity {
+                    return Err(Error::new(ErrorKind::InvalidData, "write-ahead log does not fit hash map"));
+                }
+                unsafe {
+                    let to = &mut h.buffer[page * per_page] as *mut _ as *mut u8;
+                    ptr::copy_nonoverlapping(image.as_ptr(), to, image.len());
+                }
+            }
+        }
+        h.buffer.flush()?;
+        if let Some(ref mut wal) = wal {
+            wal.checkpoint()?;
+        }
+        h.wal = wal;
+
+        for i in 0..h.capacity {
+            let hash = *h.elem_hash(i);
+            if hash != 0 && !Self::is_deleted(hash) {
+                h.num_elems += 1;
+            }
+        }
+        Ok(h)
+    }
 }
 
 impl<K, V, B> HashMap<K, V, B>
@@ -216,14 +364,21 @@ impl<K, V, B> HashMap<K, V, B>
         self.try_insert(key, value).unwrap()
     }
 
+    // Inserting a key already present replaces its value. A file-backed map logs each insert or remove
+    // on its own, which costs a log sync apiece; apply a WriteBatch to share one. Values changed in place
+    // through get_mut or entry are logged with the next commit or flush
     pub fn try_insert(&mut self, key: K, value: V) -> Result<()> {
-        self.try_insert_with_hash(Self::hash_key(&key), key, value)?;
-        Ok(())
+        if self.wal.is_some() && self.staging.is_none() {
+            let mut tx = self.begin();
+            tx.insert(key, value)?;
+            return tx.commit();
+        }
+        self.replace_or_insert(key, value)
     }
 
     pub fn get(&self, key: &K) -> Option<&V> {
         if let Some(ix) = self.lookup_index(key) {
-            Some(&self.buffer[ix].value)
+            Some(&self.elem(ix).value)
         } else {
             None
         }
@@ -231,7 +386,7 @@ impl<K, V, B> HashMap<K, V, B>
 
     pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
         if let Some(ix) = self.lookup_index(key) {
-            Some(&mut self.buffer[ix].value)
+            Some(&mut self.elem_mut(ix).value)
         } else {
             None
         }
@@ -239,13 +394,27 @@ impl<K, V, B> HashMap<K, V, B>
 
     pub fn get_key(&self, key: &K) -> Option<&K> {
         if let Some(ix) = self.lookup_index(key) {
-            Some(&self.buffer[ix].key)
+            Some(&self.elem(ix).key)
         } else {
             None
         }
     }
 
     pub fn remove(&mut self, key: &K) -> bool {
+        self.try_remove(key).unwrap()
+    }
+
+    pub fn try_remove(&mut self, key: &K) -> Result<bool> {
+        if self.wal.is_some() && self.staging.is_none() {
+            let mut tx = self.begin();
+            let removed = tx.remove(key);
+            tx.commit()?;
+            return Ok(removed);
+        }
+        Ok(self.remove_unlogged(key))
+    }
+
+    fn remove_unlogged(&mut self, key: &K) -> bool {
         if let Some(ix) = self.lookup_index(key) {
             self.num_elems -= 1;
             let elem_hash = self.elem_hash_mut(ix);
@@ -262,13 +431,14 @@ impl<K, V, B> HashMap<K, V, B>
 
     pub fn entry(&mut self, key: K) -> Entry<K, V> {
         if let Some(ix) = self.lookup_index(&key) {
-            Entry::Occupied(OccupiedEntry { elem: &mut self.buffer[ix] })
+            Entry::Occupied(OccupiedEntry { elem: self.elem_mut(ix) })
         } else {
             let hash = Self::hash_key(&key);
             let value = unsafe { mem::uninitialized() };
             let pos = self.insert_with_hash(hash, key, value);
-            self.buffer[pos].hash = 0;
-            Entry::Vacant(VacantEntry { elem: &mut self.buffer[pos] })
+            let elem = self.elem_mut(pos);
+            elem.hash = 0;
+            Entry::Vacant(VacantEntry { elem: elem })
         }
     }
 
@@ -292,6 +462,47 @@ impl<K, V, B> HashMap<K, V, B>
         Values { map: &self, ix: 0 }
     }
 
+    pub fn snapshot(&mut self) -> Snapshot<K, V, B>
+        where K: Copy,
+              V: Copy
+    {
+        let pages = self.capacity.div_ceil(Self::elems_per_page());
+        let frozen = Arc::new(Mutex::new(Frozen { pages: (0..pages).map(|_| None).collect() }));
+        self.snapshots.push(Arc::downgrade(&frozen));
+        Snapshot {
+            buffer: self.buffer.clone(),
+            frozen: frozen,
+            num_elems: self.num_elems,
+            capacity: self.capacity,
+            mask: self.mask,
+        }
+    }
+
+    pub fn begin<'a>(&'a mut self) -> Transaction<'a, K, V, B> {
+        let (num_elems, capacity) = (self.num_elems, self.capacity);
+        self.staging = Some(Staging { pages: BTreeMap::new(), resized: false, replaced: Vec::new() });
+        Transaction { map: self, num_elems: num_elems, capacity: capacity, done: false }
+    }
+
+    pub fn apply(&mut self, batch: WriteBatch<K, V>) -> Result<()> {
+        let mut tx = self.begin();
+        for op in batch.into_ops() {
+            match op {
+                Op::Insert(key, value) => { tx.insert(key, value)?; },
+                Op::Remove(key) => { tx.remove(&key); }
+            }
+        }
+        tx.commit()
+    }
+
+    // Pages changed in place are logged first, so replaying the log cannot put older images over them
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.touched.is_empty() {
+            self.begin().commit()?;
+        }
+        self.buffer.flush()
+    }
+
     //pub fn drain<'a>(&'a mut self) -> Drain<'a, K, V, B> { }
 
     //pub fn clear(&mut self) { }
@@ -329,11 +540,73 @@ impl<K, V, B> HashMap<K, V, B>
     }
 
     fn elem_hash(&self, ix: usize) -> &u64 {
-        &self.buffer[ix].hash
+        &self.elem(ix).hash
+    }
+
+    fn elem(&self, ix: usize) -> &Elem<K, V> {
+        if let Some(ref staging) = self.staging {
+            let per_page = Self::elems_per_page();
+            if let Some(elems) = staging.pages.get(&(ix / per_page)) {
+                return &elems[ix % per_page];
+            }
+        }
+        &self.buffer[ix]
     }
 
     fn elem_hash_mut(&mut self, ix: usize) -> &mut u64 {
-        &mut self.buffer[ix].hash
+        &mut self.elem_mut(ix).hash
+    }
+
+    fn elem_mut(&mut self, ix: usize) -> &mut Elem<K, V> {
+        if let Some(ref mut staging) = self.staging {
+            let per_page = Self::elems_per_page();
+            let start = (ix / per_page) * per_page;
+            let end = cmp::min(start + per_page, self.capacity);
+            let buffer = &self.buffer;
+            let elems = staging.pages.entry(ix / per_page).or_insert_with(|| {
+                (start..end).map(|i| unsafe { ptr::read(&buffer[i]) }).collect()
+            });
+            return &mut elems[ix - start];
+        }
+        if !self.snapshots.is_empty() {
+            self.preserve_page(ix / Self::elems_per_page());
+        }
+        if self.wal.is_some() {
+            self.touched.insert(ix / Self::elems_per_page());
+        }
+        &mut self.buffer[ix]
+    }
+
+    fn elems_per_page() -> usize {
+        cmp::max(1, PAGE_SIZE / cmp::max(1, mem::size_of::<Elem<K, V>>()))
+    }
+
+    // Copy a page out to every live snapshot that still reads it from the buffer
+    fn preserve_page(&mut self, page: usize) {
+        let per_page = Self::elems_per_page();
+        let start = page * per_page;
+        let end = cmp::min(start + per_page, self.capacity);
+        let buffer = &self.buffer;
+        self.snapshots.retain(|snapshot| {
+            if let Some(frozen) = snapshot.upgrade() {
+                let mut frozen = frozen.lock().unwrap();
+                if page < frozen.pages.len() && frozen.pages[page].is_none() {
+                    let elems = (start..end).map(|i| unsafe { ptr::read(&buffer[i]) }).collect();
+                    frozen.pages[page] = Some(elems);
+                }
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    fn detach_snapshots(&mut self) {
+        let pages = self.capacity.div_ceil(Self::elems_per_page());
+        for page in 0..pages {
+            self.preserve_page(page);
+        }
+        self.snapshots.clear();
     }
 
     fn insert_with_hash(&mut self, hash: u64, key: K, value: V) -> usize {
@@ -349,11 +622,31 @@ impl<K, V, B> HashMap<K, V, B>
     }
 
     fn alloc(&mut self) -> Result<()> {
-        self.buffer = self.buffer.new_sized(self.capacity * mem::size_of::<Elem<K, V>>())?;
+        if let Some(ref mut staging) = self.staging {
+            let per_page = Self::elems_per_page();
+            // The staged elements are bitwise copies that grow() has already read out, so none may drop here
+            for elems in staging.pages.values_mut() {
+                unsafe { elems.set_len(0); }
+            }
+            staging.pages.clear();
+            staging.resized = true;
+            for start in (0..self.capacity).filter(|i| i % per_page == 0) {
+                let len = cmp::min(per_page, self.capacity - start);
+                let mut elems: Vec<Elem<K, V>> = Vec::with_capacity(len);
+                unsafe {
+                    // Zeroed hashes mark every slot empty
+                    ptr::write_bytes(elems.as_mut_ptr(), 0, len);
+                    elems.set_len(len);
+                }
+                staging.pages.insert(start / per_page, elems);
+            }
+        } else {
+            self.buffer = self.buffer.new_sized(self.capacity * mem::size_of::<Elem<K, V>>())?;
 
-        for i in 0..self.capacity {
-            let mut hash = self.elem_hash_mut(i);
-            *hash = 0;
+            for i in 0..self.capacity {
+                let mut hash = self.elem_hash_mut(i);
+                *hash = 0;
+            }
         }
         
         self.resize_threshold = ((self.capacity * LOAD_FACTOR_PERCENT) as f64 / 100f64) as usize;
@@ -364,29 +657,114 @@ impl<K, V, B> HashMap<K, V, B>
 
     fn grow(&mut self) -> Result<()> {
         println!("Growing...");
-        let mut old_buffer = self.buffer.clone();
+        self.detach_snapshots();
         let old_capacity = self.capacity;
 
-        self.capacity *= 2;
-        self.alloc()?;
-
+        // Copy out first: a file buffer reallocates over its own storage
+        let mut old_elems = Vec::new();
         for i in 0..old_capacity {
-            let old_elem = &mut old_buffer[i];
-            let hash = old_elem.hash;
+            let hash = *self.elem_hash(i);
             if hash != 0 && !Self::is_deleted(hash) {
-                let k: K = unsafe { mem::uninitialized() };
-                let v: V = unsafe { mem::uninitialized() };
-                let ix = self.insert_helper(hash, k, v);
-                let new_elem = &mut self.buffer[ix];
-                mem::swap(old_elem, new_elem);
+                old_elems.push(unsafe { ptr::read(self.elem(i)) });
+            }
+        }
+
+        self.capacity *= 2;
+        if let Err(e) = self.alloc() {
+            mem::forget(old_elems);
+            self.capacity = old_capacity;
+            return Err(e);
+        }
+
+        for elem in old_elems {
+            self.insert_helper(elem.hash, elem.key, elem.value);
+        }
+
+        // Older log records describe the old layout, so a map grown outside a transaction logs the new one
+        // before anything can replay them over it
+        if self.staging.is_none() && !self.touched.is_empty() {
+            self.begin().commit()?;
+        }
+        Ok(())
+    }
+
+    // A key already present keeps its slot and takes the new value
+    fn replace_or_insert(&mut self, key: K, value: V) -> Result<()> {
+        match self.lookup_index(&key) {
+            Some(ix) => {
+                let old = mem::replace(&mut self.elem_mut(ix).value, value);
+                match self.staging {
+                    // The buffer still holds the old value until the batch commits
+                    Some(ref mut staging) => staging.replaced.push(old),
+                    None => drop(old),
+                }
+            }
+            None => { self.try_insert_with_hash(Self::hash_key(&key), key, value)?; }
+        }
+        Ok(())
+    }
+
+    fn discard_staging(&mut self, num_elems: usize, capacity: usize) {
+        self.staging = None;
+        self.num_elems = num_elems;
+        self.capacity = capacity;
+        self.resize_threshold = ((capacity * LOAD_FACTOR_PERCENT) as f64 / 100f64) as usize;
+        self.mask = capacity as u64 - 1;
+    }
+
+    fn commit_staging(&mut self) -> Result<()> {
+        let mut staging = self.staging.take().unwrap();
+        let per_page = Self::elems_per_page();
+        let elem_size = mem::size_of::<Elem<K, V>>();
+
+        if let Some(ref mut wal) = self.wal {
+            let mut images = staging.pages.iter().map(|(&page, elems)| {
+                let bytes = unsafe { slice::from_raw_parts(elems.as_ptr() as *const u8, elems.len() * elem_size) };
+                (page, bytes)
+            }).collect::<Vec<_>>();
+            // A resize stages every page, so only then are the pages changed in place already covered
+            if !staging.resized {
+                for &page in self.touched.iter().filter(|page| !staging.pages.contains_key(page)) {
+                    let len = cmp::min(per_page, self.capacity - page * per_page);
+                    let bytes = unsafe { slice::from_raw_parts(&self.buffer[page * per_page] as *const _ as *const u8, len * elem_size) };
+                    images.push((page, bytes));
+                }
+            }
+            let record = wal::encode_images(self.capacity, &images);
+            wal.append(&record);
+            wal.commit()?;
+        }
+
+        if staging.resized {
+            self.buffer = self.buffer.new_sized(self.capacity * elem_size)?;
+        }
+        for (&page, elems) in staging.pages.iter() {
+            if !self.snapshots.is_empty() {
+                self.preserve_page(page);
+            }
+            unsafe {
+                let to = &mut self.buffer[page * per_page] as *mut Elem<K, V>;
+                ptr::copy_nonoverlapping(elems.as_ptr(), to, elems.len());
             }
         }
 
+        // The log commit above is the batch's one sync; the pages reach the file whenever the log is cut
+        match self.wal {
+            Some(ref mut wal) if wal.needs_checkpoint() => {
+                self.buffer.flush()?;
+                wal.checkpoint()?;
+            }
+            Some(_) => {}
+            None => self.buffer.flush()?,
+        }
+        staging.replaced.clear();
+        self.touched.clear();
         Ok(())
     }
 
+    // The slot is empty or a tombstone; a removed element is never dropped, so nothing is dropped here
     fn construct(&mut self, ix: usize, hash: u64, key: K, val: V) {
-        self.buffer[ix] = Elem { key: key, value: val, hash: hash };
+        unsafe { ptr::write(self.elem_mut(ix), Elem { key: key, value: val, hash: hash }); }
     }
 
     fn insert_helper(&mut self, mut hash: u64, mut key: K, mut val: V) -> usize {
@@ -418,9 +796,10 @@ impl<K, V, B> HashMap<K, V, B>
                     ix = pos;
                     first = false;
                 }
-                mem::swap(&mut hash, self.elem_hash_mut(pos));
-                mem::swap(&mut key, &mut self.buffer[pos].key);
-                mem::swap(&mut val, &mut self.buffer[pos].value);
+                let elem = self.elem_mut(pos);
+                mem::swap(&mut hash, &mut elem.hash);
+                mem::swap(&mut key, &mut elem.key);
+                mem::swap(&mut val, &mut elem.value);
                 dist = existing_elem_probe_dist;
             }
 
@@ -440,7 +819,7 @@ impl<K, V, B> HashMap<K, V, B>
                 return None;
             } else if dist > self.probe_distance(elem_hash, pos as u64) {
                 return None;
-            } else if elem_hash == hash && self.buffer[pos].key == *key {
+            } else if elem_hash == hash && self.elem(pos).key == *key {
                 return Some(pos);
             }
 
@@ -461,6 +840,73 @@ impl<K, V, B> HashMap<K, V, B>
     }
 }
 
+pub struct Transaction<'a, K, V, B>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized,
+          B: 'a + Buffer<Elem<K, V>>
+{
+    map: &'a mut HashMap<K, V, B>,
+    num_elems: usize,
+    capacity: usize,
+    done: bool,
+}
+
+impl<'a, K, V, B> Transaction<'a, K, V, B>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized,
+          B: 'a + Buffer<Elem<K, V>>
+{
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        self.map.replace_or_insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.map.remove_unlogged(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.done = true;
+        let result = self.map.commit_staging();
+        if result.is_err() {
+            self.map.discard_staging(self.num_elems, self.capacity);
+        }
+        result
+    }
+
+    pub fn rollback(mut self) {
+        self.done = true;
+        self.map.discard_staging(self.num_elems, self.capacity);
+    }
+}
+
+impl<'a, K, V, B> Drop for Transaction<'a, K, V, B>
+    where K: 'static + Eq + Hash + Sized,
+          V: 'static + Sized,
+          B: 'a + Buffer<Elem<K, V>>
+{
+    fn drop(&mut self) {
+        if !self.done {
+            self.map.discard_staging(self.num_elems, self.capacity);
+        }
+    }
+}
+
 pub struct Iter<'a, K, V, B>
     where K: 'static + Eq + Hash + Sized,
           V: 'static + Sized,
@@ -481,7 +927,7 @@ impl<'a, K, V, B> Iterator for Iter<'a, K, V, B>
         while self.ix < self.map.capacity {
             let hash = *self.map.elem_hash(self.ix);
             if hash != 0 && !HashMap::<K, V>::is_deleted(hash) {
-                let ref entry = self.map.buffer[self.ix];
+                let entry = self.map.elem(self.ix);
                 self.ix += 1;
                 return Some((&entry.key, &entry.value));
             }
@@ -511,7 +957,7 @@ impl<'a, K, V, B> Iterator for Keys<'a, K, V, B>
         while self.ix < self.map.capacity {
             let hash = *self.map.elem_hash(self.ix);
             if hash != 0 && !HashMap::<K, V>::is_deleted(hash) {
-                let ref entry = self.map.buffer[self.ix];
+                let entry = self.map.elem(self.ix);
                 self.ix += 1;
                 return Some(&entry.key);
             }
@@ -541,7 +987,7 @@ impl<'a, K, V, B> Iterator for Values<'a, K, V, B>
         while self.ix < self.map.capacity {
             let hash = *self.map.elem_hash(self.ix);
             if hash != 0 && !HashMap::<K, V>::is_deleted(hash) {
-                let ref entry = self.map.buffer[self.ix];
+                let entry = self.map.elem(self.ix);
                 self.ix += 1;
                 return Some(&entry.value);
             }
@@ -551,6 +997,92 @@ impl<'a, K, V, B> Iterator for Values<'a, K, V, B>
     }
 }
 
+impl<K, V, B> Snapshot<K, V, B>
+    where K: 'static + Eq + Hash + Copy + Sized,
+          V: 'static + Copy + Sized,
+          B: Buffer<Elem<K, V>>
+{
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.lookup(key).and_then(|elem| Some(elem.value))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_elems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_elems == 0
+    }
+
+    pub fn iter<'a>(&'a self) -> SnapshotIter<'a, K, V, B> {
+        SnapshotIter { snapshot: &self, ix: 0 }
+    }
+
+    fn elem(&self, ix: usize) -> Elem<K, V> {
+        let per_page = HashMap::<K, V, B>::elems_per_page();
+        let frozen = self.frozen.lock().unwrap();
+        if let Some(ref page) = frozen.pages[ix / per_page] {
+            unsafe { ptr::read(&page[ix % per_page]) }
+        } else {
+            unsafe { ptr::read(&self.buffer[ix]) }
+        }
+    }
+
+    fn lookup(&self, key: &K) -> Option<Elem<K, V>> {
+        let hash = HashMap::<K, V, B>::hash_key(key);
+        let mut pos = (hash & self.mask) as usize;
+        let mut dist = 0;
+        loop {
+            let elem = self.elem(pos);
+            if elem.hash == 0 {
+                return None;
+            }
+            let desired = (elem.hash & self.mask) as usize;
+            let probe_distance = ((pos + self.capacity - desired) as u64 & self.mask) as usize;
+            if dist > probe_distance {
+                return None;
+            } else if elem.hash == hash && elem.key == *key {
+                return Some(elem);
+            }
+
+            pos = (pos + 1) & self.mask as usize;
+            dist += 1;
+        }
+    }
+}
+
+pub struct SnapshotIter<'a, K, V, B>
+    where K: 'static + Eq + Hash + Copy + Sized,
+          V: 'static + Copy + Sized,
+          B: 'a + Buffer<Elem<K, V>>
+{
+    snapshot: &'a Snapshot<K, V, B>,
+    ix: usize,
+}
+
+impl<'a, K, V, B> Iterator for SnapshotIter<'a, K, V, B>
+    where K: 'static + Eq + Hash + Copy + Sized,
+          V: 'static + Copy + Sized,
+          B: 'a + Buffer<Elem<K, V>>
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ix < self.snapshot.capacity {
+            let elem = self.snapshot.elem(self.ix);
+            self.ix += 1;
+            if elem.hash != 0 && !HashMap::<K, V>::is_deleted(elem.hash) {
+                return Some((elem.key, elem.value));
+            }
+        }
+        None
+    }
+}
+
 impl<K, V, B> fmt::Debug for HashMap<K, V, B>
     where K: 'static + Eq + Hash + Sized + fmt::Debug,
           V: 'static + Sized + fmt::Debug,
@@ -622,3 +1154,235 @@ fn entry_or_insert_and_iter_300_hashmap() {
     }
 }
 
+
+#[test]
+fn snapshot_is_frozen_hashmap() {
+    let mut h: HashMap<usize, usize> = HashMap::<usize, usize>::new();
+    for k in 0..100 {
+        h.insert(k, k);
+    }
+    let snapshot = h.snapshot();
+    for k in 0..50 {
+        h.remove(&k);
+    }
+    for k in 50..100 {
+        *h.get_mut(&k).unwrap() += 1000;
+    }
+    h.insert(100, 100);
+    assert_eq!(51, h.len());
+    assert_eq!(100, snapshot.len());
+    for k in 0..100 {
+        assert_eq!(Some(k), snapshot.get(&k));
+    }
+    assert!(!snapshot.contains_key(&100));
+    let mut iter = snapshot.iter().collect::<Vec<_>>();
+    iter.sort();
+    assert_eq!((0..100).map(|k| (k, k)).collect::<Vec<_>>(), iter);
+}
+
+#[test]
+fn snapshot_survives_grow_hashmap() {
+    let mut h: HashMap<usize, usize> = HashMap::<usize, usize>::new();
+    for k in 0..10 {
+        h.insert(k, k);
+    }
+    let snapshot = h.snapshot();
+    for k in 10..300 {
+        h.insert(k, k);
+    }
+    assert_eq!(300, h.len());
+    assert_eq!(10, snapshot.iter().count());
+    assert_eq!(Some(9), snapshot.get(&9));
+    assert_eq!(None, snapshot.get(&10));
+}
+
+#[test]
+fn apply_batch_hashmap() {
+    let mut h: HashMap<usize, usize> = HashMap::<usize, usize>::new();
+    for k in 0..10 {
+        h.insert(k, k);
+    }
+    let mut batch = WriteBatch::new();
+    batch.insert(3, 30).insert(10, 100).remove(4);
+    h.apply(batch).unwrap();
+    assert_eq!(10, h.len());
+    assert_eq!(Some(&30), h.get(&3));
+    assert_eq!(Some(&100), h.get(&10));
+    assert_eq!(None, h.get(&4));
+}
+
+#[test]
+fn apply_batch_and_reopen_hashmap() {
+    {
+        let mut h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::new("test_batch.db");
+        let mut batch = WriteBatch::new();
+        for k in 0..300 {
+            batch.insert(k, k * 2);
+        }
+        batch.remove(7);
+        h.apply(batch).unwrap();
+        assert_eq!(299, h.len());
+        // Only the log was synced; the file catches up at the next checkpoint
+        assert!(fs::metadata("test_batch.db.wal").unwrap().len() > 0);
+    }
+    let h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::open("test_batch.db");
+    assert_eq!(299, h.len());
+    assert_eq!(Some(&598), h.get(&299));
+    assert_eq!(None, h.get(&7));
+    fs::remove_file("test_batch.db").unwrap();
+    fs::remove_file("test_batch.db.wal").unwrap();
+}
+
+#[test]
+fn replay_logged_batch_hashmap() {
+    {
+        let mut h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::new("test_replay.db");
+        h.insert(1, 1);
+        h.flush().unwrap();
+
+        // Crash after the redo record is durable but before the pages are written
+        let mut tx = h.begin();
+        tx.insert(1, 10).unwrap();
+        tx.insert(2, 20).unwrap();
+        {
+            let staging = tx.map.staging.as_ref().unwrap();
+            let images = staging.pages.iter().map(|(&page, elems)| {
+                let bytes = unsafe { slice::from_raw_parts(elems.as_ptr() as *const u8, elems.len() * mem::size_of::<Elem<usize, usize>>()) };
+                (page, bytes)
+            }).collect::<Vec<_>>();
+            let record = wal::encode_images(tx.map.capacity, &images);
+            let wal = tx.map.wal.as_mut().unwrap();
+            wal.append(&record);
+            wal.commit().unwrap();
+        }
+        mem::forget(tx);
+    }
+    let h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::open("test_replay.db");
+    assert_eq!(2, h.len());
+    assert_eq!(Some(&10), h.get(&1));
+    assert_eq!(Some(&20), h.get(&2));
+    fs::remove_file("test_replay.db").unwrap();
+    fs::remove_file("test_replay.db.wal").unwrap();
+}
+
+#[test]
+fn transaction_commit_and_rollback_hashmap() {
+    let mut h: HashMap<usize, usize> = HashMap::<usize, usize>::new();
+    h.insert(1, 1);
+    {
+        let mut tx = h.begin();
+        tx.insert(1, 10).unwrap();
+        tx.insert(2, 20).unwrap();
+        assert_eq!(Some(&10), tx.get(&1));
+        assert!(tx.remove(&2));
+        assert!(!tx.contains_key(&2));
+        tx.insert(3, 30).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!(2, h.len());
+    assert_eq!(Some(&10), h.get(&1));
+    assert_eq!(Some(&30), h.get(&3));
+
+    {
+        let mut tx = h.begin();
+        tx.remove(&1);
+        for k in 100..400 {
+            tx.insert(k, k).unwrap();
+        }
+        assert_eq!(301, tx.len());
+        tx.rollback();
+    }
+    {
+        let mut tx = h.begin();
+        tx.insert(4, 40).unwrap();
+    }
+    assert_eq!(2, h.len());
+    assert_eq!(Some(&10), h.get(&1));
+    assert_eq!(None, h.get(&4));
+    assert_eq!(None, h.get(&100));
+}
+
+#[test]
+fn transaction_grows_with_owned_keys_hashmap() {
+    let mut h: HashMap<String, String> = HashMap::<String, String>::new();
+    h.insert("k0".to_string(), "v0".to_string());
+    {
+        let mut tx = h.begin();
+        for k in 1..300 {
+            tx.insert(format!("k{}", k), format!("v{}", k)).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+    assert_eq!(300, h.len());
+    for k in 0..300 {
+        assert_eq!(Some(&format!("v{}", k)), h.get(&format!("k{}", k)));
+    }
+}
+
+#[test]
+fn insert_replaces_and_drops_old_value_hashmap() {
+    use std::rc::Rc;
+    let value = Rc::new(());
+    let mut h: HashMap<usize, Rc<()>> = HashMap::<usize, Rc<()>>::new();
+    h.insert(1, value.clone());
+    h.insert(1, value.clone());
+    assert_eq!((1, 2), (h.len(), Rc::strong_count(&value)));
+
+    // Inside a transaction the old value lives until commit, and survives a rollback
+    {
+        let mut tx = h.begin();
+        tx.insert(1, value.clone()).unwrap();
+        tx.insert(1, value.clone()).unwrap();
+        tx.rollback();
+    }
+    assert!(Rc::strong_count(&value) >= 2);
+    let before = Rc::strong_count(&value);
+    {
+        let mut tx = h.begin();
+        tx.insert(1, Rc::new(())).unwrap();
+        tx.commit().unwrap();
+    }
+    assert_eq!((1, before - 1), (h.len(), Rc::strong_count(&value)));
+}
+
+#[test]
+fn changes_in_place_are_logged_hashmap() {
+    {
+        let mut h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::new("test_in_place.db");
+        h.insert(1, 1);
+        *h.get_mut(&1).unwrap() = 10;
+        *h.entry(2).or_insert(0) += 20;
+        h.flush().unwrap();
+        *h.get_mut(&2).unwrap() += 1;
+        h.insert(3, 30);
+        // Grown through entry, outside any transaction
+        for k in 100..400 {
+            h.entry(k).or_insert(k);
+        }
+        h.flush().unwrap();
+    }
+    // Replaying the log must not bring back the values from before the changes
+    let h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::open("test_in_place.db");
+    assert_eq!((Some(&10), Some(&21), Some(&30)), (h.get(&1), h.get(&2), h.get(&3)));
+    assert_eq!(303, h.len());
+    fs::remove_file("test_in_place.db").unwrap();
+    fs::remove_file("test_in_place.db.wal").unwrap();
+}
+
+#[test]
+fn uncommitted_transaction_is_not_durable_hashmap() {
+    {
+        let mut h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::new("test_tx.db");
+        h.insert(1, 1);
+        h.flush().unwrap();
+        let mut tx = h.begin();
+        tx.insert(1, 10).unwrap();
+        tx.insert(2, 20).unwrap();
+        mem::forget(tx);
+    }
+    let h = HashMap::<usize, usize, FileBuffer<Elem<usize, usize>>>::open("test_tx.db");
+    assert_eq!(1, h.len());
+    assert_eq!(Some(&1), h.get(&1));
+    fs::remove_file("test_tx.db").unwrap();
+    fs::remove_file("test_tx.db.wal").unwrap();
+}
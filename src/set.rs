@@ -6,6 +6,7 @@ use std::path::Path;
 use std::{fmt};
 
 use map::{HashMap, Elem};
+use batch::WriteBatch;
 use buffer::{Buffer, AnonymousBuffer, FileBuffer};
 
 pub struct HashSet<T, B>
@@ -45,12 +46,36 @@ impl<T> HashSet<T, FileBuffer<Elem<T, ()>>>
         let map = HashMap::<T, (), FileBuffer<Elem<T, ()>>>::try_new(path)?;
         Ok(Self { map: map })
     }
+
+    pub fn open<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        let map = HashMap::<T, (), FileBuffer<Elem<T, ()>>>::open(path);
+        Self { map: map }
+    }
+
+    pub fn try_open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let map = HashMap::<T, (), FileBuffer<Elem<T, ()>>>::try_open(path)?;
+        Ok(Self { map: map })
+    }
 }
 
 impl<T, B> HashSet<T, B>
     where T: 'static + Eq + Hash + Sized,
           B: Buffer<Elem<T, ()>>
 {
+    pub fn try_create_in(buffer: B) -> Result<Self> {
+        let map = HashMap::<T, (), B>::try_create_in(buffer)?;
+        Ok(Self { map: map })
+    }
+
+    pub fn try_open_in(buffer: B) -> Result<Self> {
+        let map = HashMap::<T, (), B>::try_open_in(buffer)?;
+        Ok(Self { map: map })
+    }
+
     pub fn iter<'a>(&'a self) -> Iter<'a, T, B> {
         Iter { iter: self.map.iter() }
     }
@@ -99,6 +124,14 @@ impl<T, B> HashSet<T, B>
     pub fn remove(&mut self, value: T) -> bool {
         self.map.remove(&value)
     }
+
+    pub fn apply(&mut self, batch: WriteBatch<T, ()>) -> Result<()> {
+        self.map.apply(batch)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.map.flush()
+    }
 }
 
 pub struct Iter<'a, T, B>
@@ -1,349 +1,399 @@
 
-use std::{mem, fmt, panic};
-use buffer::{Buffer, AnonymousBuffer, FileBuffer};
-use table::{Page};
-use rand::{Rng, StdRng};
-
-const ORDER: usize = 3;
+use std::{mem, ptr, fmt, cmp};
+use std::io::{Result, Error, ErrorKind};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/* On-page format:
+ *  --
+ *  The tree is a header followed by a run of equally sized blocks. Blocks
+ *  refer to each other by index, never by address, and every structural
+ *  integer is stored little-endian, so the same bytes read back identically
+ *  wherever and by whichever process they are mapped. Keys and values are
+ *  copied bytewise and keep their own representation.
+ *
+ *  Header:  magic [4] | order u32 | block size u32 | block end u32 | free u32 | root u32
+ *  Node:    tag u8 | - | key count u16 | parent u32 | entries below u32 | children [u32; order] | keys [K; order - 1]
+ *  Leaf:    tag u8 | - | key count u16 | parent u32 | next leaf u32 | previous leaf u32 | keys [K; order - 1] | values [V; order - 1]
+ *  Free:    tag u8 | - | - | - | next free u32
+ *
+ *  Blocks are numbered from 1, so index 0 doubles as "none" for parents,
+ *  the free list and leaf links.
+ *  --
+ */
+const TREE_MAGIC: [u8; 4] = *b"OZBT";
+
+const HEADER_ORDER: usize = 4;
+const HEADER_BLOCK_SIZE: usize = 8;
+const HEADER_BLOCKS: usize = 12;
+const HEADER_FREE: usize = 16;
+const HEADER_ROOT: usize = 20;
+const HEADER_SIZE: usize = 24;
+
+const BLOCK_COUNT: usize = 2;
+const BLOCK_PARENT: usize = 4;
+const BLOCK_LINK: usize = 8;
+const BLOCK_PREV: usize = 12;
+const BLOCK_ENTRIES: usize = 8;
+const BLOCK_CHILDREN: usize = 12;
+
+const TAG_FREE: u8 = 0;
+const TAG_NODE: u8 = 1;
+const TAG_LEAF: u8 = 2;
+
+pub const MIN_ORDER: usize = 3;
+const MAX_ORDER: usize = 1 << 16;
+
+#[inline]
+fn align_up(offset: usize, align: usize) -> usize {
+    offset.next_multiple_of(align)
+}
 
-#[derive(Debug)]
-pub struct Free {
-    next: isize,
+#[inline]
+unsafe fn read_u16(at: *const u8) -> usize {
+    u16::from_le_bytes(*(at as *const [u8; 2])) as usize
 }
 
-#[derive(Debug)]
-pub struct Node<K>
-    where K: PartialOrd + Copy + Sized + fmt::Debug,
-{
-    leaf: bool,
-    key_count: u8,
-	keys: [K; ORDER - 1],
-    ptrs: [isize; ORDER],
-	parent: Option<isize>,
+#[inline]
+unsafe fn write_u16(at: *mut u8, value: usize) {
+    *(at as *mut [u8; 2]) = (value as u16).to_le_bytes();
 }
 
-#[derive(Debug)]
-pub struct Bucket<V>
-    where V: Copy + Sized + fmt::Debug,
-{
-    values: [V; 1]
+#[inline]
+unsafe fn read_u32(at: *const u8) -> usize {
+    u32::from_le_bytes(*(at as *const [u8; 4])) as usize
 }
 
-#[derive(Debug)]
-struct Meta<K, V>
-    where K: PartialOrd + Copy + Sized + fmt::Debug,
-          V: Copy + Sized + fmt::Debug,
-{
-    start: *mut Block<K, V>,
-    next: isize,
-    end: *mut Block<K, V>,
-    root: isize,
+#[inline]
+unsafe fn write_u32(at: *mut u8, value: usize) {
+    *(at as *mut [u8; 4]) = (value as u32).to_le_bytes();
 }
 
-#[derive(Debug)]
-enum Block<K, V>
+pub struct BTree<K, V>
     where K: PartialOrd + Copy + Sized + fmt::Debug,
           V: Copy + Sized + fmt::Debug,
 {
-    Free(Free),
-    Node(Node<K>),
-    Bucket(Bucket<V>),
-    Meta(Meta<K, V>),
+    header: [u8; HEADER_SIZE],
+    marker: PhantomData<(K, V)>,
 }
 
-impl<K, V> Block<K, V>
+impl<K, V> BTree<K, V>
     where K: PartialOrd + Copy + Sized + fmt::Debug,
           V: Copy + Sized + fmt::Debug,
 {
-    fn as_free(&self) -> &Free {
-        match *self {
-            Block::Free(ref free) => free,
-            Block::Node(_) => panic!("as_free() called on node"),
-            Block::Bucket(_) => panic!("as_free() called on bucket"),
-            Block::Meta(_) => panic!("as_free() called on meta")
-        }
+    #[inline]
+    fn align() -> usize {
+        cmp::max(cmp::max(mem::align_of::<K>(), mem::align_of::<V>()), 4)
     }
 
-    fn as_free_mut(&mut self) -> &mut Free {
-        match *self {
-            Block::Free(ref mut free) => free,
-            Block::Node(_) => panic!("as_free_mut() called on node"),
-            Block::Bucket(_) => panic!("as_free_mut() called on bucket"),
-            Block::Meta(_) => panic!("as_free_mut() called on meta")
-        }
+    #[inline]
+    fn node_keys(order: usize) -> usize {
+        align_up(BLOCK_CHILDREN + 4 * order, mem::align_of::<K>())
     }
 
-    fn as_node(&self) -> &Node<K> {
-        match *self {
-            Block::Free(_) => panic!("as_node() called on free"),
-            Block::Node(ref node) => node,
-            Block::Bucket(_) => panic!("as_node() called on bucket"),
-            Block::Meta(_) => panic!("as_node() called on meta")
-        }
+    #[inline]
+    fn leaf_keys() -> usize {
+        align_up(BLOCK_PREV + 4, mem::align_of::<K>())
     }
 
-    fn as_node_mut(&mut self) -> &mut Node<K> {
-        match *self {
-            Block::Free(_) => panic!("as_node_mut() called on free"),
-            Block::Node(ref mut node) => node,
-            Block::Bucket(_) => panic!("as_node_mut() called on bucket"),
-            Block::Meta(_) => panic!("as_node_mut() called on meta")
-        }
+    #[inline]
+    fn leaf_values(order: usize) -> usize {
+        align_up(Self::leaf_keys() + (order - 1) * mem::size_of::<K>(), mem::align_of::<V>())
     }
 
-    fn as_bucket(&self) -> &Bucket<V> {
-        match *self {
-            Block::Free(_) => panic!("as_bucket() called on free"),
-            Block::Node(_) => panic!("as_bucket() called on node"),
-            Block::Bucket(ref bucket) => bucket,
-            Block::Meta(_) => panic!("as_bucket() called on meta")
-        }
+    // Blocks are aligned for both K and V, so keys and values can be referenced in place
+    #[inline]
+    fn block_size(order: usize) -> usize {
+        let node = Self::node_keys(order) + (order - 1) * mem::size_of::<K>();
+        let leaf = Self::leaf_values(order) + (order - 1) * mem::size_of::<V>();
+        align_up(cmp::max(node, leaf), Self::align())
     }
 
-    fn as_bucket_mut(&mut self) -> &mut Bucket<V> {
-        match *self {
-            Block::Free(_) => panic!("as_bucket_mut() called on free"),
-            Block::Node(_) => panic!("as_bucket_mut() called on node"),
-            Block::Bucket(ref mut bucket) => bucket,
-            Block::Meta(_) => panic!("as_bucket_mut() called on meta")
+    #[inline]
+    fn header_span() -> usize {
+        align_up(HEADER_SIZE, Self::align())
+    }
+
+    // Largest order whose blocks still fit the region `blocks` times over
+    fn order_for(region: usize, blocks: usize) -> usize {
+        let room = region.saturating_sub(Self::header_span()) / blocks;
+        let (mut low, mut high) = (MIN_ORDER - 1, MAX_ORDER);
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if Self::block_size(mid) <= room {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
         }
+        low
     }
 
-    fn as_meta(&self) -> &Meta<K, V> {
-        match *self {
-            Block::Free(_) => panic!("as_meta() called on free"),
-            Block::Node(_) => panic!("as_meta() called on node"),
-            Block::Bucket(_) => panic!("as_meta() called on bucket"),
-            Block::Meta(ref meta) => meta
-        }
+    // Order of a tree whose whole region is a single node
+    pub fn max_order<T>() -> usize {
+        Self::order_for(mem::size_of::<T>(), 1)
     }
 
-    fn as_meta_mut(&mut self) -> &mut Meta<K, V> {
-        match *self {
-            Block::Free(_) => panic!("as_meta_mut() called on free"),
-            Block::Node(_) => panic!("as_meta_mut() called on node"),
-            Block::Bucket(_) => panic!("as_meta_mut() called on bucket"),
-            Block::Meta(ref mut meta) => meta
-        }
+    #[inline]
+    fn order(&self) -> usize {
+        self.header_field(HEADER_ORDER)
     }
-}
 
-pub struct BTree<K, V>
-    where K: PartialOrd + Copy + Sized + fmt::Debug,
-          V: Copy + Sized + fmt::Debug,
-{
-    meta: Block<K, V>,
-}
+    #[inline]
+    fn block(&self, n: usize) -> *mut u8 {
+        let offset = Self::header_span() + (n - 1) * self.header_field(HEADER_BLOCK_SIZE);
+        unsafe { (self as *const Self as *mut u8).add(offset) }
+    }
 
-impl<K, V> BTree<K, V>
-    where K: PartialOrd + Copy + Sized + fmt::Debug,
-          V: Copy + Sized + fmt::Debug,
-{
-}
+    #[inline]
+    fn header_field(&self, field: usize) -> usize {
+        unsafe { read_u32(self.header[field..].as_ptr()) }
+    }
 
-impl<K, V> BTree<K, V>
-    where K: PartialOrd + Copy + Sized + fmt::Debug,
-          V: Copy + Sized + fmt::Debug,
-{
     #[inline]
-    unsafe fn is_leaf(node: *const Block<K, V>) -> bool {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref node) => node.leaf,
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
-        }
+    fn set_header_field(&mut self, field: usize, value: usize) {
+        unsafe { write_u32(self.header[field..].as_mut_ptr(), value) }
     }
 
     #[inline]
-    unsafe fn parent(node: *const Block<K, V>) -> Option<isize> {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref node) => node.parent,
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
-        }
+    fn blocks(&self) -> usize {
+        self.header_field(HEADER_BLOCKS)
     }
 
     #[inline]
-    unsafe fn parent_mut(node: *mut Block<K, V>) -> *mut Option<isize> {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref mut node) => &mut node.parent,
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
-        }
+    fn root(&self) -> usize {
+        self.header_field(HEADER_ROOT)
     }
 
     #[inline]
-    unsafe fn num_keys(node: *const Block<K, V>) -> u8 {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref node) => node.key_count,
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
-        }
+    fn set_root(&mut self, root: usize) {
+        self.set_header_field(HEADER_ROOT, root)
+    }
+
+    #[inline]
+    fn free(&self) -> usize {
+        self.header_field(HEADER_FREE)
     }
 
     #[inline]
-    unsafe fn num_keys_mut(node: *mut Block<K, V>) -> *mut u8 {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref mut node) => &mut node.key_count,
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
+    fn set_free(&mut self, free: usize) {
+        self.set_header_field(HEADER_FREE, free)
+    }
+
+    #[inline]
+    fn tag(&self, n: usize) -> u8 {
+        unsafe { *self.block(n) }
+    }
+
+    #[inline]
+    fn is_leaf(&self, n: usize) -> bool {
+        match self.tag(n) {
+            TAG_NODE => false,
+            TAG_LEAF => true,
+            _ => unreachable!(),
         }
     }
 
     #[inline]
-    unsafe fn nth_key(node: *const Block<K, V>, n: usize) -> *const K {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref node) => &node.keys[n],
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
+    fn num_keys(&self, n: usize) -> usize {
+        unsafe { read_u16(self.block(n).add(BLOCK_COUNT)) }
+    }
+
+    #[inline]
+    fn set_num_keys(&mut self, n: usize, count: usize) {
+        unsafe { write_u16(self.block(n).add(BLOCK_COUNT), count) }
+    }
+
+    #[inline]
+    fn parent(&self, n: usize) -> Option<usize> {
+        match unsafe { read_u32(self.block(n).add(BLOCK_PARENT)) } {
+            0 => None,
+            parent => Some(parent),
         }
     }
 
     #[inline]
-    unsafe fn nth_key_mut(node: *mut Block<K, V>, n: usize) -> *mut K {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref mut node) => &mut node.keys[n],
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
+    fn set_parent(&mut self, n: usize, parent: Option<usize>) {
+        unsafe { write_u32(self.block(n).add(BLOCK_PARENT), parent.unwrap_or(0)) }
+    }
+
+    // Next leaf for leaves, next free block for free blocks
+    #[inline]
+    fn link(&self, n: usize) -> usize {
+        unsafe { read_u32(self.block(n).add(BLOCK_LINK)) }
+    }
+
+    #[inline]
+    fn set_link(&mut self, n: usize, link: usize) {
+        unsafe { write_u32(self.block(n).add(BLOCK_LINK), link) }
+    }
+
+    #[inline]
+    fn prev(&self, n: usize) -> usize {
+        unsafe { read_u32(self.block(n).add(BLOCK_PREV)) }
+    }
+
+    #[inline]
+    fn set_prev(&mut self, n: usize, prev: usize) {
+        unsafe { write_u32(self.block(n).add(BLOCK_PREV), prev) }
+    }
+
+    // Entries in the subtree under n; nodes keep the count so positions can be found without visiting leaves
+    #[inline]
+    fn entries(&self, n: usize) -> usize {
+        if self.is_leaf(n) {
+            self.num_keys(n)
+        } else {
+            unsafe { read_u32(self.block(n).add(BLOCK_ENTRIES)) }
         }
     }
 
     #[inline]
-    unsafe fn nth_ptr(node: *const Block<K, V>, n: usize) -> *const isize {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref node) => &node.ptrs[n],
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
+    fn set_entries(&mut self, n: usize, entries: usize) {
+        unsafe { write_u32(self.block(n).add(BLOCK_ENTRIES), entries) }
+    }
+
+    // Counts an entry about to be added below a leaf, or one about to be taken away, in every node above it
+    fn count_above(&mut self, leaf: usize, added: bool) {
+        let mut n = leaf;
+        while let Some(parent) = self.parent(n) {
+            let entries = self.entries(parent);
+            self.set_entries(parent, if added { entries + 1 } else { entries - 1 });
+            n = parent;
         }
     }
 
     #[inline]
-    unsafe fn nth_ptr_mut(node: *mut Block<K, V>, n: usize) -> *mut isize {
-        match *node {
-            Block::Free(_) => unreachable!(),
-            Block::Node(ref mut node) => &mut node.ptrs[n],
-            Block::Bucket(_) => unreachable!(),
-            Block::Meta(_) => unreachable!()
-        }
-    }
-
-    unsafe fn find_leaf(&self, key: &K) -> Option<*const Block<K, V>> {
-        let mut i = 0usize;
-        let mut c = self.meta.as_meta().start.offset(self.meta.as_meta().root) as *const _;
-        while !Self::is_leaf(c) {
-            i = 0;
-            while i < Self::num_keys(c) as usize {
-                if *key >= *Self::nth_key(c, i) {
-                    i += 1;
-                } else {
-                    break;
-                }
-            }
-            c = (self.meta.as_meta().start as *const _).offset(*Self::nth_ptr(c, i));
-            if c == self.meta.as_meta().start {
-                return None
+    fn nth_ptr(&self, n: usize, i: usize) -> usize {
+        unsafe { read_u32(self.block(n).add(BLOCK_CHILDREN + 4 * i)) }
+    }
+
+    #[inline]
+    fn set_nth_ptr(&mut self, n: usize, i: usize, ptr: usize) {
+        unsafe { write_u32(self.block(n).add(BLOCK_CHILDREN + 4 * i), ptr) }
+    }
+
+    #[inline]
+    fn nth_key(&self, n: usize, i: usize) -> *mut K {
+        let keys = if self.is_leaf(n) { Self::leaf_keys() } else { Self::node_keys(self.order()) };
+        unsafe { (self.block(n).add(keys) as *mut K).add(i) }
+    }
+
+    #[inline]
+    fn nth_value(&self, n: usize, i: usize) -> *mut V {
+        unsafe { (self.block(n).add(Self::leaf_values(self.order())) as *mut V).add(i) }
+    }
+
+    // Index of the first key of n at or above key, or strictly above it when past is set
+    fn search(&self, n: usize, key: &K, past: bool) -> usize {
+        let (mut low, mut high) = (0, self.num_keys(n));
+        while low < high {
+            let mid = (low + high) / 2;
+            let probe = unsafe { &*self.nth_key(n, mid) };
+            if *probe < *key || past && *probe == *key {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
         }
-        Some(c)
+        low
     }
 
-    unsafe fn find_leaf_mut(&mut self, key: &K) -> Option<*mut Block<K, V>> {
-        let mut i = 0usize;
-        let mut c = self.meta.as_meta().start.offset(self.meta.as_meta().root);
-        while !Self::is_leaf(c) {
-            i = 0;
-            while i < Self::num_keys(c) as usize {
-                if *key >= *Self::nth_key(c, i) {
-                    i += 1;
-                } else {
-                    break;
-                }
-            }
-            c = self.meta.as_meta().start.offset(*Self::nth_ptr(c, i));
-            if c == self.meta.as_meta().start {
+    fn find_leaf(&self, key: &K) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut c = self.root();
+        while !self.is_leaf(c) {
+            c = self.nth_ptr(c, self.search(c, key, true));
+            if c == 0 {
                 return None
             }
         }
         Some(c)
     }
 
-    unsafe fn range_find_blocks<'a>(&self, key_start: &K, key_end: &K) -> Vec<(&K, &'a Block<K, V>)> {
-        let mut found = Vec::new();
-        if let Some(mut n) = self.find_leaf(key_start) {
-            let mut i = 0;
-            while i < Self::num_keys(n) as usize && *Self::nth_key(n, i) < *key_start {
-                i += 1;
-            }
-            if i != Self::num_keys(n) as usize {
-                loop {
-                    for j in i..Self::num_keys(n) as usize {
-                        let key = Self::nth_key(n, j);
-                        if *key > *key_end {
-                            return found;
-                        }
-                        let value = self.meta.as_meta().start.offset(*Self::nth_ptr(n, j));
-                        found.push((&*key, &*value));
-                    }
-                    let ptr = *Self::nth_ptr(n, ORDER - 1);
-                    if ptr == 0 {
-                        break;
-                    } else {
-                        n = self.meta.as_meta().start.offset(ptr);
-                    }
-                    i = 0;
-                }
-            }
+    fn find_entry(&self, key: &K) -> Option<(usize, usize)> {
+        let leaf = self.find_leaf(key)?;
+        let i = self.search(leaf, key, false);
+        if i < self.num_keys(leaf) && unsafe { *self.nth_key(leaf, i) == *key } {
+            Some((leaf, i))
+        } else {
+            None
         }
-        found
+    }
 
+    fn first_leaf(&self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut n = self.root();
+        while !self.is_leaf(n) {
+            n = self.nth_ptr(n, 0);
+        }
+        Some(n)
     }
 
-    unsafe fn find_block<'a>(&self, key: &K) -> Option<&'a Block<K, V>> {
-        let mut i = 0;
-        if let Some(mut c) = self.find_leaf(key) {
-            for j in 0..Self::num_keys(c) {
-                i = j as usize;
-                if *Self::nth_key(c, i) == *key {
-                    break;
-                }
-            }
-            if *Self::nth_key(c, i) == *key {
-                Some(&*(self.meta.as_meta().start as *const _).offset(*Self::nth_ptr(c, i)))
-            } else {
-                None
-            }
-        } else {
-            None
+    fn last_leaf(&self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut n = self.root();
+        while !self.is_leaf(n) {
+            n = self.nth_ptr(n, self.num_keys(n));
         }
+        Some(n)
     }
 
-    unsafe fn find_block_mut<'a>(&mut self, key: &K) -> Option<&'a mut Block<K, V>> {
-        let mut i = 0;
-        if let Some(mut c) = self.find_leaf_mut(key) {
-            for j in 0..Self::num_keys(c) {
-                i = j as usize;
-                if *Self::nth_key(c, i) == *key {
-                    break;
-                }
-            }
-            if *Self::nth_key(c, i) == *key {
-                Some(&mut*(self.meta.as_meta().start as *mut _).offset(*Self::nth_ptr(c, i)))
-            } else {
-                None
+    // Position of the first entry inside a lower bound; it may lie in the leaf after the one the key leads to
+    fn first_within(&self, bound: Bound<&K>) -> Option<(usize, usize)> {
+        let (leaf, i) = match bound {
+            Bound::Unbounded => (self.first_leaf()?, 0),
+            Bound::Included(key) => { let leaf = self.find_leaf(key)?; (leaf, self.search(leaf, key, false)) }
+            Bound::Excluded(key) => { let leaf = self.find_leaf(key)?; (leaf, self.search(leaf, key, true)) }
+        };
+        if i < self.num_keys(leaf) {
+            Some((leaf, i))
+        } else {
+            match self.link(leaf) {
+                0 => None,
+                next => Some((next, 0)),
             }
+        }
+    }
+
+    // Position of the last entry inside an upper bound; it may lie in the leaf before the one the key leads to
+    fn last_within(&self, bound: Bound<&K>) -> Option<(usize, usize)> {
+        let (leaf, i) = match bound {
+            Bound::Unbounded => { let leaf = self.last_leaf()?; (leaf, self.num_keys(leaf)) }
+            Bound::Included(key) => { let leaf = self.find_leaf(key)?; (leaf, self.search(leaf, key, true)) }
+            Bound::Excluded(key) => { let leaf = self.find_leaf(key)?; (leaf, self.search(leaf, key, false)) }
+        };
+        if i > 0 {
+            Some((leaf, i - 1))
         } else {
-            None
+            match self.prev(leaf) {
+                0 => None,
+                prev => Some((prev, self.num_keys(prev) - 1)),
+            }
         }
     }
 
+    #[inline]
+    fn step_forward(&self, (leaf, i): (usize, usize)) -> (usize, usize) {
+        if i + 1 < self.num_keys(leaf) { (leaf, i + 1) } else { (self.link(leaf), 0) }
+    }
+
+    #[inline]
+    fn step_back(&self, (leaf, i): (usize, usize)) -> (usize, usize) {
+        if i > 0 { (leaf, i - 1) } else { let prev = self.prev(leaf); (prev, self.num_keys(prev) - 1) }
+    }
+
+    #[inline]
+    fn entry(&self, (leaf, i): (usize, usize)) -> (&K, &V) {
+        unsafe { (&*self.nth_key(leaf, i), &*self.nth_value(leaf, i)) }
+    }
+
     #[inline]
     fn cut(length: usize) -> usize {
         if length % 2 == 0 {
@@ -353,577 +403,368 @@ impl<K, V> BTree<K, V>
         }
     }
 
-    #[inline]
-    fn offset_to(from: *const Block<K, V>, to: *const Block<K, V>) -> isize {
-        ((to as isize) - (from as isize)) / mem::size_of::<Block<K, V>>() as isize
-    }
-
-    unsafe fn make_node(&mut self) -> isize {
-        let new_next = self.meta.as_meta_mut().start.offset(self.meta.as_meta_mut().next + 1);
-        if new_next > self.meta.as_meta().end {
-            unreachable!()
-        } else {
-            let next = self.meta.as_meta().start.offset(self.meta.as_meta().next);
-            self.meta.as_meta_mut().next = (*next).as_free().next;
-            let new_node = &mut*next;
-            *new_node = Block::Node( Node {
-                leaf: false,
-                key_count: 0,
-                keys: mem::uninitialized(),
-                ptrs: [0; ORDER],
-                parent: None,
-            });
-            Self::offset_to(self.meta.as_meta_mut().start as *const _, new_node as *const _)
-        }
-    }
-
-    unsafe fn make_leaf(&mut self) -> isize {
-        let new_next = self.meta.as_meta_mut().start.offset(self.meta.as_meta_mut().next + 1);
-        if new_next > self.meta.as_meta().end {
-            unreachable!()
-        } else {
-            let next = self.meta.as_meta().start.offset(self.meta.as_meta().next);
-            self.meta.as_meta_mut().next = (*next).as_free().next;
-            let new_node = &mut*next;
-            *new_node = Block::Node( Node {
-                leaf: true,
-                key_count: 0,
-                keys: mem::uninitialized(),
-                ptrs: [0; ORDER],
-                parent: None,
-            });
-            Self::offset_to(self.meta.as_meta_mut().start as *const _, new_node as *const _)
-        }
-    }
-
-    unsafe fn make_bucket(&mut self) -> isize {
-        let new_next = self.meta.as_meta_mut().start.offset(self.meta.as_meta_mut().next + 1);
-        if new_next > self.meta.as_meta().end {
+    fn make_block(&mut self, tag: u8) -> usize {
+        let block = self.free();
+        if block == 0 {
             unreachable!()
-        } else {
-            let next = self.meta.as_meta().start.offset(self.meta.as_meta().next);
-            self.meta.as_meta_mut().next = (*next).as_free().next;
-            let new_bucket = &mut*next;
-            *new_bucket = Block::Bucket( Bucket {
-                values: mem::uninitialized(),
-            });
-            Self::offset_to(self.meta.as_meta_mut().start as *const _, new_bucket as *const _)
         }
+        let next = self.link(block);
+        self.set_free(next);
+        unsafe {
+            ptr::write_bytes(self.block(block), 0, self.header_field(HEADER_BLOCK_SIZE));
+            *self.block(block) = tag;
+        }
+        block
     }
 
-    unsafe fn get_left_index(&self, parent: *const Block<K, V>, left: *const Block<K, V>) -> usize {
+    fn free_block(&mut self, block: usize) {
+        let next = self.free();
+        unsafe { *self.block(block) = TAG_FREE; }
+        self.set_link(block, next);
+        self.set_free(block);
+    }
+
+    fn get_left_index(&self, parent: usize, left: usize) -> usize {
         let mut left_index = 0;
-        while left_index <= Self::num_keys(parent) as usize && *Self::nth_ptr(parent, left_index) != Self::offset_to(self.meta.as_meta().start as *const _, left) {
+        while left_index <= self.num_keys(parent) && self.nth_ptr(parent, left_index) != left {
             left_index += 1;
         }
         left_index
     }
 
-    unsafe fn insert_into_leaf(&mut self, leaf: *mut Block<K, V>, key: K, value: *mut Block<K, V>) {
-        let mut insertion_point = 0;
-        while insertion_point < Self::num_keys(leaf) as usize && *Self::nth_key(leaf, insertion_point) < key {
-            insertion_point += 1;
-        }
-
-        for i in (insertion_point..Self::num_keys(leaf) as usize).rev() {
-            let k_old = Self::nth_key_mut(leaf, i+1);
-            let k_new = Self::nth_key_mut(leaf, i);
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(leaf, i+1);
-            let p_new = Self::nth_ptr_mut(leaf, i);
-            *p_old = *p_new;
+    unsafe fn insert_into_leaf(&mut self, leaf: usize, key: K, value: V) {
+        let nk = self.num_keys(leaf);
+        let insertion_point = self.search(leaf, &key, false);
+        for i in (insertion_point..nk).rev() {
+            *self.nth_key(leaf, i + 1) = *self.nth_key(leaf, i);
+            *self.nth_value(leaf, i + 1) = *self.nth_value(leaf, i);
         }
-        let k_slot = Self::nth_key_mut(leaf, insertion_point);
-        *k_slot = key;
-        let p_slot = Self::nth_ptr_mut(leaf, insertion_point);
-        *p_slot = Self::offset_to(self.meta.as_meta_mut().start, value);
-        let n_keys = Self::num_keys_mut(leaf);
-        *n_keys += 1;
+        *self.nth_key(leaf, insertion_point) = key;
+        *self.nth_value(leaf, insertion_point) = value;
+        self.set_num_keys(leaf, nk + 1);
     }
 
-    unsafe fn split_and_insert_into_leaf(&mut self, leaf: *mut Block<K, V>, key: K, value: *mut Block<K, V>) {
-        let mut temp_keys: [K; ORDER] = mem::uninitialized();
-        let mut temp_ptrs: [isize; ORDER] = mem::uninitialized();
+    unsafe fn split_and_insert_into_leaf(&mut self, leaf: usize, key: K, value: V) {
+        let order = self.order();
+        let nk = self.num_keys(leaf);
+        let insertion_index = self.search(leaf, &key, false);
 
-        let mut insertion_index = 0;
-        let nk = Self::num_keys(leaf);
-        while (insertion_index + 1) < ORDER as usize && *Self::nth_key(leaf, insertion_index) < key {
-            insertion_index += 1;
+        let mut temp_keys = (0..nk).map(|i| *self.nth_key(leaf, i)).collect::<Vec<_>>();
+        let mut temp_values = (0..nk).map(|i| *self.nth_value(leaf, i)).collect::<Vec<_>>();
+        temp_keys.insert(insertion_index, key);
+        temp_values.insert(insertion_index, value);
+
+        let split = order / 2;
+        for i in 0..split {
+            *self.nth_key(leaf, i) = temp_keys[i];
+            *self.nth_value(leaf, i) = temp_values[i];
         }
+        self.set_num_keys(leaf, split);
 
-        for t in (0..(nk as usize)).zip((0..).filter(|&x| x != insertion_index)) {
-            let k_old = &mut temp_keys[t.1];
-            let k_new = Self::nth_key_mut(leaf, t.0);
-            *k_old = *k_new;
-            let p_old = &mut temp_ptrs[t.1];
-            let p_new = Self::nth_ptr_mut(leaf, t.0);
-            *p_old = *p_new;
+        let new_leaf = self.make_block(TAG_LEAF);
+        for (i, j) in (split..order).zip(0..) {
+            *self.nth_key(new_leaf, j) = temp_keys[i];
+            *self.nth_value(new_leaf, j) = temp_values[i];
         }
-        temp_keys[insertion_index] = key;
-        temp_ptrs[insertion_index] = Self::offset_to(self.meta.as_meta_mut().start, value);
-        let mut n_keys = Self::num_keys_mut(leaf);
-        *n_keys = 0;
+        self.set_num_keys(new_leaf, order - split);
 
-        let split = ORDER / 2;
-        for i in 0..split {
-            let k_old = Self::nth_key_mut(leaf, i);
-            let k_new = &mut temp_keys[i];
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(leaf, i);
-            let p_new = &mut temp_ptrs[i];
-            *p_old = *p_new;
-            *n_keys += 1;
-        }
-
-        let new_leaf = self.meta.as_meta_mut().start.offset(self.make_leaf());
-        n_keys = Self::num_keys_mut(new_leaf);
-        for t in (split..ORDER).zip(0..) {
-            let k_old = Self::nth_key_mut(new_leaf, t.1);
-            let k_new = &mut temp_keys[t.0];
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(new_leaf, t.1);
-            let p_new = &mut temp_ptrs[t.0];
-            *p_old = *p_new;
-            *n_keys += 1;
-        }
-        let p_next_old = Self::nth_ptr_mut(new_leaf, ORDER - 1);
-        let p_next_new = Self::nth_ptr_mut(leaf, ORDER - 1);
-        *p_next_old = *p_next_new;
-        let p_next_old = Self::nth_ptr_mut(leaf, ORDER - 1);
-        let p_next_new = Self::offset_to(self.meta.as_meta_mut().start, new_leaf);
-        *p_next_old = p_next_new;
-
-        for i in Self::num_keys(leaf) as usize..(ORDER - 1) {
-            let ptr = Self::nth_ptr_mut(leaf, i);
-            *ptr = 0;
-        }
-        for i in Self::num_keys(new_leaf) as usize..(ORDER - 1) {
-            let ptr = Self::nth_ptr_mut(new_leaf, i);
-            *ptr = 0;
-        }
-
-        let parent_ptr = Self::parent_mut(new_leaf);
-        let leaf_parent = Self::parent(leaf);
-        *parent_ptr = leaf_parent;
-        let new_key = *Self::nth_key(new_leaf, 0);
+        let next = self.link(leaf);
+        self.set_link(new_leaf, next);
+        self.set_prev(new_leaf, leaf);
+        self.set_link(leaf, new_leaf);
+        if next != 0 {
+            self.set_prev(next, new_leaf);
+        }
+
+        let leaf_parent = self.parent(leaf);
+        self.set_parent(new_leaf, leaf_parent);
+        let new_key = *self.nth_key(new_leaf, 0);
         self.insert_into_parent(leaf, new_key, new_leaf)
     }
 
-    unsafe fn insert_into_node(&mut self, n: *mut Block<K, V>, left_index: usize, key: K, right: *mut Block<K, V>) {
-        for i in (left_index..Self::num_keys(n) as usize).rev() {
-            let k_old = Self::nth_key_mut(n, i + 1);
-            let k_new = Self::nth_key_mut(n, i + 0);
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(n, i + 2);
-            let p_new = Self::nth_ptr_mut(n, i + 1);
-            *p_old = *p_new;
-        }
-        let p_old = Self::nth_ptr_mut(n, left_index + 1);
-        *p_old = Self::offset_to(self.meta.as_meta_mut().start, right);
-        let k_old = Self::nth_key_mut(n, left_index);
-        *k_old = key;
-        let n_keys = Self::num_keys_mut(n);
-        *n_keys += 1;
-    }
-
-    unsafe fn split_and_insert_into_node(&mut self, old_node: *mut Block<K, V>, left_index: usize, key: K, right: *mut Block<K, V>) {
-        let mut temp_ptrs: [isize; ORDER + 1] = mem::uninitialized();
-        let mut temp_keys: [K; ORDER] = mem::uninitialized();
-        let nk = Self::num_keys(old_node as *const _) as usize;
-        for t in (0..(nk + 1)).zip((0..).filter(|&x| x != left_index + 1)) {
-            let p = Self::nth_ptr_mut(old_node, t.0);
-            temp_ptrs[t.1] = *p;
-        }
-        for t in (0..nk).zip((0..).filter(|&x| x != left_index)) {
-            let k = Self::nth_key_mut(old_node, t.0);
-            temp_keys[t.1] = *k;
-        }
-        temp_ptrs[left_index + 1] = Self::offset_to(self.meta.as_meta_mut().start, right);
-        temp_keys[left_index] = key;
-
-        let split = Self::cut(ORDER - 1);
-        let new_node = self.meta.as_meta_mut().start.offset(self.make_node());
-        let mut n_keys = Self::num_keys_mut(old_node);
-        *n_keys = 0;
+    unsafe fn insert_into_node(&mut self, n: usize, left_index: usize, key: K, right: usize) {
+        let nk = self.num_keys(n);
+        for i in (left_index..nk).rev() {
+            *self.nth_key(n, i + 1) = *self.nth_key(n, i);
+            let ptr = self.nth_ptr(n, i + 1);
+            self.set_nth_ptr(n, i + 2, ptr);
+        }
+        self.set_nth_ptr(n, left_index + 1, right);
+        *self.nth_key(n, left_index) = key;
+        self.set_num_keys(n, nk + 1);
+    }
+
+    unsafe fn split_and_insert_into_node(&mut self, old_node: usize, left_index: usize, key: K, right: usize) {
+        let order = self.order();
+        let nk = self.num_keys(old_node);
+        let mut temp_keys = (0..nk).map(|i| *self.nth_key(old_node, i)).collect::<Vec<_>>();
+        let mut temp_ptrs = (0..(nk + 1)).map(|i| self.nth_ptr(old_node, i)).collect::<Vec<_>>();
+        temp_keys.insert(left_index, key);
+        temp_ptrs.insert(left_index + 1, right);
+
+        let split = Self::cut(order - 1);
         for i in 0..split {
-            let k_old = Self::nth_key_mut(old_node, i);
-            let k_new = &mut temp_keys[i];
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(old_node, i);
-            let p_new = &mut temp_ptrs[i];
-            *p_old = *p_new;
-            *n_keys += 1;
-        }
-        {
-            let p_old = Self::nth_ptr_mut(old_node, split);
-            let p_new = &mut temp_ptrs[split];
-            *p_old = *p_new;
-        }
-        let mut pivot = temp_keys[split];
-        n_keys = Self::num_keys_mut(new_node);
-        for t in ((split + 1)..ORDER).zip(0..) {
-            let k_old = Self::nth_key_mut(new_node, t.1);
-            let k_new = &mut temp_keys[t.0];
-            *k_old = *k_new;
-            let p_old = Self::nth_ptr_mut(new_node, t.1);
-            let p_new = &mut temp_ptrs[t.0];
-            *p_old = *p_new;
-            *n_keys += 1;
-        }
-        {
-            let p_old = Self::nth_ptr_mut(new_node, ORDER - split - 1);
-            let p_new = &mut temp_ptrs[ORDER];
-            *p_old = *p_new;
-        }
-        let parent_ptr = Self::parent_mut(new_node);
-        let node_parent = Self::parent(old_node);
-        *parent_ptr = node_parent;
-        for i in 0..(Self::num_keys(new_node) as usize + 1) {
-            let child = self.meta.as_meta_mut().start.offset(*Self::nth_ptr(new_node, i));
-            let parent = Self::parent_mut(child);
-            *parent = Some(Self::offset_to(self.meta.as_meta_mut().start, new_node));
+            *self.nth_key(old_node, i) = temp_keys[i];
+            self.set_nth_ptr(old_node, i, temp_ptrs[i]);
+        }
+        self.set_nth_ptr(old_node, split, temp_ptrs[split]);
+        for i in (split + 1)..order {
+            self.set_nth_ptr(old_node, i, 0);
+        }
+        self.set_num_keys(old_node, split);
+        let pivot = temp_keys[split];
+
+        let new_node = self.make_block(TAG_NODE);
+        for (i, j) in ((split + 1)..order).zip(0..) {
+            *self.nth_key(new_node, j) = temp_keys[i];
+            self.set_nth_ptr(new_node, j, temp_ptrs[i]);
+        }
+        self.set_nth_ptr(new_node, order - split - 1, temp_ptrs[order]);
+        self.set_num_keys(new_node, order - split - 1);
+        let moved = (0..(order - split)).map(|i| self.entries(self.nth_ptr(new_node, i))).sum::<usize>();
+        let entries = self.entries(old_node);
+        self.set_entries(new_node, moved);
+        self.set_entries(old_node, entries - moved);
+
+        let node_parent = self.parent(old_node);
+        self.set_parent(new_node, node_parent);
+        for i in 0..(self.num_keys(new_node) + 1) {
+            let child = self.nth_ptr(new_node, i);
+            self.set_parent(child, Some(new_node));
         }
 
         self.insert_into_parent(old_node, pivot, new_node)
     }
 
-    unsafe fn insert_into_parent(&mut self, left: *mut Block<K, V>, key: K, right: *mut Block<K, V>) {
-        let parent = Self::parent(left);
-        if parent.is_none() {
-            self.insert_into_new_root(left, key, right)
-        } else {
-            let parent = self.meta.as_meta_mut().start.offset(parent.unwrap());
-            let left_index = self.get_left_index(parent, left);
-            
-            if (Self::num_keys(parent) as usize + 1) < ORDER {
-                self.insert_into_node(parent, left_index, key, right)
-            } else {
-                self.split_and_insert_into_node(parent, left_index, key, right)
+    unsafe fn insert_into_parent(&mut self, left: usize, key: K, right: usize) {
+        match self.parent(left) {
+            None => self.insert_into_new_root(left, key, right),
+            Some(parent) => {
+                let left_index = self.get_left_index(parent, left);
+                if self.num_keys(parent) + 1 < self.order() {
+                    self.insert_into_node(parent, left_index, key, right)
+                } else {
+                    self.split_and_insert_into_node(parent, left_index, key, right)
+                }
             }
         }
     }
 
-    unsafe fn insert_into_new_root(&mut self, left: *mut Block<K, V>, key: K, right: *mut Block<K, V>) {
-        let root = self.make_node();
-        let prnt = Self::parent_mut(left);
-        *prnt = Some(root);
-        let prnt = Self::parent_mut(right);
-        *prnt = Some(root);
-        self.meta.as_meta_mut().root = root;
-
-        let root = self.meta.as_meta_mut().start.offset(root);
-        let r_key0 = Self::nth_key_mut(root, 0);
-        *r_key0 = key;
-        let r_ptr0 = Self::nth_ptr_mut(root, 0);
-        *r_ptr0 = Self::offset_to(self.meta.as_meta_mut().start, left);
-        let r_ptr1 = Self::nth_ptr_mut(root, 1);
-        *r_ptr1 = Self::offset_to(self.meta.as_meta_mut().start, right);
-        let r_nkey = Self::num_keys_mut(root);
-        *r_nkey += 1;
-        let r_prnt = Self::parent_mut(root);
-        *r_prnt = None;
-    }
-
-    unsafe fn start_new_tree(&mut self, key: K, value: *mut Block<K, V>) {
-        let root = self.make_leaf();
-        self.meta.as_meta_mut().root = root;
-        let root = self.meta.as_meta_mut().start.offset(root);
-        let r_key0 = Self::nth_key_mut(root, 0);
-        *r_key0 = key;
-        let r_ptr0 = Self::nth_ptr_mut(root, 0);
-        *r_ptr0 = Self::offset_to(self.meta.as_meta_mut().start, value);
-        let r_nkey = Self::num_keys_mut(root);
-        *r_nkey = 1;
-    }
-
-    unsafe fn get_neighbour_index(&self, n: *mut Block<K, V>) -> Option<usize> {
-        let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-        for i in 0..(Self::num_keys(nparent) as usize + 1) {
-            let neighbour = Self::nth_ptr(nparent, i);
-            if self.meta.as_meta().start.offset(*neighbour) == n {
+    unsafe fn insert_into_new_root(&mut self, left: usize, key: K, right: usize) {
+        let root = self.make_block(TAG_NODE);
+        self.set_parent(left, Some(root));
+        self.set_parent(right, Some(root));
+        self.set_root(root);
+
+        *self.nth_key(root, 0) = key;
+        self.set_nth_ptr(root, 0, left);
+        self.set_nth_ptr(root, 1, right);
+        self.set_num_keys(root, 1);
+        let entries = self.entries(left) + self.entries(right);
+        self.set_entries(root, entries);
+    }
+
+    unsafe fn start_new_tree(&mut self, key: K, value: V) {
+        let root = self.make_block(TAG_LEAF);
+        self.set_root(root);
+        *self.nth_key(root, 0) = key;
+        *self.nth_value(root, 0) = value;
+        self.set_num_keys(root, 1);
+    }
+
+    fn get_neighbour_index(&self, n: usize) -> Option<usize> {
+        let nparent = self.parent(n).unwrap();
+        for i in 0..(self.num_keys(nparent) + 1) {
+            if self.nth_ptr(nparent, i) == n {
                 return if i == 0 { None } else { Some(i - 1) };
             }
         }
         unreachable!("Search for nonexistent pointer to node in parent")
     }
 
-    unsafe fn remove_entry_from_node(&mut self, n: *mut Block<K, V>, key: &K, value: *mut Block<K, V>) -> usize {
-        let mut key_ix = 0;
-        for i in 0..Self::num_keys(n) as usize {
-            if *Self::nth_key(n, i) == *key {
-                key_ix = i;
-                break;
-            }
-        }
-        for i in (key_ix + 1)..Self::num_keys(n) as usize {
-            let k_old = Self::nth_key_mut(n, i - 1);
-            let k_new = Self::nth_key_mut(n, i);
-            *k_old = *k_new;
+    // Removes key from a leaf along with its value, or from a node along with the child to its right
+    unsafe fn remove_entry_from_node(&mut self, n: usize, key: &K, child: usize) -> usize {
+        let nk = self.num_keys(n);
+        let key_ix = (0..nk).find(|&i| *self.nth_key(n, i) == *key).unwrap_or(0);
+        for i in (key_ix + 1)..nk {
+            *self.nth_key(n, i - 1) = *self.nth_key(n, i);
         }
 
-        let num_pointers = if Self::is_leaf(n) { Self::num_keys(n) } else { Self::num_keys(n) + 1 } as usize;
-        let mut ptr_ix = 0;
-        for i in 0..(Self::num_keys(n) as usize + 1) {
-            if *Self::nth_ptr(n, i) == Self::offset_to(self.meta.as_meta().start, value) {
-                ptr_ix = i;
-                break;
-            }
-        }
-        for i in (ptr_ix + 1)..num_pointers {
-            let p_old = Self::nth_ptr_mut(n, i - 1);
-            let p_new = Self::nth_ptr_mut(n, i);
-            *p_old = *p_new;
-        }
-
-        let n_keys = Self::num_keys_mut(n);
-        *n_keys -= 1;
-
-        if Self::is_leaf(n) {
-            for i in Self::num_keys(n) as usize..(ORDER - 1) {
-                let ptr = Self::nth_ptr_mut(n, i);
-                *ptr = 0;
+        if self.is_leaf(n) {
+            for i in (key_ix + 1)..nk {
+                *self.nth_value(n, i - 1) = *self.nth_value(n, i);
             }
         } else {
-            for i in (Self::num_keys(n) as usize + 1)..ORDER {
-                let ptr = Self::nth_ptr_mut(n, i);
-                *ptr = 0;
+            let ptr_ix = (0..(nk + 1)).find(|&i| self.nth_ptr(n, i) == child).unwrap_or(0);
+            for i in (ptr_ix + 1)..(nk + 1) {
+                let ptr = self.nth_ptr(n, i);
+                self.set_nth_ptr(n, i - 1, ptr);
             }
+            self.set_nth_ptr(n, nk, 0);
         }
+
+        self.set_num_keys(n, nk - 1);
         key_ix
     }
 
-    unsafe fn adjust_root(&mut self) {
-        let root = self.meta.as_meta_mut().start.offset(self.meta.as_meta().root);
-        if Self::num_keys(root) == 0 {
-            if Self::is_leaf(root) {
-                self.meta.as_meta_mut().root = 0;
+    fn adjust_root(&mut self) {
+        let root = self.root();
+        if self.num_keys(root) == 0 {
+            if self.is_leaf(root) {
+                self.set_root(0);
             } else {
-                let new_root = Self::nth_ptr(root, 0);
-                self.meta.as_meta_mut().root = *new_root;
-                let parent = Self::parent_mut(self.meta.as_meta().start.offset(*new_root));
-                *parent = None;
+                let new_root = self.nth_ptr(root, 0);
+                self.set_root(new_root);
+                self.set_parent(new_root, None);
             }
-            *root = Block::Free(Free { next: self.meta.as_meta().next });
-            self.meta.as_meta_mut().next = Self::offset_to(self.meta.as_meta().start, root);
+            self.free_block(root);
         }
     }
 
-    unsafe fn merge_nodes(&mut self, mut n: *mut Block<K, V>, mut neighbour: *mut Block<K, V>, neighbour_index: Option<usize>, pivot: *const K) {
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
-        if neighbour_index.is_none() {
-            let tmp = n;
-            n = neighbour;
-            neighbour = tmp;
-        }
+    unsafe fn merge_nodes(&mut self, n: usize, neighbour: usize, neighbour_index: Option<usize>, pivot: K) {
+        // Always merge right into left
+        let (n, neighbour) = if neighbour_index.is_none() { (neighbour, n) } else { (n, neighbour) };
 
-        let neighbour_insertion_index = Self::num_keys(neighbour) as usize;
+        let neighbour_insertion_index = self.num_keys(neighbour);
 
-        if !Self::is_leaf(n) {
-            let k_nei = Self::nth_key_mut(neighbour, neighbour_insertion_index);
-            *k_nei = *pivot;
-            let n_key = Self::num_keys_mut(neighbour);
-            *n_key += 1;
-
-            let n_end = Self::num_keys(n) as usize;
-
-            for t in ((neighbour_insertion_index + 1)..).zip(0..n_end) {
-                let k_old = Self::nth_key_mut(neighbour, t.0);
-                let k_new = Self::nth_key_mut(n, t.1);
-                *k_old = *k_new;
-                let p_old = Self::nth_ptr_mut(neighbour, t.0);
-                let p_new = Self::nth_ptr_mut(n, t.1);
-                *p_old = *p_new;
-                let nei_keys = Self::num_keys_mut(neighbour);
-                let n_keys = Self::num_keys_mut(n);
-                *nei_keys += 1;
-                *n_keys -= 1;
+        if !self.is_leaf(n) {
+            *self.nth_key(neighbour, neighbour_insertion_index) = pivot;
+            let n_end = self.num_keys(n);
+            for (i, j) in ((neighbour_insertion_index + 1)..).zip(0..n_end) {
+                *self.nth_key(neighbour, i) = *self.nth_key(n, j);
+                let ptr = self.nth_ptr(n, j);
+                self.set_nth_ptr(neighbour, i, ptr);
             }
-
-            let p_old = Self::nth_ptr_mut(neighbour, neighbour_insertion_index + n_end + 1);
-            let p_new = Self::nth_ptr_mut(n, n_end);
-            *p_old = *p_new;
-
-            for i in 0..(Self::num_keys(neighbour) as usize + 1) {
-                let offset = *Self::nth_ptr(neighbour, i);
-                let node = self.meta.as_meta().start.offset(offset);
-                let parent = Self::parent_mut(node);
-                *parent = Some(Self::offset_to(self.meta.as_meta().start, neighbour));
+            let ptr = self.nth_ptr(n, n_end);
+            self.set_nth_ptr(neighbour, neighbour_insertion_index + n_end + 1, ptr);
+            self.set_num_keys(neighbour, neighbour_insertion_index + n_end + 1);
+            let entries = self.entries(neighbour) + self.entries(n);
+            self.set_entries(neighbour, entries);
+
+            for i in 0..(self.num_keys(neighbour) + 1) {
+                let child = self.nth_ptr(neighbour, i);
+                self.set_parent(child, Some(neighbour));
             }
         } else {
-            for t in (neighbour_insertion_index..).zip(0..Self::num_keys(n) as usize) {
-                let k_old = Self::nth_key_mut(neighbour, t.0);
-                let k_new = Self::nth_key_mut(n, t.1);
-                *k_old = *k_new;
-                let p_old = Self::nth_ptr_mut(neighbour, t.0);
-                let p_new = Self::nth_ptr_mut(n, t.1);
-                *p_old = *p_new;
-                let nei_keys = Self::num_keys_mut(neighbour);
-                *nei_keys += 1;
+            let n_end = self.num_keys(n);
+            for (i, j) in (neighbour_insertion_index..).zip(0..n_end) {
+                *self.nth_key(neighbour, i) = *self.nth_key(n, j);
+                *self.nth_value(neighbour, i) = *self.nth_value(n, j);
+            }
+            self.set_num_keys(neighbour, neighbour_insertion_index + n_end);
+            let next = self.link(n);
+            self.set_link(neighbour, next);
+            if next != 0 {
+                self.set_prev(next, neighbour);
             }
-            let p_old = Self::nth_ptr_mut(neighbour, ORDER - 1);
-            let p_new = Self::nth_ptr_mut(n, ORDER - 1);
-            *p_old = *p_new;
-        }
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
         }
 
-        let parent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-        self.delete_entry(parent, &*pivot, n);
-        *n = Block::Free(Free { next: self.meta.as_meta().next });
-        self.meta.as_meta_mut().next = Self::offset_to(self.meta.as_meta().start, n);
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
+        let parent = self.parent(n).unwrap();
+        self.delete_entry(parent, &pivot, n);
+        self.free_block(n);
     }
 
-    unsafe fn redistribute_nodes(&mut self, n: *mut Block<K, V>, neighbour: *mut Block<K, V>, neighbour_index: Option<usize>, pivot_index: usize, pivot: *const K) {
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
+    // Carries the entries under a child that changes parents from one node to the other
+    fn move_entries(&mut self, from: usize, to: usize, child: usize) {
+        let moved = self.entries(child);
+        let (from_entries, to_entries) = (self.entries(from), self.entries(to));
+        self.set_entries(from, from_entries - moved);
+        self.set_entries(to, to_entries + moved);
+    }
+
+    unsafe fn redistribute_nodes(&mut self, n: usize, neighbour: usize, neighbour_index: Option<usize>, pivot_index: usize, pivot: K) {
+        let nparent = self.parent(n).unwrap();
+        let nk = self.num_keys(n);
+        let nnk = self.num_keys(neighbour);
         if neighbour_index.is_some() {
-            let nk = Self::num_keys(n) as usize;
-            if !Self::is_leaf(n) {
-                let p_old = Self::nth_ptr_mut(n, nk + 1);
-                let p_new = Self::nth_ptr_mut(n, nk);
-                *p_old = *p_new;
-            }
+            // Take the last entry of the left neighbour
             for i in (1..(nk + 1)).rev() {
-                let k_old = Self::nth_key_mut(n, i);
-                let k_new = Self::nth_key_mut(n, i - 1);
-                *k_old = *k_new;
-                let p_old = Self::nth_ptr_mut(n, i);
-                let p_new = Self::nth_ptr_mut(n, i - 1);
-                *p_old = *p_new;
+                *self.nth_key(n, i) = *self.nth_key(n, i - 1);
             }
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
-            let nk = Self::num_keys(neighbour) as usize;
-            if !Self::is_leaf(n) {
-                let p_old = Self::nth_ptr_mut(n, 0);
-                let p_new = Self::nth_ptr_mut(neighbour, nk);
-                *p_old = *p_new;
-                let offset = *Self::nth_ptr(n, 0);
-                let node = self.meta.as_meta().start.offset(offset);
-                let parent = Self::parent_mut(node);
-                *parent = Some(Self::offset_to(self.meta.as_meta().start, n));
-
-                let ptr = Self::nth_ptr_mut(neighbour, nk);
-                *ptr = 0;
-                let key0 = Self::nth_key_mut(n, 0);
-                *key0 = *pivot;
-                let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-                let keyn = Self::nth_key_mut(nparent, pivot_index);
-                let nkey = Self::nth_key_mut(neighbour, nk - 1);
-                *keyn = *nkey;
+            if !self.is_leaf(n) {
+                for i in (1..(nk + 2)).rev() {
+                    let ptr = self.nth_ptr(n, i - 1);
+                    self.set_nth_ptr(n, i, ptr);
+                }
+                let child = self.nth_ptr(neighbour, nnk);
+                self.set_nth_ptr(n, 0, child);
+                self.set_parent(child, Some(n));
+                self.move_entries(neighbour, n, child);
+                self.set_nth_ptr(neighbour, nnk, 0);
+                *self.nth_key(n, 0) = pivot;
+                *self.nth_key(nparent, pivot_index) = *self.nth_key(neighbour, nnk - 1);
             } else {
-                let p_old = Self::nth_ptr_mut(n, 0);
-                let p_new = Self::nth_ptr_mut(neighbour, nk - 1);
-                *p_old = *p_new;
-                let ptr = Self::nth_ptr_mut(neighbour, nk - 1);
-                *ptr = 0;
-                let key0 = Self::nth_key_mut(n, 0);
-                let nkey = Self::nth_key_mut(neighbour, nk - 1);
-                *key0 = *nkey;
-                let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-                let keyn = Self::nth_key_mut(nparent, pivot_index);
-                *keyn = *key0;
+                for i in (1..(nk + 1)).rev() {
+                    *self.nth_value(n, i) = *self.nth_value(n, i - 1);
+                }
+                *self.nth_value(n, 0) = *self.nth_value(neighbour, nnk - 1);
+                *self.nth_key(n, 0) = *self.nth_key(neighbour, nnk - 1);
+                *self.nth_key(nparent, pivot_index) = *self.nth_key(n, 0);
             }
         } else {
-            let nk = Self::num_keys(n) as usize;
-            if Self::is_leaf(n) {
-                let k_old = Self::nth_key_mut(n, nk);
-                let k_new = Self::nth_key_mut(neighbour, 0);
-                *k_old = *k_new;
-                let p_old = Self::nth_ptr_mut(n, nk);
-                let p_new = Self::nth_ptr_mut(neighbour, 0);
-                *p_old = *p_new;
-                let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-                let keyn  = Self::nth_key_mut(nparent, pivot_index);
-                let nkey1 = Self::nth_key_mut(neighbour, 1);
-                *keyn = *nkey1;
+            // Take the first entry of the right neighbour
+            if self.is_leaf(n) {
+                *self.nth_key(n, nk) = *self.nth_key(neighbour, 0);
+                *self.nth_value(n, nk) = *self.nth_value(neighbour, 0);
+                *self.nth_key(nparent, pivot_index) = *self.nth_key(neighbour, 1);
+                for i in 0..(nnk - 1) {
+                    *self.nth_key(neighbour, i) = *self.nth_key(neighbour, i + 1);
+                    *self.nth_value(neighbour, i) = *self.nth_value(neighbour, i + 1);
+                }
             } else {
-                let keyn = Self::nth_key_mut(n, nk);
-                *keyn = *pivot;
-                let p_old = Self::nth_ptr_mut(n, nk + 1);
-                let p_new = Self::nth_ptr_mut(neighbour, 0);
-                *p_old = *p_new;
-                let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-                let keyn = Self::nth_key_mut(nparent, pivot_index);
-                let nkey0 = Self::nth_key_mut(neighbour, 0);
-                *keyn = *nkey0
-            }
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
-            let nk = Self::num_keys(neighbour) as usize;
-            for i in 0..(nk - 1) {
-                let k_old = Self::nth_key_mut(neighbour, i);
-                let k_new = Self::nth_key_mut(neighbour, i + 1);
-                *k_old = *k_new;
-                let p_old = Self::nth_ptr_mut(neighbour, i);
-                let p_new = Self::nth_ptr_mut(neighbour, i + 1);
-                *p_old = *p_new;
-            }
-            if !Self::is_leaf(n) {
-                let p_old = Self::nth_ptr_mut(neighbour, nk - 1);
-                let p_new = Self::nth_ptr_mut(neighbour, nk);
-                *p_old = *p_new;
+                *self.nth_key(n, nk) = pivot;
+                let child = self.nth_ptr(neighbour, 0);
+                self.set_nth_ptr(n, nk + 1, child);
+                self.set_parent(child, Some(n));
+                self.move_entries(neighbour, n, child);
+                *self.nth_key(nparent, pivot_index) = *self.nth_key(neighbour, 0);
+                for i in 0..(nnk - 1) {
+                    *self.nth_key(neighbour, i) = *self.nth_key(neighbour, i + 1);
+                }
+                for i in 0..nnk {
+                    let ptr = self.nth_ptr(neighbour, i + 1);
+                    self.set_nth_ptr(neighbour, i, ptr);
+                }
+                self.set_nth_ptr(neighbour, nnk, 0);
             }
         }
 
-        let n_key = Self::num_keys_mut(n);
-        *n_key += 1;
-        let nei_key = Self::num_keys_mut(neighbour);
-        *nei_key -= 1;
-        if !Self::is_leaf(n) {
-            let ptrn = Self::nth_ptr_mut(n, *n_key as usize);
-            let oldparent = Self::parent_mut(self.meta.as_meta().start.offset(*ptrn));
-            *oldparent = Some(Self::offset_to(self.meta.as_meta().start, n));
-        }
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
+        self.set_num_keys(n, nk + 1);
+        self.set_num_keys(neighbour, nnk - 1);
     }
 
-    unsafe fn delete_entry(&mut self, n: *mut Block<K, V>, key: &K, value: *mut Block<K, V>) {
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
-        let ix = self.remove_entry_from_node(n, key, value);
+    unsafe fn delete_entry(&mut self, n: usize, key: &K, child: usize) {
+        let ix = self.remove_entry_from_node(n, key, child);
 
-        if n == self.meta.as_meta().start.offset(self.meta.as_meta().root) {
+        if n == self.root() {
             self.adjust_root();
             return;
-        } 
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
         }
 
-        if ix == 0 && n != self.meta.as_meta().start.offset(self.meta.as_meta().root) {
-            let mut parent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-            let mut parent_ix = 0;
-            while self.meta.as_meta().start.offset(*Self::nth_ptr(parent, parent_ix)) != n {
-                parent_ix += 1;
-            }
-            let mut next_smallest = if Self::num_keys(n) == 0 {
-                if parent_ix == Self::num_keys(parent) as usize {
+        // Separators are copies of leaf keys; a pivot leaving an inner node appears nowhere above it
+        if ix == 0 && self.is_leaf(n) {
+            let mut parent = self.parent(n).unwrap();
+            let mut parent_ix = self.get_left_index(parent, n);
+            let next_smallest = if self.num_keys(n) == 0 {
+                if parent_ix == self.num_keys(parent) {
                     None
                 } else {
-                    let next_child = self.meta.as_meta().start.offset(*Self::nth_ptr(parent, parent_ix + 1));
-                    Some(*Self::nth_key(next_child, 0))
+                    let next_child = self.nth_ptr(parent, parent_ix + 1);
+                    Some(*self.nth_key(next_child, 0))
                 }
             } else {
-                Some(*Self::nth_key(n, 0))
+                Some(*self.nth_key(n, 0))
             };
             if let Some(replacement) = next_smallest {
                 loop {
                     if parent_ix > 0 {
-                        let parent_key = Self::nth_key_mut(parent, parent_ix - 1);
+                        let parent_key = self.nth_key(parent, parent_ix - 1);
                         if *parent_key == *key {
                             *parent_key = replacement;
                         }
                     }
-                    if let Some(offset) = Self::parent(parent) {
-                        let grandparent = self.meta.as_meta().start.offset(offset);
-                        parent_ix = 0;
-                        while self.meta.as_meta().start.offset(*Self::nth_ptr(grandparent, parent_ix)) != parent {
-                            parent_ix += 1;
-                        }
+                    if let Some(grandparent) = self.parent(parent) {
+                        parent_ix = self.get_left_index(grandparent, parent);
                         parent = grandparent;
                     } else {
                         break;
@@ -931,190 +772,411 @@ impl<K, V> BTree<K, V>
                 }
             }
         }
-        let min_keys = Self::cut(ORDER) - 1;
-        if (Self::num_keys(n) as usize) < min_keys {
+
+        let min_keys = Self::cut(self.order()) - 1;
+        if self.num_keys(n) < min_keys {
             let neighbour_index = self.get_neighbour_index(n);
             let pivot_idx = neighbour_index.unwrap_or(0);
-            let nparent = self.meta.as_meta().start.offset(Self::parent(n).unwrap());
-            let pivot = Self::nth_key_mut(nparent, pivot_idx);
-            let neighbour_offset = if neighbour_index.is_none() { Self::nth_ptr_mut(nparent, 1) } else { Self::nth_ptr_mut(nparent, neighbour_index.unwrap()) };
-            let neighbour = self.meta.as_meta().start.offset(*neighbour_offset);
+            let nparent = self.parent(n).unwrap();
+            let pivot = *self.nth_key(nparent, pivot_idx);
+            let neighbour = self.nth_ptr(nparent, neighbour_index.map_or(1, |i| i));
 
-            let min_keys = Self::cut(ORDER) - 1;
-            if Self::num_keys(neighbour) as usize > min_keys {
+            if self.num_keys(neighbour) > min_keys {
                 self.redistribute_nodes(n, neighbour, neighbour_index, pivot_idx, pivot);
             } else {
                 self.merge_nodes(n, neighbour, neighbour_index, pivot);
             }
         }
-        for i in 0..Self::offset_to(self.meta.as_meta().start, self.meta.as_meta().end) {
-        }
     }
-    
+
     pub fn pop_front(&mut self) -> Option<(K, V)> {
-        unsafe {
-            if self.is_empty() {
-                None
-            } else {
-                let mut node = self.meta.as_meta().start.offset(self.meta.as_meta().root);
-                while !Self::is_leaf(node) {
-                    node = self.meta.as_meta().start.offset(*Self::nth_ptr(node, 0));
-                }
-                let key = *Self::nth_key(node, 0);
-                let bucket = self.meta.as_meta().start.offset(*Self::nth_ptr(node, 0));
-                let value = (*bucket).as_bucket().values[0];
-                self.delete(&key);
-                Some((key, value))
-            }
-        }
+        let (&key, &value) = self.first()?;
+        self.delete(&key);
+        Some((key, value))
     }
-    
+
     pub fn pop_back(&mut self) -> Option<(K, V)> {
-        unsafe {
-            if self.is_empty() {
-                None
-            } else {
-                let mut node = self.meta.as_meta().start.offset(self.meta.as_meta().root);
-                while !Self::is_leaf(node) {
-                    node = self.meta.as_meta().start.offset(*Self::nth_ptr(node, Self::num_keys(node) as usize - 1));
-                }
-                let key = *Self::nth_key(node, 0);
-                let bucket = self.meta.as_meta().start.offset(*Self::nth_ptr(node, 0));
-                let value = (*bucket).as_bucket().values[0];
-                self.delete(&key);
-                Some((key, value))
-            }
-        }
+        let (&key, &value) = self.last()?;
+        self.delete(&key);
+        Some((key, value))
     }
 
     pub fn delete(&mut self, key: &K) -> bool {
-        unsafe {
-            if let Some(key_record) = self.find_block_mut(key) {
-                if let Some(key_leaf) = self.find_leaf_mut(key) {
-                    let value = &mut*key_record as *mut _;
-                    self.delete_entry(key_leaf, key, value);
-                    *value = Block::Free(Free { next: self.meta.as_meta().next });
-                    self.meta.as_meta_mut().next = Self::offset_to(self.meta.as_meta().start, value);
-                    return true;
-                }
-            }
-            return false;
+        if let Some((leaf, _)) = self.find_entry(key) {
+            self.count_above(leaf, false);
+            unsafe { self.delete_entry(leaf, key, 0); }
+            true
+        } else {
+            false
         }
     }
 
+    // False when the key is already present or there is no room left
     pub fn insert(&mut self, key: K, value: V) -> bool {
+        if self.find_entry(&key).is_some() {
+            return false;
+        }
         unsafe {
-            if self.is_empty() {
-                let block = self.meta.as_meta_mut().start.offset(self.make_bucket());
-                (*block).as_bucket_mut().values[0] = value;
-                self.start_new_tree(key, block);
-                true
-            } else if true || self.find_block(&key).is_none() {
-                if self.is_full() {
-                    false
-                } else {
-                    let block = self.meta.as_meta_mut().start.offset(self.make_bucket());
-                    (*block).as_bucket_mut().values[0] = value;
-                    let leaf = self.find_leaf_mut(&key).unwrap();
-                    if (Self::num_keys(leaf) as usize + 1) < ORDER {
-                        self.insert_into_leaf(leaf, key, block);
-                    } else {
-                        self.split_and_insert_into_leaf(leaf, key, block);
-                    }
-                    true
+            match self.find_leaf(&key) {
+                None if self.free() != 0 => self.start_new_tree(key, value),
+                Some(leaf) if self.num_keys(leaf) + 1 < self.order() => {
+                    self.count_above(leaf, true);
+                    self.insert_into_leaf(leaf, key, value)
                 }
-            } else {
-                true
+                // A split takes a leaf, a node per inner level and a new root
+                Some(leaf) if self.free_blocks() > self.height() => {
+                    self.count_above(leaf, true);
+                    self.split_and_insert_into_leaf(leaf, key, value)
+                }
+                _ => return false,
             }
         }
+        true
     }
 
     pub fn find(&self, key: &K) -> Option<&V> {
-        unsafe {
-            self.find_block(key).and_then(|ref x| Some(&x.as_bucket().values[0] as *const _)).and_then(|x| x.as_ref())
-        }
+        self.find_entry(key).map(|(leaf, i)| unsafe { &*self.nth_value(leaf, i) })
+    }
+
+    pub fn find_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.find_entry(key).map(|(leaf, i)| unsafe { &mut*self.nth_value(leaf, i) })
     }
 
+    #[cfg(test)]
     pub fn range_find(&self, key_start: &K, key_end: &K) -> Vec<(&K, &V)> {
-        unsafe {
-            self.range_find_blocks(key_start, key_end).iter().map(|x| (x.0, &x.1.as_bucket().values[0])).collect::<Vec<_>>()
+        self.range((Bound::Included(key_start), Bound::Included(key_end))).collect()
+    }
+
+    // Entries within the bounds in key order, read lazily from either end along the leaf links
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V> {
+        let ends = match (self.first_within(range.start_bound()), self.last_within(range.end_bound())) {
+            (Some(front), Some(back)) if unsafe { *self.nth_key(front.0, front.1) <= *self.nth_key(back.0, back.1) } => Some((front, back)),
+            _ => None,
+        };
+        Range { tree: self, ends: ends }
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V> {
+        self.range(..)
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.first_within(Bound::Unbounded).map(|at| self.entry(at))
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        self.last_within(Bound::Unbounded).map(|at| self.entry(at))
+    }
+
+    // Greatest entry at or below key
+    pub fn floor(&self, key: &K) -> Option<(&K, &V)> {
+        self.last_within(Bound::Included(key)).map(|at| self.entry(at))
+    }
+
+    // Least entry at or above key
+    pub fn ceiling(&self, key: &K) -> Option<(&K, &V)> {
+        self.first_within(Bound::Included(key)).map(|at| self.entry(at))
+    }
+
+    // Greatest entry strictly below key
+    pub fn predecessor(&self, key: &K) -> Option<(&K, &V)> {
+        self.last_within(Bound::Excluded(key)).map(|at| self.entry(at))
+    }
+
+    // Least entry strictly above key
+    pub fn successor(&self, key: &K) -> Option<(&K, &V)> {
+        self.first_within(Bound::Excluded(key)).map(|at| self.entry(at))
+    }
+
+    // Entry at position i in key order, skipping whole subtrees by their counts
+    pub fn nth(&self, mut i: usize) -> Option<(&K, &V)> {
+        if i >= self.len() {
+            return None;
+        }
+        let mut n = self.root();
+        while !self.is_leaf(n) {
+            let mut child = 0;
+            while i >= self.entries(self.nth_ptr(n, child)) {
+                i -= self.entries(self.nth_ptr(n, child));
+                child += 1;
+            }
+            n = self.nth_ptr(n, child);
         }
+        Some(self.entry((n, i)))
+    }
+
+    // Number of entries below key, which is also the position key has or would have
+    pub fn rank(&self, key: &K) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let mut rank = 0;
+        let mut n = self.root();
+        while !self.is_leaf(n) {
+            let child = self.search(n, key, true);
+            rank += (0..child).map(|i| self.entries(self.nth_ptr(n, i))).sum::<usize>();
+            n = self.nth_ptr(n, child);
+        }
+        rank + self.search(n, key, false)
+    }
+
+    pub fn len(&self) -> usize {
+        if self.is_empty() { 0 } else { self.entries(self.root()) }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.meta.as_meta().root == 0
+        self.root() == 0
     }
 
-    pub fn is_full(&self) -> bool {
-        unsafe {
-            self.meta.as_meta().start.offset(self.meta.as_meta().next + 1) > self.meta.as_meta().end
+    fn free_blocks(&self) -> usize {
+        let mut count = 0;
+        let mut next = self.free();
+        while next != 0 && count < self.blocks() {
+            count += 1;
+            next = self.link(next);
         }
+        count
     }
 
-    pub fn load_from<'a, T>(data: &mut T) -> &'a mut Self {
-        let btree = unsafe { &mut*(data as *mut T as *mut Self) };
-        unsafe {
-            btree.meta.as_meta_mut().start = (&mut btree.meta.as_meta_mut() as *mut _ as *mut _).offset(0);
-            btree.meta.as_meta_mut().end = (data as *mut _).offset(1) as *mut _;
+    fn height(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let mut height = 1;
+        let mut node = self.root();
+        while !self.is_leaf(node) {
+            node = self.nth_ptr(node, 0);
+            height += 1;
+        }
+        height
+    }
+
+    // Only the header is checked here, so attaching stays cheap; validate() walks the blocks
+    pub fn load_from<'a, T>(data: &mut T) -> Result<&'a mut Self> {
+        Self::load_region(data as *mut T as *mut u8, mem::size_of::<T>())
+    }
+
+    pub fn load_region<'a>(data: *mut u8, len: usize) -> Result<&'a mut Self> {
+        let invalid = |msg| Err(Error::new(ErrorKind::InvalidData, msg));
+        if !(data as usize).is_multiple_of(Self::align()) || len < HEADER_SIZE {
+            return invalid("tree region is misaligned or too small");
+        }
+        let btree = unsafe { &mut*(data as *mut Self) };
+        if btree.header[..4] != TREE_MAGIC {
+            return invalid("tree header is missing");
+        }
+        let order = btree.order();
+        if !(MIN_ORDER..=MAX_ORDER).contains(&order) || btree.header_field(HEADER_BLOCK_SIZE) != Self::block_size(order) {
+            return invalid("tree was written with another order or other key or value types");
+        }
+        let blocks = btree.blocks();
+        if blocks < 2 || Self::header_span() + (blocks - 1) * Self::block_size(order) > len ||
+            btree.root() >= blocks || btree.free() >= blocks {
+            return invalid("tree header is out of bounds");
+        }
+        Ok(btree)
+    }
+
+    pub fn create_with_order<T>(data: &mut T, order: usize) -> &mut Self {
+        Self::create_region(data as *mut T as *mut u8, mem::size_of::<T>(), order)
+    }
+
+    pub fn create_region<'a>(data: *mut u8, len: usize, order: usize) -> &'a mut Self {
+        assert!((data as usize).is_multiple_of(Self::align()), "tree region is misaligned");
+        assert!((MIN_ORDER..=MAX_ORDER).contains(&order), "tree order out of range");
+        let blocks = Self::blocks_within(len, order);
+        assert!(blocks >= 2, "tree region holds no blocks");
+        let btree = unsafe { &mut*(data as *mut Self) };
+        btree.header = [0; HEADER_SIZE];
+        btree.header[..4].copy_from_slice(&TREE_MAGIC);
+        btree.set_header_field(HEADER_ORDER, order);
+        btree.set_header_field(HEADER_BLOCK_SIZE, Self::block_size(order));
+        btree.set_header_field(HEADER_BLOCKS, blocks);
+        btree.set_root(0);
+        btree.set_free(0);
+        for i in (1..blocks).rev() {
+            btree.free_block(i);
         }
         btree
     }
 
-    pub fn create_from<T>(data: &mut T) -> &mut Self {
-        let btree = unsafe { &mut*(data as *mut T as *mut Self) };
-        unsafe {
-            btree.meta = Block::Meta(mem::zeroed());
-            btree.meta.as_meta_mut().start = (&mut btree.meta as *mut _).offset(0);
-            btree.meta.as_meta_mut().next = 1;
-            btree.meta.as_meta_mut().end = (data as *mut _).offset(1) as *mut _;
-            btree.meta.as_meta_mut().root = 0;
-            for i in 1.. {
-                let block = btree.meta.as_meta().start.offset(i);
-                if block >= btree.meta.as_meta().end {
-                    break;
+    // Block end for a region of len bytes; block 0 is the header
+    fn blocks_within(len: usize, order: usize) -> usize {
+        len.saturating_sub(Self::header_span()) / Self::block_size(order) + 1
+    }
+
+    // Hand the blocks of a region that has grown to len bytes to the free list. Blocks are addressed
+    // by index, so the existing ones stay where they are
+    pub fn grow(&mut self, len: usize) {
+        let blocks = Self::blocks_within(len, self.order());
+        for i in (self.blocks()..blocks).rev() {
+            self.free_block(i);
+        }
+        if blocks > self.blocks() {
+            self.set_header_field(HEADER_BLOCKS, blocks);
+        }
+    }
+
+    // Every block is reached exactly once, either from the root or the free list; nodes hold sorted keys
+    // within their parent's separators and count the entries below them, leaves sit at one depth and chain
+    // both ways in key order
+    pub fn validate(&self) -> bool {
+        let blocks = self.blocks();
+        let mut seen = vec![false; blocks];
+        seen[0] = true;
+        let mut free = self.free();
+        while free != 0 {
+            if free >= blocks || seen[free] || self.tag(free) != TAG_FREE {
+                return false;
+            }
+            seen[free] = true;
+            free = self.link(free);
+        }
+        if self.is_empty() {
+            return seen.iter().all(|&x| x);
+        }
+        let mut leaves = Vec::new();
+        let mut leaf_depth = None;
+        let mut stack = vec![Frame { node: self.root(), parent: None, low: None, high: None, depth: 0 }];
+        while let Some(Frame { node: n, parent, low, high, depth }) = stack.pop() {
+            if n == 0 || n >= blocks || seen[n] || self.parent(n) != parent {
+                return false;
+            }
+            seen[n] = true;
+            let tag = self.tag(n);
+            let nk = self.num_keys(n);
+            if tag != TAG_NODE && tag != TAG_LEAF || nk == 0 || nk >= self.order() {
+                return false;
+            }
+            let keys = (0..nk).map(|i| unsafe { *self.nth_key(n, i) }).collect::<Vec<_>>();
+            // Keys must compare strictly below their successor, so an unordered pair (a NaN) is rejected as well
+            let below = |a: &K, b: &K| a.partial_cmp(b) == Some(cmp::Ordering::Less);
+            if keys.windows(2).any(|pair| !below(&pair[0], &pair[1])) ||
+                low.is_some_and(|low| keys[0] < low) || high.is_some_and(|high| !below(&keys[nk - 1], &high)) {
+                return false;
+            }
+            if tag == TAG_LEAF {
+                if *leaf_depth.get_or_insert(depth) != depth {
+                    return false;
+                }
+                leaves.push(n);
+            } else {
+                if self.entries(n) != (0..(nk + 1)).map(|i| self.nth_ptr(n, i)).filter(|&c| c != 0 && c < blocks && (self.tag(c) == TAG_NODE || self.tag(c) == TAG_LEAF))
+                    .map(|c| self.entries(c)).sum::<usize>() {
+                    return false;
+                }
+                // Pushed right to left so leaves are collected in key order
+                for i in (0..(nk + 1)).rev() {
+                    let child_low = if i == 0 { low } else { Some(keys[i - 1]) };
+                    let child_high = if i == nk { high } else { Some(keys[i]) };
+                    stack.push(Frame { node: self.nth_ptr(n, i), parent: Some(n), low: child_low, high: child_high, depth: depth + 1 });
                 }
-                *block = Block::Free(Free { next: i + 1 });
             }
         }
-        btree
+        let linked = leaves.windows(2).all(|pair| self.link(pair[0]) == pair[1] && self.prev(pair[1]) == pair[0]);
+        linked && self.prev(leaves[0]) == 0 && self.link(leaves[leaves.len() - 1]) == 0 && seen.iter().all(|&x| x)
     }
 }
 
+/// A node still to be checked by `validate`, with the key bounds its parent puts on it
+struct Frame<K> {
+    node: usize,
+    parent: Option<usize>,
+    low: Option<K>,
+    high: Option<K>,
+    depth: usize,
+}
+
 impl<K, V> fmt::Debug for BTree<K, V>
     where K: PartialOrd + Copy + Sized + fmt::Debug,
           V: Copy + Sized + fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unsafe {
-            write!(f, "")
+        writeln!(f, "order {}, root {}, {} free blocks", self.order(), self.root(), self.free_blocks())?;
+        for n in 1..self.blocks() {
+            match self.tag(n) {
+                TAG_NODE => {
+                    let nk = self.num_keys(n);
+                    let keys = (0..nk).map(|i| unsafe { *self.nth_key(n, i) }).collect::<Vec<_>>();
+                    let ptrs = (0..(nk + 1)).map(|i| self.nth_ptr(n, i)).collect::<Vec<_>>();
+                    writeln!(f, "{}: node {:?} {:?} entries {} parent {:?}", n, keys, ptrs, self.entries(n), self.parent(n))?;
+                }
+                TAG_LEAF => {
+                    let entries = (0..self.num_keys(n)).map(|i| unsafe { (*self.nth_key(n, i), *self.nth_value(n, i)) }).collect::<Vec<_>>();
+                    writeln!(f, "{}: leaf {:?} prev {} next {} parent {:?}", n, entries, self.prev(n), self.link(n), self.parent(n))?;
+                }
+                _ => {}
+            }
         }
+        Ok(())
+    }
+}
+pub struct Range<'a, K, V>
+    where K: PartialOrd + Copy + Sized + fmt::Debug + 'a,
+          V: Copy + Sized + fmt::Debug + 'a,
+{
+    tree: &'a BTree<K, V>,
+    ends: Option<((usize, usize), (usize, usize))>, // Next entries from the front and the back, None once they meet
+}
+
+impl<'a, K, V> Iterator for Range<'a, K, V>
+    where K: PartialOrd + Copy + Sized + fmt::Debug,
+          V: Copy + Sized + fmt::Debug,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (front, back) = self.ends?;
+        self.ends = if front == back { None } else { Some((self.tree.step_forward(front), back)) };
+        Some(self.tree.entry(front))
     }
 }
 
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V>
+    where K: PartialOrd + Copy + Sized + fmt::Debug,
+          V: Copy + Sized + fmt::Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (front, back) = self.ends?;
+        self.ends = if front == back { None } else { Some((front, self.tree.step_back(back))) };
+        Some(self.tree.entry(back))
+    }
+}
+
+#[cfg(test)]
+use buffer::AnonymousBuffer;
+#[cfg(test)]
+use table::Page;
+#[cfg(test)]
+use rand::{Rng, StdRng};
+
+// A tree over the whole of data, with an order that leaves room for eight nodes, enough for a few levels
+#[cfg(test)]
+fn create_from<K, V, T>(data: &mut T) -> &mut BTree<K, V>
+    where K: PartialOrd + Copy + Sized + fmt::Debug,
+          V: Copy + Sized + fmt::Debug,
+{
+    let order = cmp::max(BTree::<K, V>::order_for(mem::size_of::<T>(), 8), MIN_ORDER);
+    BTree::create_with_order(data, order)
+}
+
 #[test]
 fn insert_and_find_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
     btree.insert(2, 20);
-    unsafe {
-        for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-            println!("{}: {:?}", i, *btree.meta.as_meta_mut().start.offset(i));
-        }
-    }
+    println!("{:?}", btree);
     assert_eq!(Some(&20), btree.find(&2));
 }
 
 #[test]
 fn insert_and_find_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in 1..16 {
         assert_eq!(Some(&x), btree.find(&x));
@@ -1124,7 +1186,7 @@ fn insert_and_find_15_btree() {
 #[test]
 fn insert_and_find_range_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
     }
@@ -1134,95 +1196,71 @@ fn insert_and_find_range_15_btree() {
 #[test]
 fn insert_and_delete_left_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in 1..16 {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
 }
 
 #[test]
 fn insert_and_delete_right_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in (1..16).rev() {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
 }
 
 #[test]
 fn insert_and_delete_inner_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in vec![8,7,9,6,10,5,11,4,12,3,13,2,14,1,15] {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
 }
 
 #[test]
 fn insert_and_delete_outer_15_btree() {
     let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
-    let btree: &mut BTree<i32, i32> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
     for x in 1..16 {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in vec![8,7,9,6,10,5,11,4,12,3,13,2,14,1,15].iter().rev() {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
 }
 
 #[test]
 fn insert_and_delete_random_300_btree() {
     let mut buffer = AnonymousBuffer::<[Page; 10]>::try_new(mem::size_of::<[Page; 10]>()).unwrap();
-    let btree: &mut BTree<u8, u8> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<u16, u16> = BTree::create_with_order(&mut buffer[0], 3);
     let mut inp = (1..301).collect::<Vec<_>>();
     let mut out = (1..301).collect::<Vec<_>>();
     let mut rng = StdRng::new().unwrap();
@@ -1234,26 +1272,20 @@ fn insert_and_delete_random_300_btree() {
     //out = vec![4, 11, 14, 7, 5, 8, 10, 1, 3, 6, 9, 13, 12, 2];
     for x in inp {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in out {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
 }
 
 #[test]
 fn insert_and_delete_random_300_duplicate_btree() {
     let mut buffer = AnonymousBuffer::<[Page; 10]>::try_new(mem::size_of::<[Page; 10]>()).unwrap();
-    let btree: &mut BTree<u8, u8> = BTree::create_from(&mut buffer[0]);
+    let btree: &mut BTree<u8, u8> = BTree::create_with_order(&mut buffer[0], 3);
     let mut inp = (1..151).collect::<Vec<_>>();
     let mut out = (1..151).collect::<Vec<_>>();
     inp.extend((1..151));
@@ -1267,19 +1299,256 @@ fn insert_and_delete_random_300_duplicate_btree() {
     //out = vec![4, 11, 14, 7, 5, 8, 10, 1, 3, 6, 9, 13, 12, 2];
     for x in inp {
         btree.insert(x, x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After insert {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
-            }
-        }
+        println!("After insert {}: {:?}", x, btree);
+        assert!(btree.validate());
     }
     for x in out {
         btree.delete(&x);
-        unsafe {
-            for i in 0..BTree::offset_to(btree.meta.as_meta().start, btree.meta.as_meta().end) {
-                println!("After delete {}: {}: {:?}", x, i, *btree.meta.as_meta_mut().start.offset(i));
+        println!("After delete {}: {:?}", x, btree);
+        assert!(btree.validate());
+    }
+}
+
+
+#[test]
+fn load_moved_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(2 * mem::size_of::<Page>()).unwrap();
+    {
+        let btree: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
+        for x in 1..8 {
+            btree.insert(x, x * 10);
+        }
+    }
+    unsafe {
+        ::std::ptr::copy_nonoverlapping(&buffer[0] as *const Page, &mut buffer[1] as *mut Page, 1);
+        ::std::ptr::write_bytes(&mut buffer[0] as *mut Page, 0, 1);
+    }
+    let btree: &mut BTree<i32, i32> = BTree::load_from(&mut buffer[1]).unwrap();
+    assert!(btree.validate());
+    for x in 1..8 {
+        assert_eq!(Some(&(x * 10)), btree.find(&x));
+    }
+}
+
+#[test]
+fn load_rejects_invalid_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    assert!(BTree::<i32, i32>::load_from(&mut buffer[0]).is_err());
+    {
+        let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
+        for x in 1..8 {
+            btree.insert(x, x * 10);
+        }
+    }
+    assert!(BTree::<[u64; 32], i32>::load_from(&mut buffer[0]).is_err());
+    let bytes = unsafe { ::std::slice::from_raw_parts_mut(&mut buffer[0] as *mut Page as *mut u8, mem::size_of::<Page>()) };
+    let root = bytes[HEADER_ROOT..HEADER_ROOT + 4].to_vec();
+    bytes[HEADER_ROOT..HEADER_ROOT + 4].copy_from_slice(&(!0u32).to_le_bytes());
+    assert!(BTree::<i32, i32>::load_from(&mut buffer[0]).is_err());
+    bytes[HEADER_ROOT..HEADER_ROOT + 4].copy_from_slice(&root);
+    assert!(BTree::<i32, i32>::load_from(&mut buffer[0]).unwrap().validate());
+    // Point the root at a leaf: the header still loads but the structure does not hold
+    let block = |n: usize| BTree::<i32, i32>::header_span() + (n - 1) * BTree::<i32, i32>::block_size(3);
+    let leaf = (1..).find(|&n| bytes[block(n)] == TAG_LEAF).unwrap();
+    bytes[HEADER_ROOT..HEADER_ROOT + 4].copy_from_slice(&(leaf as u32).to_le_bytes());
+    assert!(!BTree::<i32, i32>::load_from(&mut buffer[0]).unwrap().validate());
+}
+
+#[test]
+fn insert_until_full_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let btree: &mut BTree<[u64; 32], i32> = create_from(&mut buffer[0]);
+    let mut inserted = 0;
+    while btree.insert([inserted as u64; 32], inserted) {
+        inserted += 1;
+    }
+    assert!(inserted > 0);
+    for x in 0..inserted {
+        assert_eq!(Some(&x), btree.find(&[x as u64; 32]));
+    }
+}
+
+#[test]
+fn find_in_empty_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let btree: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
+    assert_eq!(None, btree.find(&1));
+    btree.insert(1, 1);
+    btree.delete(&1);
+    assert_eq!(None, btree.find(&1));
+    assert!(btree.range_find(&0, &10).is_empty());
+}
+
+#[test]
+fn range_find_from_between_leaves() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 3);
+    for k in 0..10 {
+        assert!(btree.insert(k * 10, k));
+    }
+    for start in 0..100 {
+        let found = btree.range_find(&start, &100).iter().map(|&(&k, _)| k).collect::<Vec<_>>();
+        assert_eq!((0..10).map(|k| k * 10).filter(|&k| k >= start).collect::<Vec<_>>(), found);
+    }
+}
+
+#[test]
+fn insert_duplicate_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let btree: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
+    for k in 0..5 {
+        assert!(btree.insert(k, k));
+    }
+    assert!(!btree.insert(3, 30));
+    assert_eq!(Some(&3), btree.find(&3));
+    assert_eq!(5, btree.range_find(&0, &10).len());
+}
+
+#[test]
+fn derived_fanout_btree() {
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let btree: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
+    assert!(btree.order() > 3);
+    let mut inserted = 0;
+    while btree.insert(inserted, inserted * 10) {
+        inserted += 1;
+    }
+    assert!(inserted as usize >= btree.order() * 2);
+    assert!(btree.height() <= 2);
+    assert!(btree.validate());
+    for x in 0..inserted {
+        assert_eq!(Some(&(x * 10)), btree.find(&x));
+    }
+
+    // A page-sized node holds as many entries as fit and refuses the next one
+    let order = BTree::<i32, i32>::max_order::<Page>();
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], order);
+    for x in 0..(order as i32 - 1) {
+        assert!(btree.insert(x, x));
+    }
+    assert!(!btree.insert(order as i32, 0));
+    assert_eq!(order - 1, btree.range_find(&0, &(order as i32)).len());
+}
+
+#[test]
+fn insert_and_delete_random_order_8_btree() {
+    let mut buffer = AnonymousBuffer::<[Page; 16]>::try_new(mem::size_of::<[Page; 16]>()).unwrap();
+    let btree: &mut BTree<u32, u64> = BTree::create_with_order(&mut buffer[0], 8);
+    let mut inp = (0..1000).collect::<Vec<_>>();
+    let mut out = inp.clone();
+    let mut rng = StdRng::new().unwrap();
+    rng.shuffle(&mut inp);
+    rng.shuffle(&mut out);
+    for &x in &inp {
+        assert!(btree.insert(x, x as u64 * 3));
+    }
+    assert!(btree.validate());
+    for (i, &x) in out.iter().enumerate() {
+        assert!(btree.delete(&x));
+        assert!(btree.validate());
+        if i % 100 == 0 {
+            for &y in &out[(i + 1)..] {
+                assert_eq!(Some(&(y as u64 * 3)), btree.find(&y));
             }
         }
     }
+    assert!(btree.is_empty());
+}
+
+#[test]
+fn range_iterators_btree() {
+    use std::collections::BTreeMap;
+    let mut buffer = AnonymousBuffer::<[Page; 4]>::try_new(mem::size_of::<[Page; 4]>()).unwrap();
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 4);
+    let mut reference = BTreeMap::new();
+    let mut keys = (0..200).map(|k| k * 3).collect::<Vec<_>>();
+    let mut rng = StdRng::new().unwrap();
+    rng.shuffle(&mut keys);
+    for &k in &keys {
+        assert!(btree.insert(k, -k));
+        reference.insert(k, -k);
+    }
+    for &k in keys.iter().take(50) {
+        assert!(btree.delete(&k));
+        reference.remove(&k);
+    }
+    assert!(btree.validate());
+
+    assert!(btree.iter().eq(reference.iter()));
+    assert!(btree.iter().rev().eq(reference.iter().rev()));
+    for &(low, high) in &[(0, 600), (-10, 5), (1, 2), (7, 8), (298, 302), (590, 700), (100, 400)] {
+        assert!(btree.range(low..high).eq(reference.range(low..high)));
+        assert!(btree.range(low..=high).rev().eq(reference.range(low..=high).rev()));
+        assert!(btree.range((Bound::Excluded(low), Bound::Included(high))).eq(reference.range((Bound::Excluded(low), Bound::Included(high)))));
+        assert!(btree.range(..high).eq(reference.range(..high)));
+        assert!(btree.range(low..).eq(reference.range(low..)));
+    }
+    assert_eq!(0, btree.range(50..50).count());
+    assert_eq!(0, btree.range((Bound::Included(60), Bound::Excluded(40))).count());
+
+    // Both ends can be consumed alternately until they meet
+    let mut range = btree.range(100..400);
+    let mut expected = reference.range(100..400);
+    for i in 0.. {
+        let (got, want) = if i % 3 == 0 { (range.next_back(), expected.next_back()) } else { (range.next(), expected.next()) };
+        assert_eq!(want, got);
+        if got.is_none() {
+            break;
+        }
+    }
+
+    let mut buffer = AnonymousBuffer::<Page>::try_new(mem::size_of::<Page>()).unwrap();
+    let empty: &mut BTree<i32, i32> = create_from(&mut buffer[0]);
+    assert_eq!(0, empty.iter().count());
+    assert_eq!(None, empty.range(3..).next_back());
 }
 
+#[test]
+fn ordered_navigation_btree() {
+    use std::collections::BTreeMap;
+    let mut buffer = AnonymousBuffer::<[Page; 4]>::try_new(mem::size_of::<[Page; 4]>()).unwrap();
+    let btree: &mut BTree<i32, i32> = BTree::create_with_order(&mut buffer[0], 4);
+    assert_eq!((None, None, None, 0), (btree.first(), btree.nth(0), btree.floor(&1), btree.rank(&1)));
+    let mut reference = BTreeMap::new();
+    let mut keys = (0..300).map(|k| k * 2).collect::<Vec<_>>();
+    let mut rng = StdRng::new().unwrap();
+    rng.shuffle(&mut keys);
+    for (i, &k) in keys.iter().enumerate() {
+        btree.insert(k, k * 10);
+        reference.insert(k, k * 10);
+        if i % 4 == 3 {
+            btree.delete(&keys[i / 2]);
+            reference.remove(&keys[i / 2]);
+        }
+    }
+    assert!(btree.validate());
+    assert_eq!(reference.len(), btree.len());
+    assert_eq!(reference.iter().next(), btree.first());
+    assert_eq!(reference.iter().next_back(), btree.last());
+    for probe in -3..603 {
+        assert_eq!(reference.range(..=probe).next_back(), btree.floor(&probe));
+        assert_eq!(reference.range(probe..).next(), btree.ceiling(&probe));
+        assert_eq!(reference.range(..probe).next_back(), btree.predecessor(&probe));
+        assert_eq!(reference.range((Bound::Excluded(probe), Bound::Unbounded)).next(), btree.successor(&probe));
+        assert_eq!(reference.range(..probe).count(), btree.rank(&probe));
+    }
+    for (i, entry) in reference.iter().enumerate() {
+        assert_eq!(Some(entry), btree.nth(i));
+    }
+    assert_eq!(None, btree.nth(reference.len()));
+
+    // Popping from the back yields the greatest entry each time
+    while let Some((k, v)) = btree.pop_back() {
+        assert_eq!(reference.iter().next_back().map(|(&k, &v)| (k, v)), Some((k, v)));
+        reference.remove(&k);
+        assert!(btree.validate());
+        if reference.len() == 100 {
+            break;
+        }
+    }
+    while let Some((k, _)) = btree.pop_front() {
+        assert_eq!(reference.keys().next(), Some(&k));
+        reference.remove(&k);
+    }
+    assert!(reference.is_empty() && btree.is_empty() && btree.validate());
+}
@@ -4,85 +4,238 @@
  *  let expr1 = operators::eq(col2name, "foo");
  *  let expr2 = operators::between(col1name, &16, &32);
  *  let expr = operators::and(expr1, expr2);
- *  let where = operators::where(expr);
+ *  let clause = operators::where_(expr);
  *  db.select(
  *    &[col1name,col2name,col3name],
  *    tablename,
- *    &[where]
+ *    &[clause]
  *  )
  *  --
  */
 
-mod btree;
+pub mod operators;
+pub(crate) mod btree;
+mod schema;
+mod table;
+mod value;
 
-use std::{mem, cmp, fmt};
-use std::io::Result;
+use std::{fs, mem, ptr, slice, cmp, fmt};
+use std::collections::BTreeMap;
+use std::io::{Result, Error, ErrorKind};
 use std::path::Path;
-use std::any::{Any, TypeId};
+use std::fs::OpenOptions;
 
 use buffer::{Buffer, AnonymousBuffer, FileBuffer};
 use table::btree::BTree;
+pub use table::schema::{Schema, Column, ConstraintViolation};
+pub use table::value::{ColumnType, Value};
+pub use table::table::{Table, Table0, Table1, Table2, Table3, RowType, ColumnValue, Record};
+use table::operators::Where;
+use wal::{self, Wal};
+#[cfg(test)]
+use crash::CrashTestBuffer;
 
 pub const PAGE_SIZE: usize = 4095;
-pub const STRING_SIZE: usize = 256;
+pub const STRING_SIZE: usize = 64;
 
 pub const PAGE_USED: u8 = !0;
 pub const PAGE_AVAIL: u8 = 1;
 pub const PAGE_FREE: u8 = 0;
 
+// Pages tracked by one trunk page; trunk n covers pages n * TRUNK_SPAN onwards
+const TRUNK_SPAN: usize = PAGE_SIZE - 7;
+
+const DB_MAGIC: [u8; 8] = *b"OZONEDB\0";
+const DB_VERSION: u32 = 14;
+
+const ROOT_TABLE: &str = "ozone_root";
+const ROOT_COLUMNS: [(&str, ColumnType); 10] = [
+    ("name", ColumnType::Text),
+    ("type", ColumnType::UInt),
+    ("colnum", ColumnType::UInt),
+    ("colnames", ColumnType::Blob),
+    ("coltypes", ColumnType::Blob),
+    ("primary", ColumnType::UInt),
+    ("indexroot", ColumnType::UInt),
+    ("rowdata", ColumnType::UInt),
+    ("parent", ColumnType::Text),
+    ("unique", ColumnType::Bool),
+];
+
+/* Key Layout
+ * ------------
+ * len       u32      Length of the whole key
+ * data      [u8]     A key of up to STRING_SIZE bytes, zero padded, or for a longer key:
+ * prefix    [u8]     Its first KEY_PREFIX bytes
+ * hash      u64      FNV-1a hash of all of it
+ * tiebreak  u32      Tells apart long keys sharing prefix, hash and length
+ * chain     u32      First Overflow page, holding the bytes after the prefix
+ *
+ * Keys compare byte by byte, a shorter key before any it is a prefix of. In the tree, long keys
+ * sharing their first KEY_PREFIX bytes come after every short key with that prefix, ordered by
+ * hash, length and tiebreak, so scans that bound such keys widen to the prefix and filter the rows
+ * they read. Ranges read back sort each such group by the full text of its keys, which puts them
+ * in byte order. The database finds a long key by reading the chains of the keys that match it up
+ * to the tiebreak.
+ */
+
+const KEY_PREFIX: usize = STRING_SIZE - 16;
+const KEY_TIEBREAK: usize = KEY_PREFIX + 8;
+const KEY_CHAIN: usize = KEY_PREFIX + 12;
+
+// Table and index names are capped here rather than by the key size
+const NAME_SIZE: usize = 256;
+
 #[derive(Copy)]
-struct ByteString([u8; STRING_SIZE]);
+pub struct ByteString {
+    len: u32,
+    data: [u8; STRING_SIZE],
+}
 
 #[macro_export]
 macro_rules! bytestring {
-    ($str:expr) => {{
-        {
-			let mut array = [0u8; STRING_SIZE];
-            for (&x, p) in $str.as_bytes().iter().zip(array.iter_mut()) {
-                *p = x;
-            }
-            ByteString(array)
+    ($str:expr) => {
+        ByteString::from_bytes($str.as_bytes())
+    }
+}
+
+impl ByteString {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut data = [0u8; STRING_SIZE];
+        if bytes.len() <= STRING_SIZE {
+            data[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            data[..KEY_PREFIX].copy_from_slice(&bytes[..KEY_PREFIX]);
+            data[KEY_PREFIX..KEY_TIEBREAK].copy_from_slice(&fnv1a(bytes).to_be_bytes());
+        }
+        ByteString { len: cmp::min(bytes.len(), u32::MAX as usize) as u32, data: data }
+    }
+
+    // The whole key, or only the first KEY_PREFIX bytes of a long one
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..cmp::min(self.len as usize, self.head_len())]
+    }
+
+    pub fn is_long(&self) -> bool {
+        self.len as usize > STRING_SIZE
+    }
+
+    fn head(&self) -> &[u8] {
+        &self.data[..cmp::min(self.len as usize, KEY_PREFIX)]
+    }
+
+    fn head_len(&self) -> usize {
+        if self.is_long() { KEY_PREFIX } else { STRING_SIZE }
+    }
+
+    fn tiebreak(&self) -> u32 {
+        let mut array = [0u8; 4];
+        array.copy_from_slice(&self.data[KEY_TIEBREAK..KEY_CHAIN]);
+        u32::from_be_bytes(array)
+    }
+
+    fn with_tiebreak(&self, tiebreak: u32) -> Self {
+        let mut key = *self;
+        key.data[KEY_TIEBREAK..KEY_CHAIN].copy_from_slice(&tiebreak.to_be_bytes());
+        key
+    }
+
+    // The Overflow chain of a long key, which takes no part in comparisons
+    fn chain(&self) -> usize {
+        let mut array = [0u8; 4];
+        array.copy_from_slice(&self.data[KEY_CHAIN..]);
+        u32::from_le_bytes(array) as usize
+    }
+
+    fn with_chain(&self, chain: usize) -> Self {
+        let mut key = *self;
+        key.data[KEY_CHAIN..].copy_from_slice(&(chain as u32).to_le_bytes());
+        key
+    }
+
+    // Sorts after every key
+    fn max() -> Self {
+        ByteString { len: u32::MAX, data: [!0; STRING_SIZE] }
+    }
+
+    // The lowest and highest keys sharing this key's first KEY_PREFIX bytes, or the key itself when shorter
+    fn floor(&self) -> Self {
+        if self.len as usize > KEY_PREFIX { ByteString::from_bytes(&self.data[..KEY_PREFIX]) } else { *self }
+    }
+
+    fn ceiling(&self) -> Self {
+        if self.len as usize <= KEY_PREFIX {
+            return *self;
         }
-    }}
+        let mut data = [!0u8; STRING_SIZE];
+        data[..KEY_PREFIX].copy_from_slice(&self.data[..KEY_PREFIX]);
+        ByteString { len: u32::MAX, data: data }
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &x| (hash ^ x as u64).wrapping_mul(0x100000001b3))
+}
+
+// Longer strings become long keys, whose text only the database can recover once it has stored them
+impl<'a> From<&'a str> for ByteString {
+    fn from(s: &'a str) -> Self {
+        bytestring!(s)
+    }
 }
 
 impl PartialEq for ByteString {
     fn eq(&self, other: &Self) -> bool {
-        self.0[..] == other.0[..]
+        self.cmp(other) == cmp::Ordering::Equal
     }
 }
 
+impl Eq for ByteString {}
+
 impl PartialOrd for ByteString {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        PartialOrd::partial_cmp(&&self.0[..], &&other.0[..])
+        Some(self.cmp(other))
+    }
+}
+
+// Tree order, which matches text order except among long keys sharing a prefix (see Key Layout)
+impl Ord for ByteString {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        let order = self.head().cmp(other.head()).then_with(|| self.is_long().cmp(&other.is_long()));
+        if !self.is_long() || !other.is_long() {
+            return order.then_with(|| self.data[KEY_PREFIX..].cmp(&other.data[KEY_PREFIX..]))
+                .then_with(|| self.len.cmp(&other.len));
+        }
+        order.then_with(|| self.data[KEY_PREFIX..KEY_TIEBREAK].cmp(&other.data[KEY_PREFIX..KEY_TIEBREAK]))
+            .then_with(|| self.len.cmp(&other.len))
+            .then_with(|| self.tiebreak().cmp(&other.tiebreak()))
     }
 }
 
 impl fmt::Debug for ByteString {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(&&self.0[..], f)
+        fmt::Debug::fmt(self.as_bytes(), f)
     }
 }
 
 impl Clone for ByteString {
     fn clone(&self) -> Self {
-        let mut array = [0u8; 256];
-        for (&x, p) in self.0.iter().zip(array.iter_mut()) {
-            *p = x;
-        }
-        ByteString(array)
+        *self
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[repr(u8)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum PageType {
     Unallocated = 00,       // Free page: uninitialised data
     Metadata = 01,          // Metadata page: global variables
-    PageTrunk = 02,         // Page data array: ArrayList<usize>(cursor)
-    Directory = 03,         // Table directory: BPlusTree<ByteString, usize>(tblname, pageidx) -> ColumnDirectory
-    IndexRoot = 04,         // Leaf pages tree: BPlusTree<Vn, usize>(keyval, pageidx) -> IndexLeaf
-    IndexLeaf = 05,         // Index data tree: BPlusTree<Vn, (usize, usize)>(keyval, (pageidx,offset)) -> FreeListPage
-    RowData = 06,           // Row values: FreeList<(size(V1),..,size(VN),V1,..,VN)>
+    PageTrunk = 02,         // Page state array: ArrayList<u8>(next trunk)
+    Directory = 03,         // Table directory root: BPlusTree<ByteString, usize>(lowest tblname, pageidx) -> IndexRoot | DirectoryLeaf
+    IndexRoot = 04,         // Tree node: BPlusTree<Vn, usize>(lowest keyval, pageidx) -> IndexRoot | IndexLeaf | DirectoryLeaf
+    IndexLeaf = 05,         // Index data tree: BPlusTree<Vn, (usize, usize)>(keyval, (pageidx,slot)) -> FreeListPage
+    RowData = 06,           // Row values: FreeList<(N,size(V1),..,size(VN),V1,..,VN)>
+    Overflow = 07,          // Spilled row and key bytes: ArrayList<u8>(next overflow)
+    DirectoryLeaf = 08,     // Table directory leaf: BPlusTree<ByteString, usize>(tblname, pageidx) -> ColumnDirectory
 }
 
 /* Initial File Structure
@@ -93,46 +246,173 @@ enum PageType {
  * Page3 - Table0.name IndexRoot
  * Page4 -      ""     IndexLeaf
  * Page5 - RowData
- * Page6 - Unallocated
+ * Page6 - Directory leaf
  * Page7 - Unallocated
+ *
+ * Trees span pages. A tree's root page never moves; it and the IndexRoot nodes under it map the
+ * lowest key beneath each child to the child's page, the first entry of a root under the empty key.
+ * Splitting the root moves its entries down into two new nodes. The pages of each level link to
+ * the next through cont, so range scans walk the leaves in order.
  */
 
 /* Table0 Schema
  * ------------
- * Column:  name     type   colnum  colnames       primary
- * Example: "root"   table  5       LinkedListPtr  0
+ * Column:  name          type   colnum  colnames    coltypes      primary  indexroot  rowdata  parent  unique
+ * Example: "ozone_root"  table  10      ["name"..]  [Text,UInt..] 0        3          5        ""      false
+ *          "users.email" index  1       ["email"]   [Text]        0        9          0        "users" true
+ *
+ * A secondary index is named "<table>.<column>" and keyed on the column's value followed by the
+ * row's primary key. The Directory maps every table and index name to its IndexRoot page as well.
  */
 
+#[repr(C, align(8))]
 pub struct Page {
     data: [u8; PAGE_SIZE],
     typeid: PageType,
 }
 
+#[repr(C)]
 struct MetadataPage {
-    journal: [u8; PAGE_SIZE], // Write ahead log
+    magic: [u8; 8], // "OZONEDB\0"
+    version: u32, // File format version
+    size: u32, // Page count
+    free: u32, // Most recently freed page, 0 when none; each free page holds the next in its first 4 bytes
+    fresh: u32, // First page never handed out
+    journal: [u8; PAGE_SIZE - 27], // Reserved: the write-ahead log lives beside the file
 }
 
+#[repr(C)]
 struct BPlusTreePage {
-    btree: [u8; PAGE_SIZE - 4], // B+ Tree data
-    cont: u32, // Next btree
+    btree: [u8; PAGE_SIZE - 7], // B+ Tree data
+    cont: u32, // Next page on the same level of the tree, 0 on the last
+}
+
+impl BPlusTreePage {
+    // The pages are the nodes of the outer tree, so the in-page tree is a single node filling the page
+    fn create_tree<K, V>(&mut self) -> &mut BTree<K, V>
+        where K: PartialOrd + Copy + Sized + fmt::Debug,
+              V: Copy + Sized + fmt::Debug,
+    {
+        let order = BTree::<K, V>::max_order::<[u8; PAGE_SIZE - 7]>();
+        BTree::create_with_order(&mut self.btree, order)
+    }
 }
 
+#[repr(C)]
 struct ArrayListPage {
-    data: [u8; PAGE_SIZE - 4], // Uninitialized space
-    next: u8, // Next arraylist
+    data: [u8; TRUNK_SPAN], // Uninitialized space
+    next: u32, // Next arraylist
 }
 
+/* RowData Page Layout
+ * ------------
+ * data      [u8]     Records from the front, the slot directory from the back
+ * slot      u16 u16  Offset and length of a record; slot n ends 4n bytes before the end of data
+ * slots     u16      Slots in the directory; a deleted row's slot has offset SLOT_DEAD until reused
+ * live      u16      Rows not yet deleted
+ * cursor    u32      End of the records
+ * next      u32      Next page of the table's rows, 0 on the last
+ *
+ * Rows are addressed by (page, slot), so compacting the records never moves a row's address.
+ *
+ * A record too large for an empty page is stored as an overflow cell instead:
+ * mark      u32      OVERFLOW_MARK, a field count no record can have
+ * length    u32      Length of the whole record
+ * chain     u32      First Overflow page, each holding the next TRUNK_SPAN bytes after the prefix
+ * prefix    [u8]     The first INLINE_PREFIX bytes of the record
+ */
+
+const SLOT_DEAD: u16 = !0;
+const OVERFLOW_MARK: u32 = !0;
+const INLINE_PREFIX: usize = 256;
+
+#[repr(C)]
 struct FreeListPage {
-    data: [u8; PAGE_SIZE - 8], // Uninitialized space
+    data: [u8; PAGE_SIZE - 15], // Uninitialized space
+    slots: u16, // Slot directory length
+    live: u16, // Rows not yet removed
     cursor: u32, // Current cell
     next: u32, // Next freelist
 }
 
+impl FreeListPage {
+    fn slot(&self, slot: usize) -> (u16, u16) {
+        let end = self.data.len() - 4 * slot;
+        let entry = &self.data[(end - 4)..end];
+        (u16::from_le_bytes([entry[0], entry[1]]), u16::from_le_bytes([entry[2], entry[3]]))
+    }
+
+    fn set_slot(&mut self, slot: usize, offset: u16, length: u16) {
+        let end = self.data.len() - 4 * slot;
+        self.data[(end - 4)..(end - 2)].copy_from_slice(&offset.to_le_bytes());
+        self.data[(end - 2)..end].copy_from_slice(&length.to_le_bytes());
+    }
+
+    // Room between the records and the slot directory
+    fn free_space(&self) -> usize {
+        self.data.len() - 4 * self.slots as usize - self.cursor as usize
+    }
+
+    // Room taken by records of deleted or shrunk rows
+    fn dead_space(&self) -> usize {
+        let live = (0..self.slots as usize).map(|slot| self.slot(slot))
+            .filter(|&(offset, _)| offset != SLOT_DEAD)
+            .map(|(_, length)| length as usize)
+            .sum::<usize>();
+        self.cursor as usize - live
+    }
+
+    // Slide the live records to the front, keeping their slots
+    fn compact(&mut self) {
+        let mut records = Vec::with_capacity(self.cursor as usize);
+        for slot in 0..self.slots as usize {
+            let (offset, length) = self.slot(slot);
+            if offset != SLOT_DEAD {
+                self.set_slot(slot, records.len() as u16, length);
+                records.extend_from_slice(&self.data[offset as usize..(offset as usize + length as usize)]);
+            }
+        }
+        self.data[..records.len()].copy_from_slice(&records);
+        self.cursor = records.len() as u32;
+    }
+
+    // The slot a new row takes: the first deleted one, or a new slot at the end of the directory
+    fn free_slot(&self) -> usize {
+        (0..self.slots as usize).find(|&slot| self.slot(slot).0 == SLOT_DEAD).unwrap_or(self.slots as usize)
+    }
+
+    // Whether place would succeed, without touching the page
+    fn fits(&self, slot: usize, record: &[u8]) -> bool {
+        let grow = if slot == self.slots as usize { 4 } else { 0 };
+        record.len() + grow <= self.free_space() + self.dead_space()
+    }
+
+    // Store a record in the given slot, compacting first if only that makes room
+    fn place(&mut self, slot: usize, record: &[u8]) -> bool {
+        let grow = if slot == self.slots as usize { 4 } else { 0 };
+        if record.len() + grow > self.free_space() {
+            if !self.fits(slot, record) {
+                return false;
+            }
+            self.compact();
+        }
+        let offset = self.cursor as usize;
+        self.data[offset..(offset + record.len())].copy_from_slice(record);
+        self.cursor += record.len() as u32;
+        self.slots += (grow / 4) as u16;
+        self.set_slot(slot, offset as u16, record.len() as u16);
+        true
+    }
+}
+
 pub struct Database<B>
     where B: Buffer<Page>
 {
     buffer: B,
     size: usize,
+    dirty: BTreeMap<usize, Box<Page>>, // Pages written since the last commit
+    room: BTreeMap<usize, usize>, // Page each RowData chain, by first page, last had room in; only a hint
+    wal: Option<Wal>,
 }
 
 #[derive(PartialOrd, PartialEq, Clone, Copy, Debug)]
@@ -148,9 +428,7 @@ impl Database<AnonymousBuffer<Page>> {
 
     pub fn try_new() -> Result<Self> {
         let buffer = AnonymousBuffer::try_new(8 * mem::size_of::<Page>())?;
-        let mut db = Database { buffer: buffer, size: 8 };
-        db.init();
-        Ok(db)
+        Self::try_create_in(buffer)
     }
 }
 
@@ -161,285 +439,2260 @@ impl Database<FileBuffer<Page>> {
         Self::try_new(path).unwrap()
     }
 
+    // Refuses to replace a file that already holds data, see overwrite
     pub fn try_new<P>(path: P) -> Result<Self>
         where P: AsRef<Path> + Clone
     {
+        match fs::metadata(path.as_ref()) {
+            Ok(ref metadata) if metadata.len() > 0 => {
+                Err(Error::new(ErrorKind::AlreadyExists, "refusing to overwrite an existing file"))
+            }
+            _ => Self::try_overwrite(path),
+        }
+    }
+
+    pub fn overwrite<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        Self::try_overwrite(path).unwrap()
+    }
+
+    pub fn try_overwrite<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path.as_ref())?;
         let buffer = FileBuffer::try_new(path, 8 * mem::size_of::<Page>())?;
-        let mut db = Database { buffer: buffer, size: 8 };
-        db.init();
-        Ok(db)
+        Self::try_create_in(buffer)
     }
-}
 
-#[test]
-fn db_init() {
-    let db = Database::<AnonymousBuffer<Page>>::new();
+    pub fn open<P>(path: P) -> Self
+        where P: AsRef<Path> + Clone
+    {
+        Self::try_open(path).unwrap()
+    }
+
+    pub fn try_open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path> + Clone
+    {
+        let len = fs::metadata(path.as_ref())?.len() as usize;
+        let buffer = FileBuffer::try_new(path, len)?;
+        Self::try_open_in(buffer)
+    }
 }
 
 impl<B> Database<B>
     where B: Buffer<Page>
 {
-    fn metadata(&self) -> &'static MetadataPage {
-        let metadata_page = &self.buffer[0];
-        assert_eq!(metadata_page.typeid, PageType::Metadata);
-        unsafe { &*(&metadata_page.data as *const _ as *const MetadataPage) }
+    // Lay out an empty database over the buffer, discarding whatever it held
+    pub fn try_create_in(mut buffer: B) -> Result<Self> {
+        let wal = match buffer.path() {
+            Some(path) => {
+                let mut wal = Wal::beside(path)?;
+                wal.checkpoint()?;
+                Some(wal)
+            }
+            None => None,
+        };
+        if buffer.len() < 8 * mem::size_of::<Page>() {
+            buffer.resize(8 * mem::size_of::<Page>())?;
+        }
+        let mut db = Database { buffer: buffer, size: 8, dirty: BTreeMap::new(), room: BTreeMap::new(), wal: wal };
+        db.init();
+        db.commit()?;
+        Ok(db)
     }
 
-    fn metadata_mut(&mut self) -> &'static mut MetadataPage {
-        let metadata_page = &mut self.buffer[0];
-        metadata_page.typeid = PageType::Metadata;
-        unsafe { &mut*(&mut metadata_page.data as *mut _ as *mut MetadataPage) }
+    // Reattach to a database previously written to the buffer, replaying its log
+    pub fn try_open_in(buffer: B) -> Result<Self> {
+        let wal = match buffer.path() {
+            Some(path) => Some(Wal::beside(path)?),
+            None => None,
+        };
+        let size = buffer.len() / mem::size_of::<Page>();
+        let mut db = Database { buffer: buffer, size: size, dirty: BTreeMap::new(), room: BTreeMap::new(), wal: wal };
+        db.recover()?;
+        db.validate()?;
+        db.size = db.metadata().size as usize;
+        Ok(db)
     }
 
-    fn arraylist(&self, page_ix: usize, pagetype: PageType) -> &'static ArrayListPage {
-        let arraylist_page = &self.buffer[page_ix];
-        assert_eq!(arraylist_page.typeid, pagetype);
-        unsafe { &*(&arraylist_page.data as *const _ as *const ArrayListPage) }
+    pub fn create_table(&mut self, name: &str, schema: &Schema) -> Result<()> {
+        self.atomically(|db| db.try_create_table(name, schema))
     }
 
-    fn arraylist_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut ArrayListPage {
-        let arraylist_page = &mut self.buffer[page_ix];
-        arraylist_page.typeid = pagetype;
-        unsafe { &mut*(&mut arraylist_page.data as *mut _ as *mut ArrayListPage) }
+    pub fn drop_table(&mut self, name: &str) -> Result<()> {
+        self.atomically(|db| db.try_drop_table(name))
     }
 
-    fn freelist(&self, page_ix: usize, pagetype: PageType) -> &'static FreeListPage {
-        let freelist_page = &self.buffer[page_ix];
-        assert_eq!(freelist_page.typeid, pagetype);
-        unsafe { &*(&freelist_page.data as *const _ as *const FreeListPage) }
+    pub fn list_tables(&self) -> Vec<String> {
+        let mut names = self.tree_range::<usize>(2, &bytestring!(""), &ByteString::max()).iter()
+            .filter_map(|&(name, _)| self.key_text(&name).ok())
+            .map(|name| String::from_utf8_lossy(&name).into_owned())
+            .filter(|name| name != ROOT_TABLE && self.table_catalog(name).is_ok())
+            .collect::<Vec<_>>();
+        // Long names come out of the tree after the short ones sharing their prefix
+        names.sort();
+        names
     }
 
-    fn freelist_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut FreeListPage {
-        let freelist_page = &mut self.buffer[page_ix];
-        freelist_page.typeid = pagetype;
-        unsafe { &mut*(&mut freelist_page.data as *mut _ as *mut FreeListPage) }
+    pub fn describe_table(&self, name: &str) -> Result<Schema> {
+        self.table_catalog(name).map(|catalog| catalog.schema)
     }
 
-    fn bplustree(&self, page_ix: usize, pagetype: PageType) -> &'static BPlusTreePage {
-        let bplustree_page = &self.buffer[page_ix];
-        assert_eq!(bplustree_page.typeid, pagetype);
-        unsafe { &*(&bplustree_page.data as *const _ as *const BPlusTreePage) }
+    // Index a column, filling the index from the rows already in the table
+    pub fn create_index(&mut self, table: &str, column: &str, unique: bool) -> Result<()> {
+        self.atomically(|db| db.try_create_index(table, column, unique))
     }
 
-    fn bplustree_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut BPlusTreePage {
-        let bplustree_page = &mut self.buffer[page_ix];
-        bplustree_page.typeid = pagetype;
-        unsafe { &mut*(&mut bplustree_page.data as *mut _ as *mut BPlusTreePage) }
+    pub fn drop_index(&mut self, table: &str, column: &str) -> Result<()> {
+        self.atomically(|db| db.try_drop_index(table, column))
     }
 
-    fn init(&mut self) {
-        let metadata = self.metadata_mut();
-        for x in metadata.journal.iter_mut() {
-            *x = 0;
-        }
+    // The indexed columns of a table
+    pub fn list_indexes(&self, table: &str) -> Result<Vec<String>> {
+        self.user_catalog(table)?;
+        Ok(self.indexes(table)?.into_iter().map(|index| index.schema.columns()[0].name.clone()).collect())
+    }
 
-        let root_name = "ozone_root";
-        let root_columns = [
-            "name",
-            "type",
-            "colnum",
-            "colnames",
-            "primary"
-        ];
+    pub fn insert(&mut self, table: &str, row: &[Value]) -> Result<()> {
+        self.atomically(|db| db.try_insert(table, row))
+    }
 
-        let trunk = self.arraylist_mut(1, PageType::PageTrunk);
-        trunk.next = 0;
-        for x in trunk.data.iter_mut() {
-            *x = PAGE_FREE;
-        }
-        trunk.data[0] = PAGE_USED;
-        trunk.data[1] = PAGE_USED;
+    // Replaces the row with the same primary key and any rows sharing a unique value with it
+    pub fn insert_or_replace(&mut self, table: &str, row: &[Value]) -> Result<()> {
+        self.atomically(|db| {
+            let catalog = db.user_catalog(table)?;
+            catalog.schema.check(row)?;
+            let mut conflicts = vec![row[catalog.schema.primary_index().unwrap()].clone()];
+            for index in db.indexes(table)? {
+                let value = &row[catalog.schema.position(&index.schema.columns()[0].name).unwrap()];
+                if !index.unique || value.is_null() {
+                    continue;
+                }
+                for other in db.index_rows(&index, &catalog.schema, value)? {
+                    conflicts.push(other[catalog.schema.primary_index().unwrap()].clone());
+                }
+            }
+            for key in conflicts {
+                db.try_delete(table, &key)?;
+            }
+            db.try_insert(table, row)
+        })
+    }
 
-        let page = self.bplustree_mut(2, PageType::Directory);
-        let directory: &mut BTree<ByteString, usize> = BTree::create_from(&mut page.btree);
-        trunk.data[2] = PAGE_USED;
+    // Returns false, leaving the table as it was, when the row would violate a key
+    pub fn insert_or_ignore(&mut self, table: &str, row: &[Value]) -> Result<bool> {
+        match self.atomically(|db| db.try_insert(table, row)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if ConstraintViolation::of(e).is_some() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 
-        let page = self.bplustree_mut(3, PageType::IndexRoot);
-        let col_name: &mut BTree<ByteString, usize> = BTree::create_from(&mut page.btree);
-        directory.insert(bytestring!(root_name), 2);
-        trunk.data[3] = PAGE_USED;
+    pub fn get(&self, table: &str, key: &Value) -> Result<Option<Vec<Value>>> {
+        let catalog = self.user_catalog(table)?;
+        match self.tree_lookup(catalog.root, &key.key_bytes())? {
+            Some((_, (page_ix, slot))) => Ok(Some(catalog.schema.decode_row(&self.freelist_row(page_ix, slot)?)?)),
+            None => Ok(None),
+        }
+    }
 
-        let page = self.bplustree_mut(4, PageType::IndexLeaf);
-        page.cont = 0;
-        let idx_name: &mut BTree<ByteString, (usize, usize)> = BTree::create_from(&mut page.btree);
-        col_name.insert(bytestring!(root_name), 3);
-        trunk.data[4] = PAGE_USED;
+    pub fn create_table_for<T: Record>(&mut self) -> Result<()> {
+        self.create_table(T::table_name(), &T::schema())
+    }
 
-        let row_data = self.freelist_mut(5, PageType::RowData);
-        row_data.cursor = 0;
-        trunk.data[5] = PAGE_AVAIL;
+    pub fn insert_record<T: Record>(&mut self, record: &T) -> Result<()> {
+        self.insert(T::table_name(), &record.to_values())
+    }
 
-        let mut row = Vec::new();
-        row.push(Entry::from(&root_name));
-        row.push(Entry::from(&TableType::Table));
-        row.push(Entry::from(&root_columns.len()));
-        row.push(Entry::from(&root_columns));
-        row.push(Entry::from(&0));
-        let ptr = self.freelist_insert_row(&row);
-        idx_name.insert(bytestring!(root_name), ptr);
-    }
-
-    fn freelist_insert(&mut self, data: &Entry) -> (usize, usize) {
-        let ref data = Entry::Data(match *data {
-            Entry::Data(ref vec) => vec.to_vec(),
-            Entry::Entry(ref vec) => vec.iter().map(|x| self.freelist_insert(x))
-                                           .fold(Vec::new(), |mut v, x| { v.extend(Entry::bytes(&x)); v })
-        });
-        let trunk = self.arraylist_mut(1, PageType::PageTrunk);
-        let iter = trunk.data.iter().enumerate().filter(|&x| *x.1 != PAGE_USED);
-        for (_, page_ix) in iter {
-            if let Some(offset) = self.freelist_insert_into_page(*page_ix as usize, data) {
-                return (*page_ix as usize, offset);
-            }
+    pub fn get_record<T: Record, K: ColumnValue>(&self, key: K) -> Result<Option<T>> {
+        match self.get(T::table_name(), &key.into_value())? {
+            Some(values) => T::from_values(values).map(Some),
+            None => Ok(None),
         }
-        unreachable!()
     }
 
-    fn freelist_insert_into_page(&mut self, page_ix: usize, data: &Entry) -> Option<usize> {
-        let row_data = self.freelist_mut(page_ix, PageType::RowData);
-        let free_space = &row_data.data as *const _ as usize - &row_data.cursor as *const _ as usize;
-        if data.size() > free_space {
-            None
-        } else {
-            let offset = row_data.cursor as usize;
-            let ref length = mem::size_of_val(data);
-            let mut size = mem::size_of::<usize>();
-            let mut ptr = length as *const _ as *const u8;
-            while size > 0 {
-                row_data.data[row_data.cursor as usize] = unsafe { *ptr };
-                row_data.cursor += 1;
-                ptr = unsafe { ptr.offset(1) };
-                size -= 1;
-            }
-            let mut size = mem::size_of_val(data);
-            let mut ptr = data as *const _ as *const u8;
-            while size > 0 {
-                row_data.data[row_data.cursor as usize] = unsafe { *ptr };
-                row_data.cursor += 1;
-                ptr = unsafe { ptr.offset(1) };
-                size -= 1;
-            }
-            Some(offset)
-        }
-    }
-
-    fn freelist_insert_row(&mut self, entry: &Vec<Entry>) -> (usize, usize) {
-        let entry = entry.iter().map(|x| Entry::Data(match *x {
-            Entry::Data(ref vec) => vec.to_vec(),
-            Entry::Entry(ref vec) => vec.iter().map(|x| self.freelist_insert(x))
-                                           .fold(Vec::new(), |mut v, x| { v.extend(Entry::bytes(&x)); v })
-        })).collect::<Vec<_>>();
-        let trunk = self.arraylist_mut(1, PageType::PageTrunk);
-        let iter = trunk.data.iter().enumerate().filter(|&x| *x.1 != PAGE_USED);
-        for (_, page_ix) in iter {
-            if let Some(offset) = self.freelist_insert_row_into_page(*page_ix as usize, &entry) {
-                return (*page_ix as usize, offset);
-            }
-        }
-        unreachable!()
+    // A typed handle on the table, failing unless its columns have T's types
+    pub fn table<T: RowType>(&mut self, name: &str) -> Result<Table<B, T>> {
+        Table::open(self, name)
     }
 
-    fn freelist_insert_row_into_page(&mut self, page_ix: usize, entry: &Vec<Entry>) -> Option<usize> {
-        let row_data = self.freelist_mut(page_ix, PageType::RowData);
-        let free_space = &row_data.data as *const _ as usize - &row_data.cursor as *const _ as usize;
-        if entry.iter().fold(0, |sum, x| sum + x.size()) > free_space {
-            None
+    // Rows matching every clause in primary key order, projected onto the columns; no columns selects them all
+    pub fn select(&self, columns: &[&str], table: &str, clauses: &[Where]) -> Result<Vec<Vec<Value>>> {
+        let catalog = self.user_catalog(table)?;
+        let schema = &catalog.schema;
+        let projection = if columns.is_empty() {
+            (0..schema.len()).collect::<Vec<_>>()
         } else {
-            let offset = row_data.cursor as usize;
-            for value in entry {
-                let ref length = mem::size_of_val(value);
-                let mut size = mem::size_of::<usize>();
-                let mut ptr = length as *const _ as *const u8;
-                while size > 0 {
-                    row_data.data[row_data.cursor as usize] = unsafe { *ptr };
-                    row_data.cursor += 1;
-                    ptr = unsafe { ptr.offset(1) };
-                    size -= 1;
-                }
-            }
-            for value in entry {
-                let mut size = mem::size_of_val(value);
-                let mut ptr = value as *const _ as *const u8;
-                while size > 0 {
-                    row_data.data[row_data.cursor as usize] = unsafe { *ptr };
-                    row_data.cursor += 1;
-                    ptr = unsafe { ptr.offset(1) };
-                    size -= 1;
+            columns.iter().map(|&name| {
+                schema.position(name).ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no such column: {}", name)))
+            }).collect::<Result<Vec<_>>>()?
+        };
+        for clause in clauses {
+            clause.expr().check(schema)?;
+        }
+
+        // Scan the primary key range the clauses allow, or else that of the first index they bound
+        let primary = schema.primary_column().unwrap();
+        let mut scan = self.scan_range(catalog.root, primary, false, clauses);
+        if scan.is_none() {
+            for index in self.indexes(table)? {
+                scan = self.scan_range(index.root, &index.schema.columns()[0].name, true, clauses);
+                if scan.is_some() {
+                    break;
                 }
             }
-            Some(offset)
         }
-    }
+        let (root, low, high) = scan.unwrap_or((catalog.root, bytestring!(""), ByteString::max()));
 
-    fn create_page(&mut self, pagetype: PageType) -> usize {
-        let iter = self.arraylist(1, PageType::PageTrunk).data.iter();
-        for (i, &page_ix) in iter.enumerate() {
-            match page_ix {
-                PAGE_USED => { continue; },
-                PAGE_AVAIL => { continue; },
-                PAGE_FREE => {
-                    if i > self.size {
-                        unimplemented!();
-                    } else {
-                        let page = &mut self.buffer[i];
-                        page.typeid = pagetype;
-                        return i;
-                    }
-                },
-                val => { panic!("Invalid value 0x{:0>8b} in page trunk", val); }
+        if low > high {
+            return Ok(Vec::new());
+        }
+        let mut rows = Vec::new();
+        for (_, (page_ix, slot)) in self.index_range(root, &low, &high)? {
+            let row = schema.decode_row(&self.freelist_row(page_ix, slot)?)?;
+            if clauses.iter().all(|clause| clause.expr().eval(schema, &row)) {
+                rows.push((row[schema.primary_index().unwrap()].clone(), projection.iter().map(|&ix| row[ix].clone()).collect::<Vec<_>>()));
             }
         }
-        unimplemented!()
+        // Rows read through a secondary index come back out of primary key order
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(rows.into_iter().map(|(_, row)| row).collect())
+    }
+
+    // Returns false when no row has the key
+    pub fn update(&mut self, table: &str, key: &Value, changes: &[(&str, Value)]) -> Result<bool> {
+        self.atomically(|db| db.try_update(table, key, changes))
+    }
+
+    // Returns false when no row has the key
+    pub fn delete(&mut self, table: &str, key: &Value) -> Result<bool> {
+        self.atomically(|db| db.try_delete(table, key))
     }
 }
 
-pub enum Entry {
-    Entry(Vec<Entry>),
-    Data(Vec<u8>),
+#[test]
+fn db_init() {
+    let db = Database::<AnonymousBuffer<Page>>::new();
 }
 
-impl Entry {
-    pub fn new() -> Self {
-        Entry::Entry(Vec::new())
+#[test]
+fn db_grow_past_one_trunk() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    let mut last = 0;
+    while last < TRUNK_SPAN - 1 {
+        last = db.create_page(PageType::IndexLeaf).unwrap();
     }
+    // The second trunk takes the first page it covers
+    last = db.create_page(PageType::IndexLeaf).unwrap();
+    assert_eq!(TRUNK_SPAN + 1, last);
+    assert_eq!(vec![1, TRUNK_SPAN], db.trunks());
+    assert_eq!(PageType::PageTrunk, db.page(TRUNK_SPAN).typeid);
+    assert_eq!(PAGE_USED, db.arraylist(TRUNK_SPAN, PageType::PageTrunk).data[1]);
+    assert_eq!(PAGE_FREE, db.arraylist(TRUNK_SPAN, PageType::PageTrunk).data[2]);
+    db.commit().unwrap();
+    db.validate().unwrap();
 
-    fn size(&self) -> usize {
-        match *self {
-            Entry::Data(ref data) => data.len() + mem::size_of::<usize>(),
-            Entry::Entry(ref entry) => {
-                let mut size = 0;
-                for value in entry {
-                    size += mem::size_of::<usize>();
-                    size += match *value {
-                        Entry::Data(ref vec) => mem::size_of_val(value),
-                        Entry::Entry(ref entry) => mem::size_of::<(usize, usize)>()
-                    }
-                }
-                size
-            }
-        }
+    db.free_page(last);
+    db.free_page(7);
+    assert_eq!(7, db.create_page(PageType::IndexLeaf).unwrap());
+    assert_eq!(last, db.create_page(PageType::RowData).unwrap());
+    assert_eq!(PAGE_AVAIL, db.arraylist(TRUNK_SPAN, PageType::PageTrunk).data[1]);
+}
+
+#[test]
+fn db_recover_committed_pages() {
+    {
+        let mut db = Database::<FileBuffer<Page>>::new("test_recover.db");
+        let page = db.freelist_mut(7, PageType::RowData);
+        page.cursor = 42;
+
+        // Crash after the page images are logged but before they reach the file
+        let images = db.dirty.iter().map(|(&page_ix, page)| {
+            let bytes = unsafe { slice::from_raw_parts(&**page as *const Page as *const u8, mem::size_of::<Page>()) };
+            (page_ix, bytes)
+        }).collect::<Vec<_>>();
+        let record = wal::encode_images(db.size, &images);
+        let wal = db.wal.as_mut().unwrap();
+        wal.append(&record);
+        wal.commit().unwrap();
+    }
+    let buffer = FileBuffer::try_new("test_recover.db", 8 * mem::size_of::<Page>()).unwrap();
+    let wal = Wal::beside("test_recover.db").unwrap();
+    let mut db = Database { buffer: buffer, size: 8, dirty: BTreeMap::new(), room: BTreeMap::new(), wal: Some(wal) };
+    assert_eq!(PageType::Unallocated, db.buffer[7].typeid);
+    db.recover().unwrap();
+    assert_eq!(42, db.freelist(7, PageType::RowData).cursor);
+    ::std::fs::remove_file("test_recover.db").unwrap();
+    ::std::fs::remove_file("test_recover.db.wal").unwrap();
+}
+
+#[test]
+fn db_open_existing() {
+    {
+        let mut db = Database::<FileBuffer<Page>>::new("test_open.db");
+        db.freelist_mut(7, PageType::RowData).cursor = 42;
+        db.commit().unwrap();
+    }
+    assert_eq!(ErrorKind::AlreadyExists, Database::<FileBuffer<Page>>::try_new("test_open.db").err().unwrap().kind());
+    {
+        let db = Database::<FileBuffer<Page>>::open("test_open.db");
+        assert_eq!(42, db.freelist(7, PageType::RowData).cursor);
+        assert_eq!(Some(3), db.tree_find::<usize>(2, &bytestring!("ozone_root")));
     }
+    let db = Database::<FileBuffer<Page>>::overwrite("test_open.db");
+    assert_eq!(PageType::Unallocated, db.page(7).typeid);
+    // Without its log, a zeroed file is not a database
+    fs::remove_file("test_open.db.wal").unwrap();
+    fs::write("test_open.db", vec![0u8; 8 * mem::size_of::<Page>()]).unwrap();
+    assert_eq!(ErrorKind::InvalidData, Database::<FileBuffer<Page>>::try_open("test_open.db").err().unwrap().kind());
+    fs::remove_file("test_open.db").unwrap();
+    fs::remove_file("test_open.db.wal").unwrap();
+}
+
+#[test]
+fn db_create_and_drop_table() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    let mut schema = Schema::new();
+    schema.column("id", ColumnType::UInt).column("email", ColumnType::Text).column("name", ColumnType::Text).primary("email");
+    db.create_table("users", &schema).unwrap();
+    assert_eq!(ErrorKind::AlreadyExists, db.create_table("users", &schema).err().unwrap().kind());
+    assert_eq!(ErrorKind::InvalidInput, db.create_table("bad", Schema::new().column("a", ColumnType::Int).primary("b")).err().unwrap().kind());
+    assert_eq!(vec!["users".to_owned()], db.list_tables());
+    assert_eq!(schema, db.describe_table("users").unwrap());
+    assert_eq!(Some("email"), db.describe_table("users").unwrap().primary_column());
+
+    let used = db.arraylist(1, PageType::PageTrunk).data.iter().filter(|&&x| x != PAGE_FREE).count();
+    db.drop_table("users").unwrap();
+    assert_eq!(used - 3, db.arraylist(1, PageType::PageTrunk).data.iter().filter(|&&x| x != PAGE_FREE).count());
+    assert!(db.list_tables().is_empty());
+    assert_eq!(ErrorKind::NotFound, db.describe_table("users").err().unwrap().kind());
+    assert_eq!(ErrorKind::NotFound, db.drop_table("users").err().unwrap().kind());
+    assert_eq!(ErrorKind::PermissionDenied, db.drop_table("ozone_root").err().unwrap().kind());
+}
 
-    pub fn from<T>(value: &T) -> Self
-        where T: Copy + Any
+#[test]
+fn db_reopen_tables() {
     {
-        Entry::Data(Entry::bytes(value))
+        let mut db = Database::<FileBuffer<Page>>::overwrite("test_tables.db");
+        db.create_table("a", Schema::new().column("x", ColumnType::Float)).unwrap();
+        db.create_table("b", Schema::new().column("y", ColumnType::Bool).column("z", ColumnType::Timestamp).primary("z")).unwrap();
+        assert!(db.size > 8);
+    }
+    let db = Database::<FileBuffer<Page>>::open("test_tables.db");
+    assert_eq!(vec!["a".to_owned(), "b".to_owned()], db.list_tables());
+    assert_eq!(Some("z"), db.describe_table("b").unwrap().primary_column());
+    assert_eq!(ColumnType::Timestamp, db.describe_table("b").unwrap().columns()[1].column_type);
+    fs::remove_file("test_tables.db").unwrap();
+    fs::remove_file("test_tables.db.wal").unwrap();
+}
+
+#[test]
+fn db_insert_get_update_delete() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    let mut schema = Schema::new();
+    schema.column("id", ColumnType::UInt).column("name", ColumnType::Text).column("score", ColumnType::Float);
+    db.create_table("players", &schema).unwrap();
+
+    let row = vec![Value::UInt(1), Value::from("ada"), Value::Float(9.5)];
+    db.insert("players", &row).unwrap();
+    assert_eq!(Some(row.clone()), db.get("players", &Value::UInt(1)).unwrap());
+    assert_eq!(None, db.get("players", &Value::UInt(2)).unwrap());
+    assert_eq!(ErrorKind::AlreadyExists, db.insert("players", &row).err().unwrap().kind());
+    assert_eq!(ErrorKind::InvalidInput, db.insert("players", &[Value::UInt(2), Value::Int(3), Value::Null]).err().unwrap().kind());
+    assert_eq!(ErrorKind::PermissionDenied, db.insert("ozone_root", &row).err().unwrap().kind());
+
+    assert!(db.update("players", &Value::UInt(1), &[("score", Value::Null)]).unwrap());
+    assert_eq!(Some(vec![Value::UInt(1), Value::from("ada"), Value::Null]), db.get("players", &Value::UInt(1)).unwrap());
+    assert!(db.update("players", &Value::UInt(1), &[("id", Value::UInt(7))]).unwrap());
+    assert_eq!(None, db.get("players", &Value::UInt(1)).unwrap());
+    assert_eq!(Value::from("ada"), db.get("players", &Value::UInt(7)).unwrap().unwrap()[1]);
+    assert!(!db.update("players", &Value::UInt(1), &[("name", Value::from("bob"))]).unwrap());
+    assert_eq!(ErrorKind::InvalidInput, db.update("players", &Value::UInt(7), &[("rank", Value::Null)]).err().unwrap().kind());
+
+    assert!(db.delete("players", &Value::UInt(7)).unwrap());
+    assert!(!db.delete("players", &Value::UInt(7)).unwrap());
+    assert_eq!(None, db.get("players", &Value::UInt(7)).unwrap());
+}
+
+#[test]
+fn db_select_rows() {
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("name", ColumnType::Text).column("note", ColumnType::Text)).unwrap();
+    for &(k, name, note) in [(1, "ant", Some("a")), (2, "bee", None), (3, "bat", Some("c")), (4, "cat", None)].iter() {
+        db.insert("t", &[Value::Int(k), Value::from(name), note.map_or(Value::Null, Value::from)]).unwrap();
+    }
+
+    let names = |clauses: &[Where]| -> Vec<Value> {
+        db.select(&["name"], "t", clauses).unwrap().into_iter().map(|mut row| row.remove(0)).collect()
+    };
+    assert_eq!(4, names(&[]).len());
+    assert_eq!(vec![Value::from("bee"), Value::from("bat")], names(&[where_(operators::between("k", 2i64, 3i64))]));
+    assert_eq!(vec![Value::from("bat")], names(&[where_(operators::prefix("name", "b")), where_(operators::gt("k", 2i64))]));
+    assert_eq!(vec![Value::from("bee"), Value::from("cat")], names(&[where_(operators::is_null("note"))]));
+    assert_eq!(vec![Value::from("ant"), Value::from("cat")], names(&[where_(operators::or(
+        operators::like("name", "%nt"), operators::in_("k", vec![4i64, 9])))]));
+    assert_eq!(Vec::<Value>::new(), names(&[where_(operators::gt("k", 3i64)), where_(operators::lt("k", 3i64))]));
+
+    assert_eq!(vec![vec![Value::Null, Value::Int(2)]],
+        db.select(&["note", "k"], "t", &[where_(operators::eq("k", 2i64))]).unwrap());
+    assert_eq!(ErrorKind::InvalidInput, db.select(&["rank"], "t", &[]).unwrap_err().kind());
+    assert_eq!(ErrorKind::InvalidInput, db.select(&[], "t", &[where_(operators::eq("rank", 1i64))]).unwrap_err().kind());
+}
+
+#[test]
+fn db_secondary_indexes() {
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("name", ColumnType::Text).column("city", ColumnType::Text)).unwrap();
+    for &(k, name, city) in [(4, "dee", "oslo"), (1, "ann", "rome"), (3, "cy", "oslo"), (2, "bo", "lima")].iter() {
+        db.insert("t", &[Value::Int(k), Value::from(name), Value::from(city)]).unwrap();
+    }
+    assert_eq!(ErrorKind::AlreadyExists, db.create_index("t", "city", true).unwrap_err().kind());
+    db.create_index("t", "city", false).unwrap();
+    assert_eq!(ErrorKind::AlreadyExists, db.create_index("t", "city", false).unwrap_err().kind());
+    assert_eq!(ErrorKind::InvalidInput, db.create_index("t", "zip", false).unwrap_err().kind());
+    assert_eq!(vec!["city".to_owned()], db.list_indexes("t").unwrap());
+    assert_eq!(vec!["t".to_owned()], db.list_tables());
+    assert_eq!(ErrorKind::NotFound, db.describe_table("t.city").unwrap_err().kind());
+
+    let index = db.indexes("t").unwrap().remove(0);
+    let all = |db: &Database<AnonymousBuffer<Page>>| db.index_range(index.root, &bytestring!(""), &ByteString::max()).unwrap().len();
+    assert_eq!(4, all(&db));
+
+    // The bounds of a value take in every key it starts, whatever bytes the primary key adds
+    let oslo = Value::from("oslo");
+    let mut key = oslo.key_bytes();
+    key.extend_from_slice(&[0, 0xFF, 0xFF, 7]);
+    db.index_insert(index.root, &key, (0, 0)).unwrap();
+    let bounds = (Database::<AnonymousBuffer<Page>>::index_bound(&oslo, true, false), Database::<AnonymousBuffer<Page>>::index_bound(&oslo, true, true));
+    assert_eq!(3, db.index_range(index.root, &bounds.0, &bounds.1).unwrap().len());
+    db.index_delete(index.root, &key).unwrap();
+    let (root, _, _) = db.scan_range(index.root, "city", true, &[where_(operators::eq("city", "oslo"))]).unwrap();
+    assert_eq!(index.root, root);
+    let keys = |db: &Database<AnonymousBuffer<Page>>, clause| -> Vec<Value> {
+        db.select(&["k"], "t", &[clause]).unwrap().into_iter().map(|mut row| row.remove(0)).collect()
+    };
+    assert_eq!(vec![Value::Int(3), Value::Int(4)], keys(&db, where_(operators::eq("city", "oslo"))));
+    assert_eq!(vec![Value::Int(2), Value::Int(3), Value::Int(4)], keys(&db, where_(operators::between("city", "lima", "oslo"))));
+
+    assert!(db.update("t", &Value::Int(3), &[("city", Value::from("lima"))]).unwrap());
+    assert_eq!(vec![Value::Int(4)], keys(&db, where_(operators::eq("city", "oslo"))));
+    assert_eq!(vec![Value::Int(2), Value::Int(3)], keys(&db, where_(operators::eq("city", "lima"))));
+    assert!(db.delete("t", &Value::Int(2)).unwrap());
+    assert_eq!(vec![Value::Int(3)], keys(&db, where_(operators::eq("city", "lima"))));
+    assert_eq!(3, all(&db));
+
+    db.drop_index("t", "city").unwrap();
+    assert_eq!(ErrorKind::NotFound, db.drop_index("t", "city").unwrap_err().kind());
+    assert!(db.list_indexes("t").unwrap().is_empty());
+
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("name", ColumnType::Text)).unwrap();
+    db.insert("t", &[Value::Int(1), Value::from("ann")]).unwrap();
+    db.insert("t", &[Value::Int(2), Value::Null]).unwrap();
+    db.create_index("t", "name", true).unwrap();
+    assert_eq!(ErrorKind::AlreadyExists, db.insert("t", &[Value::Int(3), Value::from("ann")]).unwrap_err().kind());
+    assert_eq!(ErrorKind::AlreadyExists, db.update("t", &Value::Int(2), &[("name", Value::from("ann"))]).unwrap_err().kind());
+    db.insert("t", &[Value::Int(3), Value::Null]).unwrap();
+    assert!(db.update("t", &Value::Int(1), &[("name", Value::from("ann"))]).unwrap());
+    assert_eq!(vec![Value::Int(1)], keys(&db, where_(operators::eq("name", "ann"))));
+    // Only names under "t." are read, and a table sharing that prefix is not one of t's indexes
+    let mut schema = Schema::new();
+    schema.column("k", ColumnType::Int).column("name", ColumnType::Text);
+    for &table in ["t.x", "t/", "s", "tt"].iter() {
+        db.create_table(table, &schema).unwrap();
+        db.create_index(table, "name", false).unwrap();
     }
+    assert_eq!(vec!["name".to_owned()], db.list_indexes("t").unwrap());
+    assert_eq!(vec!["name".to_owned()], db.list_indexes("t.x").unwrap());
+    for &table in ["t.x", "t/", "s", "tt"].iter() {
+        db.drop_table(table).unwrap();
+    }
+    db.drop_table("t").unwrap();
+    assert!(db.list_tables().is_empty());
+    assert_eq!(ErrorKind::NotFound, db.drop_index("t", "name").unwrap_err().kind());
+}
+
+#[test]
+fn db_enforce_keys_and_upsert() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("email", ColumnType::Text)).unwrap();
+    db.create_index("t", "email", true).unwrap();
+    db.insert("t", &[Value::Int(1), Value::from("a@x")]).unwrap();
+    db.insert("t", &[Value::Int(2), Value::from("b@x")]).unwrap();
+
+    let e = db.insert("t", &[Value::Int(1), Value::from("c@x")]).unwrap_err();
+    assert_eq!(Some(&ConstraintViolation::PrimaryKey { table: "t".to_owned() }), ConstraintViolation::of(&e));
+    let e = db.insert("t", &[Value::Int(3), Value::from("b@x")]).unwrap_err();
+    assert_eq!(Some(&ConstraintViolation::Unique { table: "t".to_owned(), column: "email".to_owned() }), ConstraintViolation::of(&e));
+    assert_eq!(None, db.get("t", &Value::Int(3)).unwrap());
+    let e = db.update("t", &Value::Int(2), &[("k", Value::Int(1))]).unwrap_err();
+    assert!(ConstraintViolation::of(&e).is_some());
+
+    assert!(!db.insert_or_ignore("t", &[Value::Int(1), Value::from("c@x")]).unwrap());
+    assert!(!db.insert_or_ignore("t", &[Value::Int(3), Value::from("a@x")]).unwrap());
+    assert!(db.insert_or_ignore("t", &[Value::Int(3), Value::from("c@x")]).unwrap());
+    assert_eq!(ErrorKind::InvalidInput, db.insert_or_ignore("t", &[Value::Int(4), Value::Int(5)]).unwrap_err().kind());
+
+    db.insert_or_replace("t", &[Value::Int(1), Value::from("z@x")]).unwrap();
+    assert_eq!(Some(vec![Value::Int(1), Value::from("z@x")]), db.get("t", &Value::Int(1)).unwrap());
+    // Takes b@x from row 2, which goes, and replaces row 3 by key
+    db.insert_or_replace("t", &[Value::Int(3), Value::from("b@x")]).unwrap();
+    let rows = db.select(&[], "t", &[]).unwrap();
+    assert_eq!(vec![vec![Value::Int(1), Value::from("z@x")], vec![Value::Int(3), Value::from("b@x")]], rows);
+}
 
-    pub fn bytes<T>(value: &T) -> Vec<u8>
-        where T: Copy + Any
+#[test]
+fn db_split_index_leaves_and_reopen() {
     {
-        let mut array = Vec::new();
-        let mut size = mem::size_of_val(value);
-        let mut ptr = value as *const _ as *const u8;
-        while size > 0 {
-            array.push(unsafe { *ptr });
-            ptr = unsafe { ptr.offset(1) };
-            size -= 1;
+        let mut db = Database::<FileBuffer<Page>>::overwrite("test_rows.db");
+        db.create_table("t", Schema::new().column("k", ColumnType::Int).column("v", ColumnType::Text)).unwrap();
+        for k in 0..64 {
+            db.insert("t", &[Value::Int(k), Value::from(format!("v{}", k))]).unwrap();
         }
-        array
+        assert!(db.leaves(db.catalog("t").unwrap().root).len() > 1);
+    }
+    let db = Database::<FileBuffer<Page>>::open("test_rows.db");
+    for k in 0..64 {
+        assert_eq!(Some(vec![Value::Int(k), Value::from(format!("v{}", k))]), db.get("t", &Value::Int(k)).unwrap());
     }
+    assert_eq!(None, db.get("t", &Value::Int(64)).unwrap());
+    assert_eq!(64, db.select(&["k"], "t", &[]).unwrap().len());
+    fs::remove_file("test_rows.db").unwrap();
+    fs::remove_file("test_rows.db.wal").unwrap();
 }
 
+#[test]
+fn db_survives_every_crash() {
+    let buffer = CrashTestBuffer::try_with_path("test_crash_db.db", mem::size_of::<Page>()).unwrap();
+    // Only the log is synced on commit, so the file may hold none of the new database
+    Database::try_create_in(buffer.clone()).unwrap();
+    buffer.crash();
+    assert!(buffer.unflushed_pages() > 0);
+    assert!(buffer.crash_modes().len() > 3);
+    for crash in buffer.crash_modes() {
+        let db = Database::try_open_in(buffer.reboot(crash).unwrap()).unwrap();
+        assert_eq!(8, db.size, "{:?}", crash);
+        assert_eq!(PageType::Metadata, db.page(0).typeid);
+        assert_eq!(PageType::PageTrunk, db.page(1).typeid);
+    }
+    ::std::fs::remove_file("test_crash_db.db.wal").unwrap();
+}
 
+#[test]
+fn db_recycle_pages() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("v", ColumnType::Blob)).unwrap();
+    let data = db.catalog("t").unwrap().data;
+    for k in 0..5 {
+        db.insert("t", &[Value::Int(k), Value::Blob(vec![7; 1500])]).unwrap();
+    }
+    let mut chain = vec![data];
+    while db.freelist(*chain.last().unwrap(), PageType::RowData).next != 0 {
+        chain.push(db.freelist(*chain.last().unwrap(), PageType::RowData).next as usize);
+    }
+    assert_eq!(3, chain.len());
 
+    // A shrunk row stays in its slot, a grown one moves to the first page with room
+    db.update("t", &Value::Int(4), &[("v", Value::Blob(vec![8; 10]))]).unwrap();
+    assert_eq!(1, db.freelist(chain[2], PageType::RowData).live);
+    db.update("t", &Value::Int(0), &[("v", Value::Blob(vec![9; 2600]))]).unwrap();
+    assert_eq!((1, 2), (db.freelist(data, PageType::RowData).live, db.freelist(chain[2], PageType::RowData).live));
+    assert_eq!(Some(vec![Value::Int(0), Value::Blob(vec![9; 2600])]), db.get("t", &Value::Int(0)).unwrap());
+    db.delete("t", &Value::Int(0)).unwrap();
+    db.delete("t", &Value::Int(4)).unwrap();
+    assert_eq!(chain[2], db.metadata().free as usize);
+    assert_eq!(PAGE_FREE, db.page_state(chain[2]));
+    assert_eq!(0, db.freelist(chain[1], PageType::RowData).next);
+    for k in 0..5 {
+        db.delete("t", &Value::Int(k)).unwrap();
+    }
+    assert_eq!(0, db.freelist(data, PageType::RowData).next);
+    assert_eq!((0, 0), (db.freelist(data, PageType::RowData).live, db.freelist(data, PageType::RowData).cursor));
+    let mut free = vec![db.metadata().free as usize];
+    while *free.last().unwrap() != 0 {
+        free.push(Database::<AnonymousBuffer<Page>>::next_free(db.page(*free.last().unwrap())) as usize);
+    }
+    assert!(free.contains(&chain[1]) && free.contains(&chain[2]));
+
+    let (size, fresh) = (db.size, db.metadata().fresh);
+    db.drop_table("t").unwrap();
+    db.create_table("u", Schema::new().column("k", ColumnType::Int)).unwrap();
+    db.insert("u", &[Value::Int(1)]).unwrap();
+    assert_eq!((size, fresh), (db.size, db.metadata().fresh));
+    db.validate().unwrap();
+}
+
+#[test]
+fn db_slotted_rows() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("v", ColumnType::Blob)).unwrap();
+    let data = db.catalog("t").unwrap().data;
+    for k in 0..6 {
+        db.insert("t", &[Value::Int(k), Value::Blob(vec![k as u8; 600])]).unwrap();
+    }
+    assert_eq!(0, db.freelist(data, PageType::RowData).next);
+
+    // Freed slots are reused and the page compacts once most of it is dead
+    db.delete("t", &Value::Int(1)).unwrap();
+    db.delete("t", &Value::Int(5)).unwrap();
+    assert_eq!((5, 4), (db.freelist(data, PageType::RowData).slots, db.freelist(data, PageType::RowData).live));
+    db.insert("t", &[Value::Int(6), Value::Blob(vec![6; 100])]).unwrap();
+    assert_eq!((5, 5), (db.freelist(data, PageType::RowData).slots, db.freelist(data, PageType::RowData).live));
+    db.delete("t", &Value::Int(0)).unwrap();
+    assert!(db.freelist(data, PageType::RowData).dead_space() > 0);
+    db.delete("t", &Value::Int(2)).unwrap();
+    assert_eq!(0, db.freelist(data, PageType::RowData).dead_space());
+    db.delete("t", &Value::Int(3)).unwrap();
+
+    // Growing a row past the page's free space compacts it in place
+    db.update("t", &Value::Int(4), &[("v", Value::Blob(vec![4; 3500]))]).unwrap();
+    assert_eq!(0, db.freelist(data, PageType::RowData).next);
+    assert_eq!(Some(vec![Value::Int(4), Value::Blob(vec![4; 3500])]), db.get("t", &Value::Int(4)).unwrap());
+    assert_eq!(Some(vec![Value::Int(6), Value::Blob(vec![6; 100])]), db.get("t", &Value::Int(6)).unwrap());
+    db.validate().unwrap();
+}
+
+#[test]
+fn db_insert_dirties_only_the_page_written() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int).column("v", ColumnType::Blob)).unwrap();
+    let data = db.catalog("t").unwrap().data;
+    for k in 0..800 {
+        db.insert("t", &[Value::Int(k), Value::Blob(vec![k as u8; 500])]).unwrap();
+    }
+    let chain = ::std::iter::successors(Some(data), |&page_ix| match db.freelist(page_ix, PageType::RowData).next {
+        0 => None,
+        next => Some(next as usize),
+    }).collect::<Vec<_>>();
+    assert!(chain.len() > 100);
+
+    // Without the hint the full pages are only read on the way to the last one
+    db.room.clear();
+    db.try_insert("t", &[Value::Int(800), Value::Blob(vec![0; 500])]).unwrap();
+    assert!(db.dirty.len() < 8, "{} pages dirtied", db.dirty.len());
+    assert!(db.dirty.contains_key(chain.last().unwrap()) && !db.dirty.contains_key(&data));
+    db.commit().unwrap();
+
+    // Room made by a delete is taken first
+    db.delete("t", &Value::Int(3)).unwrap();
+    db.try_insert("t", &[Value::Int(801), Value::Blob(vec![0; 500])]).unwrap();
+    assert!(db.dirty.len() < 8 && db.dirty.contains_key(&data));
+    db.commit().unwrap();
+    assert_eq!(801, db.select(&["k"], "t", &[]).unwrap().len());
+    db.validate().unwrap();
+}
+
+#[test]
+fn db_overflow_rows() {
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("docs", Schema::new().column("k", ColumnType::Int).column("body", ColumnType::Blob)).unwrap();
+    // Free list pages plus pages never handed out
+    let free_pages = |db: &Database<AnonymousBuffer<Page>>| {
+        let (mut free, mut count) = (db.metadata().free as usize, db.size - db.metadata().fresh as usize);
+        while free != 0 {
+            free = Database::<AnonymousBuffer<Page>>::next_free(db.page(free)) as usize;
+            count += 1;
+        }
+        count
+    };
+    let body = (0..40000).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+    db.insert("docs", &[Value::Int(1), Value::Blob(body.clone())]).unwrap();
+    db.insert("docs", &[Value::Int(2), Value::Blob(vec![2; 10])]).unwrap();
+    assert_eq!(Some(vec![Value::Int(1), Value::Blob(body.clone())]), db.get("docs", &Value::Int(1)).unwrap());
+    let (_, (page_ix, slot)) = db.tree_lookup::<(usize, usize)>(db.catalog("docs").unwrap().root, &Value::Int(1).key_bytes()).unwrap().unwrap();
+    assert_eq!((12 + INLINE_PREFIX) as u16, db.freelist(page_ix, PageType::RowData).slot(slot).1);
+
+    // Rewriting a spilled row replaces its chain, deleting it frees the chain
+    let free = free_pages(&db);
+    db.update("docs", &Value::Int(1), &[("body", Value::Blob(body[..20000].to_vec()))]).unwrap();
+    assert_eq!(free + 5, free_pages(&db));
+    assert_eq!(1, db.select(&["k"], "docs", &[where_(operators::eq("body", body[..20000].to_vec()))]).unwrap().len());
+    db.delete("docs", &Value::Int(1)).unwrap();
+    assert_eq!(free + 10, free_pages(&db));
+    db.insert("docs", &[Value::Int(3), Value::Blob(body.clone())]).unwrap();
+    assert_eq!(free, free_pages(&db));
+    db.drop_table("docs").unwrap();
+    assert!(free_pages(&db) > free + 10);
+    db.validate().unwrap();
+}
+
+#[test]
+fn byte_string_order() {
+    let long = |tail: &str| ByteString::from_bytes(format!("{}{}", "x".repeat(300), tail).as_bytes());
+    assert!(bytestring!("a") != bytestring!("a\u{0}"));
+    assert!(bytestring!("") < bytestring!("a") && bytestring!("a") < bytestring!("a\u{0}") && bytestring!("a\u{0}") < bytestring!("b"));
+    assert_eq!(b"a\0", bytestring!("a\u{0}").as_bytes());
+    assert!(long("a") != long("b") && long("a") == long("a"));
+    assert!(long("a").is_long() && !bytestring!(&"x".repeat(STRING_SIZE)[..]).is_long());
+    assert!(bytestring!(&"x".repeat(STRING_SIZE)[..]) < long("a") && long("a") < bytestring!("y"));
+    assert!(long("a").floor() <= long("b") && long("b") <= long("a").ceiling() && long("a").ceiling() < ByteString::max());
+
+    // Only the tiebreak orders long keys alike up to it, and the chain is not compared at all
+    assert!(long("a").with_tiebreak(0) < long("a").with_tiebreak(1) && long("a").with_tiebreak(1) < long("a").ceiling());
+    assert_eq!(long("a").with_chain(7), long("a"));
+    assert_eq!((3, 9), (long("a").with_tiebreak(3).with_chain(9).tiebreak(), long("a").with_tiebreak(3).with_chain(9).chain()));
+}
+
+#[test]
+fn db_long_keys() {
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("urls", Schema::new().column("url", ColumnType::Text).column("title", ColumnType::Text)).unwrap();
+    db.create_index("urls", "title", true).unwrap();
+    let url = |n: usize| format!("https://example.com/{}/{}", "a".repeat(300), n);
+    let title = |n: usize| format!("{}{}", "t".repeat(400), n);
+    for n in 0..5 {
+        db.insert("urls", &[Value::from(&url(n)[..]), Value::from(&title(n)[..])]).unwrap();
+    }
+    assert_eq!(ConstraintViolation::of(&db.insert("urls", &[Value::from("b"), Value::from(&title(3)[..])]).unwrap_err()),
+        Some(&ConstraintViolation::Unique { table: "urls".to_owned(), column: "title".to_owned() }));
+    assert_eq!(ConstraintViolation::of(&db.insert("urls", &[Value::from(&url(3)[..]), Value::from("c")]).unwrap_err()),
+        Some(&ConstraintViolation::PrimaryKey { table: "urls".to_owned() }));
+    for n in 0..5 {
+        assert_eq!(Some(vec![Value::from(&url(n)[..]), Value::from(&title(n)[..])]), db.get("urls", &Value::from(&url(n)[..])).unwrap());
+    }
+    assert_eq!(None, db.get("urls", &Value::from(&url(7)[..])).unwrap());
+
+    // Scans bounded by long keys widen to their prefix, then keep only matching rows in key order
+    let rows = db.select(&["url"], "urls", &[where_(operators::between("url", &url(1)[..], &url(3)[..]))]).unwrap();
+    assert_eq!((1..4).map(|n| vec![Value::from(&url(n)[..])]).collect::<Vec<_>>(), rows);
+    let rows = db.select(&["url"], "urls", &[where_(operators::eq("title", &title(4)[..]))]).unwrap();
+    assert_eq!(vec![vec![Value::from(&url(4)[..])]], rows);
+    assert!(db.delete("urls", &Value::from(&url(2)[..])).unwrap());
+    assert_eq!(4, db.select(&[], "urls", &[]).unwrap().len());
+}
+
+#[test]
+fn db_long_keys_in_text_order() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Text)).unwrap();
+    let prefix = "p".repeat(KEY_PREFIX);
+    let tails = ["c".repeat(80), "b".to_owned(), "a".repeat(100), String::new(), "bz".to_owned(), "b".repeat(30), "a".to_owned(), "q".repeat(9)];
+    for tail in tails.iter() {
+        db.insert("t", &[Value::from(&format!("{}{}", prefix, tail)[..])]).unwrap();
+    }
+    let root = db.catalog("t").unwrap().root;
+    let keys = db.index_range(root, &bytestring!(""), &ByteString::max()).unwrap().iter()
+        .map(|&(key, _)| db.key_text(&key).unwrap())
+        .collect::<Vec<_>>();
+    let mut expected = tails.iter().map(|tail| Value::from(&format!("{}{}", prefix, tail)[..]).key_bytes()).collect::<Vec<_>>();
+    expected.sort();
+    assert!(keys.iter().any(|key| key.len() <= STRING_SIZE) && keys.iter().any(|key| key.len() > STRING_SIZE));
+    assert_eq!(expected, keys);
+
+    for name in &[format!("{}b", prefix), format!("{}{}", prefix, "a".repeat(100)), format!("{}a", prefix)] {
+        db.create_table(name, Schema::new().column("k", ColumnType::Int)).unwrap();
+    }
+    assert_eq!(vec![format!("{}a", prefix), format!("{}{}", prefix, "a".repeat(100)), format!("{}b", prefix), "t".to_owned()], db.list_tables());
+}
+
+#[test]
+fn db_long_keys_compare_in_full() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Text).column("v", ColumnType::Int)).unwrap();
+    let root = db.catalog("t").unwrap().root;
+    let (a, b) = (format!("{}a", "x".repeat(100)), format!("{}b", "x".repeat(100)));
+    let (key_a, key_b) = (Value::from(&a[..]).key_bytes(), Value::from(&b[..]).key_bytes());
+
+    // Store b's text under a's hash, as if the two collided; a is still told apart by its text
+    let chain = db.overflow_write(&key_b[KEY_PREFIX..]).unwrap();
+    db.tree_insert(root, ByteString::from_bytes(&key_a).with_chain(chain), (5usize, 0usize)).unwrap();
+    assert_eq!(None, db.get("t", &Value::from(&a[..])).unwrap());
+    db.insert("t", &[Value::from(&a[..]), Value::Int(1)]).unwrap();
+    assert_eq!(Some(vec![Value::from(&a[..]), Value::Int(1)]), db.get("t", &Value::from(&a[..])).unwrap());
+    let (key, _) = db.tree_lookup::<(usize, usize)>(root, &key_a).unwrap().unwrap();
+    assert_eq!((1, key_a.clone()), (key.tiebreak(), db.key_text(&key).unwrap()));
+    db.update("t", &Value::from(&a[..]), &[("v", Value::Int(2))]).unwrap();
+    assert!(db.delete("t", &Value::from(&a[..])).unwrap());
+    assert_eq!(Some((5, 0)), db.tree_find::<(usize, usize)>(root, &ByteString::from_bytes(&key_a)));
+
+    // Long names keep their text, and their chains go back to the free list with them
+    let name = "n".repeat(200);
+    db.create_table(&name, Schema::new().column("k", ColumnType::Int)).unwrap();
+    db.create_index(&name, "k", false).unwrap();
+    assert_eq!(ErrorKind::AlreadyExists, db.create_table(&name, Schema::new().column("k", ColumnType::Int)).unwrap_err().kind());
+    assert!(db.list_tables().contains(&name));
+    assert_eq!(vec!["k".to_owned()], db.list_indexes(&name).unwrap());
+    db.drop_table(&name).unwrap();
+    assert!(!db.list_tables().contains(&name));
+    let size = db.size;
+    db.create_table(&name, Schema::new().column("k", ColumnType::Int)).unwrap();
+    db.create_index(&name, "k", false).unwrap();
+    assert_eq!(size, db.size);
+    assert_eq!(ErrorKind::InvalidInput, db.create_table(&"n".repeat(NAME_SIZE + 1), Schema::new().column("k", ColumnType::Int)).unwrap_err().kind());
+}
+
+#[test]
+fn db_multi_level_trees() {
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    for n in 0..200 {
+        db.create_table(&format!("t{}", n), Schema::new().column("k", ColumnType::Int).column("v", ColumnType::Text)).unwrap();
+    }
+    assert_eq!(200, db.list_tables().len());
+    assert!(db.tree_pages(2).len() > 3);
+    db.create_index("t7", "v", false).unwrap();
+    for k in 0..3000 {
+        db.insert("t7", &[Value::Int(k), Value::from(&format!("v{:04}", k % 150)[..])]).unwrap();
+    }
+    let root = db.catalog("t7").unwrap().root;
+    let (_, path) = db.tree_path(root, &bytestring!(""));
+    assert!(path.len() > 1);
+    assert_eq!(3000, db.index_range(root, &bytestring!(""), &ByteString::max()).unwrap().len());
+    assert_eq!(Some(vec![Value::Int(534), Value::from("v0084")]), db.get("t7", &Value::Int(534)).unwrap());
+    let rows = db.select(&["k"], "t7", &[where_(operators::between("k", 290i64, 310i64))]).unwrap();
+    assert_eq!((290..311).map(|k| vec![Value::Int(k)]).collect::<Vec<_>>(), rows);
+    let rows = db.select(&["k"], "t7", &[where_(operators::eq("v", "v0042"))]).unwrap();
+    assert_eq!((0..20).map(|i| vec![Value::Int(42 + 150 * i)]).collect::<Vec<_>>(), rows);
+
+    // Emptied leaves and the nodes left with nothing beneath them are recycled
+    for k in 0..2500 {
+        db.delete("t7", &Value::Int(k)).unwrap();
+    }
+    assert_eq!(500, db.select(&[], "t7", &[]).unwrap().len());
+    let root = db.catalog("t7").unwrap().root;
+    assert!(db.leaves(root).iter().all(|&leaf| !db.tree_entries::<(usize, usize)>(leaf).is_empty()));
+    let mut pages = db.tree_pages(root);
+    pages.extend(db.tree_pages(db.catalog("t7.v").unwrap().root));
+    for n in 0..200 {
+        db.drop_table(&format!("t{}", n)).unwrap();
+    }
+    assert!(db.list_tables().is_empty());
+    assert!(pages.iter().all(|&page_ix| db.page_state(page_ix) == PAGE_FREE));
+    db.validate().unwrap();
+}
+
+#[test]
+fn db_recycle_index_leaves() {
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int)).unwrap();
+    let root = db.catalog("t").unwrap().root;
+    let mut k = 0;
+    while db.leaves(root).len() < 2 {
+        db.insert("t", &[Value::Int(k)]).unwrap();
+        k += 1;
+    }
+    let leaves = db.leaves(root);
+    for k in 0..k {
+        db.delete("t", &Value::Int(k)).unwrap();
+    }
+    assert_eq!(vec![leaves[1]], db.leaves(root));
+    assert_eq!(leaves[0], db.metadata().free as usize);
+    db.insert("t", &[Value::Int(0)]).unwrap();
+    db.validate().unwrap();
+}
+
+#[test]
+fn db_free_list_survives_reopen_and_crash() {
+    let buffer = CrashTestBuffer::try_with_path("test_crash_free.db", mem::size_of::<Page>()).unwrap();
+    let mut db = Database::try_create_in(buffer.clone()).unwrap();
+    db.create_table("t", Schema::new().column("k", ColumnType::Int)).unwrap();
+    let catalog = db.catalog("t").unwrap();
+    db.drop_table("t").unwrap();
+    buffer.crash();
+    assert!(buffer.unflushed_pages() > 0);
+    assert!(buffer.crash_modes().len() > 3);
+    for crash in buffer.crash_modes() {
+        let mut db = Database::try_open_in(buffer.reboot(crash).unwrap()).unwrap();
+        assert!(db.list_tables().is_empty(), "{:?}", crash);
+        let mut reused = (0..3).map(|_| db.create_page(PageType::IndexLeaf).unwrap()).collect::<Vec<_>>();
+        reused.sort();
+        assert_eq!(vec![catalog.root, catalog.root + 1, catalog.data], reused, "{:?}", crash);
+    }
+    ::std::fs::remove_file("test_crash_free.db.wal").unwrap();
+}
+
+impl<B> Database<B>
+    where B: Buffer<Page>
+{
+    fn page(&self, page_ix: usize) -> &'static Page {
+        match self.dirty.get(&page_ix) {
+            Some(page) => unsafe { &*(&**page as *const Page) },
+            None => unsafe { &*(&self.buffer[page_ix] as *const Page) },
+        }
+    }
+
+    // Writes go to a private copy of the page until commit, so the file never holds half a change
+    fn page_mut(&mut self, page_ix: usize) -> &'static mut Page {
+        let buffer = &self.buffer;
+        let page = self.dirty.entry(page_ix).or_insert_with(|| {
+            Box::new(unsafe { ptr::read(&buffer[page_ix]) })
+        });
+        unsafe { &mut*(&mut **page as *mut Page) }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let dirty = mem::replace(&mut self.dirty, BTreeMap::new());
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        if let Some(ref mut wal) = self.wal {
+            let images = dirty.iter().map(|(&page_ix, page)| {
+                let bytes = unsafe { slice::from_raw_parts(&**page as *const Page as *const u8, mem::size_of::<Page>()) };
+                (page_ix, bytes)
+            }).collect::<Vec<_>>();
+            wal.append(&wal::encode_images(self.size, &images));
+            wal.commit()?;
+        }
+        for (page_ix, page) in dirty {
+            unsafe { ptr::copy_nonoverlapping(&*page as *const Page, &mut self.buffer[page_ix] as *mut Page, 1); }
+        }
+        // Without a log the file itself is the only copy; with one, the pages catch up when the log is cut
+        match self.wal {
+            Some(ref mut wal) if wal.needs_checkpoint() => {
+                self.buffer.flush()?;
+                wal.checkpoint()?;
+            }
+            Some(_) => {}
+            None => self.buffer.flush()?,
+        }
+        Ok(())
+    }
+
+    // Commit everything f did, or nothing if it failed
+    fn atomically<F, T>(&mut self, f: F) -> Result<T>
+        where F: FnOnce(&mut Self) -> Result<T>
+    {
+        match f(self) {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.rollback();
+                Err(e)
+            }
+        }
+    }
+
+    // Forget uncommitted pages, including any growth they recorded
+    fn rollback(&mut self) {
+        self.dirty.clear();
+        self.room.clear();
+        self.size = self.metadata().size as usize;
+    }
+
+    fn recover(&mut self) -> Result<()> {
+        let mut records = Vec::new();
+        if let Some(ref mut wal) = self.wal {
+            wal.replay(|record| {
+                records.push(wal::decode_images(record)?);
+                Ok(())
+            })?;
+        }
+        let size = records.iter().map(|&(size, _)| size).max().unwrap_or(0);
+        if size > self.size {
+            self.buffer.resize(size * mem::size_of::<Page>())?;
+            self.size = size;
+        }
+        for (_, images) in records {
+            for (page_ix, image) in images {
+                if page_ix >= self.size || image.len() != mem::size_of::<Page>() {
+                    return Err(Error::new(ErrorKind::InvalidData, "write-ahead log does not fit database"));
+                }
+                unsafe { ptr::copy_nonoverlapping(image.as_ptr(), &mut self.buffer[page_ix] as *mut Page as *mut u8, image.len()); }
+            }
+        }
+        self.buffer.flush()?;
+        if let Some(ref mut wal) = self.wal {
+            wal.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Read the type byte without trusting it to be a valid PageType
+    fn page_type(&self, page_ix: usize) -> Option<PageType> {
+        let typeid = unsafe { *(&self.page(page_ix).typeid as *const PageType as *const u8) };
+        match typeid {
+            00 => Some(PageType::Unallocated),
+            01 => Some(PageType::Metadata),
+            02 => Some(PageType::PageTrunk),
+            03 => Some(PageType::Directory),
+            04 => Some(PageType::IndexRoot),
+            05 => Some(PageType::IndexLeaf),
+            06 => Some(PageType::RowData),
+            07 => Some(PageType::Overflow),
+            08 => Some(PageType::DirectoryLeaf),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |msg| Err(Error::new(ErrorKind::InvalidData, msg));
+        if self.size < 8 || self.page_type(0) != Some(PageType::Metadata) {
+            return invalid("file is not a database");
+        }
+        let metadata = self.metadata();
+        if metadata.magic != DB_MAGIC {
+            return invalid("file is not a database");
+        }
+        if metadata.version != DB_VERSION {
+            return invalid("unsupported database version");
+        }
+        if metadata.size < 8 || metadata.size as usize > self.size {
+            return invalid("page count does not match file length");
+        }
+        if self.page_type(1) != Some(PageType::PageTrunk) || self.page_type(2) != Some(PageType::Directory) {
+            return invalid("page trunk or directory is missing");
+        }
+        // Trunk pages chain forwards, so a corrupt link cannot loop
+        let (mut trunk_ix, mut covered) = (1, 0);
+        loop {
+            if trunk_ix >= self.size || self.page_type(trunk_ix) != Some(PageType::PageTrunk) {
+                return invalid("page trunk is corrupt");
+            }
+            let trunk = self.arraylist(trunk_ix, PageType::PageTrunk);
+            if trunk.data.iter().any(|&x| x != PAGE_USED && x != PAGE_AVAIL && x != PAGE_FREE) {
+                return invalid("page trunk is corrupt");
+            }
+            covered += TRUNK_SPAN;
+            match trunk.next as usize {
+                0 => break,
+                next if next > trunk_ix => trunk_ix = next,
+                _ => return invalid("page trunk is corrupt"),
+            }
+        }
+        if covered < metadata.size as usize {
+            return invalid("page trunk does not cover the file");
+        }
+        if metadata.fresh as usize > metadata.size as usize {
+            return invalid("page count does not match file length");
+        }
+        let (mut free, mut count) = (metadata.free as usize, 0);
+        while free != 0 {
+            count += 1;
+            if free >= metadata.fresh as usize || count > metadata.size as usize ||
+                self.page_type(free) != Some(PageType::Unallocated) || self.page_state(free) != PAGE_FREE {
+                return invalid("free page list is corrupt");
+            }
+            free = Self::next_free(self.page(free)) as usize;
+        }
+        if !self.tree_is_sound::<usize>(2, PageType::DirectoryLeaf) || !self.tree_is_sound::<(usize, usize)>(3, PageType::IndexLeaf) {
+            return invalid("catalog tree is corrupt");
+        }
+        if self.tree_find::<usize>(2, &bytestring!(ROOT_TABLE)).is_none() {
+            return invalid("directory has no root table");
+        }
+        Ok(())
+    }
+
+    // Every node is a non-empty IndexRoot, the root also Directory, or a leaf page inside the file, reached once,
+    // each holding an intact in-page tree
+    fn tree_is_sound<V>(&self, root: usize, leaf_type: PageType) -> bool
+        where V: Copy + Sized + fmt::Debug,
+    {
+        let mut pages = vec![root];
+        let mut i = 0;
+        while i < pages.len() {
+            let page_ix = pages[i];
+            if page_ix >= self.size || pages[..i].contains(&page_ix) {
+                return false;
+            }
+            let pagetype = self.page_type(page_ix);
+            let page = &self.page(page_ix).data as *const _ as *mut BPlusTreePage;
+            let intact = if pagetype == Some(leaf_type) {
+                BTree::<ByteString, V>::load_from(unsafe { &mut (*page).btree }).map(|tree| tree.validate())
+            } else {
+                BTree::<ByteString, usize>::load_from(unsafe { &mut (*page).btree }).map(|tree| tree.validate())
+            };
+            if !intact.unwrap_or(false) {
+                return false;
+            }
+            if pagetype == Some(PageType::IndexRoot) || i == 0 && pagetype == Some(PageType::Directory) {
+                let children = self.tree_entries::<usize>(page_ix);
+                if children.is_empty() || i == 0 && children[0].0 != bytestring!("") {
+                    return false;
+                }
+                pages.extend(children.into_iter().map(|(_, child)| child));
+            } else if pagetype != Some(leaf_type) {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    fn metadata(&self) -> &'static MetadataPage {
+        let metadata_page = self.page(0);
+        assert_eq!(metadata_page.typeid, PageType::Metadata);
+        unsafe { &*(&metadata_page.data as *const _ as *const MetadataPage) }
+    }
+
+    fn metadata_mut(&mut self) -> &'static mut MetadataPage {
+        let metadata_page = self.page_mut(0);
+        metadata_page.typeid = PageType::Metadata;
+        unsafe { &mut*(&mut metadata_page.data as *mut _ as *mut MetadataPage) }
+    }
+
+    fn arraylist(&self, page_ix: usize, pagetype: PageType) -> &'static ArrayListPage {
+        let arraylist_page = self.page(page_ix);
+        assert_eq!(arraylist_page.typeid, pagetype);
+        unsafe { &*(&arraylist_page.data as *const _ as *const ArrayListPage) }
+    }
+
+    fn arraylist_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut ArrayListPage {
+        let arraylist_page = self.page_mut(page_ix);
+        arraylist_page.typeid = pagetype;
+        unsafe { &mut*(&mut arraylist_page.data as *mut _ as *mut ArrayListPage) }
+    }
+
+    fn freelist(&self, page_ix: usize, pagetype: PageType) -> &'static FreeListPage {
+        let freelist_page = self.page(page_ix);
+        assert_eq!(freelist_page.typeid, pagetype);
+        unsafe { &*(&freelist_page.data as *const _ as *const FreeListPage) }
+    }
+
+    fn freelist_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut FreeListPage {
+        let freelist_page = self.page_mut(page_ix);
+        freelist_page.typeid = pagetype;
+        unsafe { &mut*(&mut freelist_page.data as *mut _ as *mut FreeListPage) }
+    }
+
+    fn bplustree(&self, page_ix: usize, pagetype: PageType) -> &'static BPlusTreePage {
+        let bplustree_page = self.page(page_ix);
+        assert_eq!(bplustree_page.typeid, pagetype);
+        unsafe { &*(&bplustree_page.data as *const _ as *const BPlusTreePage) }
+    }
+
+    fn bplustree_mut(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut BPlusTreePage {
+        let bplustree_page = self.page_mut(page_ix);
+        bplustree_page.typeid = pagetype;
+        unsafe { &mut*(&mut bplustree_page.data as *mut _ as *mut BPlusTreePage) }
+    }
+
+    // Tree pages are position independent; open() has validated the catalog trees already
+    fn btree<K, V>(&self, page_ix: usize, pagetype: PageType) -> &'static BTree<K, V>
+        where K: PartialOrd + Copy + Sized + fmt::Debug,
+              V: Copy + Sized + fmt::Debug,
+    {
+        let page = self.bplustree(page_ix, pagetype) as *const BPlusTreePage as *mut BPlusTreePage;
+        BTree::load_from(unsafe { &mut (*page).btree }).expect("tree page is corrupt")
+    }
+
+    fn btree_mut<K, V>(&mut self, page_ix: usize, pagetype: PageType) -> &'static mut BTree<K, V>
+        where K: PartialOrd + Copy + Sized + fmt::Debug,
+              V: Copy + Sized + fmt::Debug,
+    {
+        BTree::load_from(&mut self.bplustree_mut(page_ix, pagetype).btree).expect("tree page is corrupt")
+    }
+
+    fn init(&mut self) {
+        let size = self.size;
+        let metadata = self.metadata_mut();
+        metadata.magic = DB_MAGIC;
+        metadata.version = DB_VERSION;
+        metadata.size = size as u32;
+        metadata.free = 0;
+        metadata.fresh = 7;
+        for x in metadata.journal.iter_mut() {
+            *x = 0;
+        }
+
+        let trunk = self.arraylist_mut(1, PageType::PageTrunk);
+        trunk.next = 0;
+        for x in trunk.data.iter_mut() {
+            *x = PAGE_FREE;
+        }
+        trunk.data[0] = PAGE_USED;
+        trunk.data[1] = PAGE_USED;
+
+        let page = self.bplustree_mut(2, PageType::Directory);
+        let directory: &mut BTree<ByteString, usize> = page.create_tree();
+        directory.insert(bytestring!(""), 6);
+        trunk.data[2] = PAGE_USED;
+
+        let page = self.bplustree_mut(6, PageType::DirectoryLeaf);
+        page.cont = 0;
+        let directory: &mut BTree<ByteString, usize> = page.create_tree();
+        trunk.data[6] = PAGE_USED;
+
+        let page = self.bplustree_mut(3, PageType::IndexRoot);
+        let col_name: &mut BTree<ByteString, usize> = page.create_tree();
+        directory.insert(bytestring!(ROOT_TABLE), 3);
+        trunk.data[3] = PAGE_USED;
+
+        let page = self.bplustree_mut(4, PageType::IndexLeaf);
+        page.cont = 0;
+        let idx_name: &mut BTree<ByteString, (usize, usize)> = page.create_tree();
+        col_name.insert(bytestring!(""), 4);
+        trunk.data[4] = PAGE_USED;
+
+        let row_data = self.freelist_mut(5, PageType::RowData);
+        row_data.slots = 0;
+        row_data.live = 0;
+        row_data.cursor = 0;
+        row_data.next = 0;
+        trunk.data[5] = PAGE_AVAIL;
+
+        let mut schema = Schema::new();
+        for &(column, column_type) in ROOT_COLUMNS.iter() {
+            schema.column(column, column_type);
+        }
+        let root = Catalog {
+            name: ROOT_TABLE.to_owned(), tabletype: TableType::Table, schema: schema, root: 3, data: 5,
+            parent: String::new(), unique: false,
+        };
+        let ptr = self.freelist_insert_row(5, &root.to_row()).unwrap();
+        idx_name.insert(bytestring!(ROOT_TABLE), ptr);
+    }
+
+    // Store a row in the first page with room of the table's RowData chain, starting from the page that last had
+    // room and extending the chain when none after it has; only the page written is dirtied
+    fn freelist_insert_row(&mut self, first_page: usize, row: &[Entry]) -> Result<(usize, usize)> {
+        let record = self.overflow_cell(row)?;
+        let mut page_ix = self.room.get(&first_page).cloned().unwrap_or(first_page);
+        loop {
+            if let Some(slot) = self.freelist_insert_row_into_page(page_ix, &record) {
+                self.room.insert(first_page, page_ix);
+                return Ok((page_ix, slot));
+            }
+            let next = self.freelist(page_ix, PageType::RowData).next as usize;
+            if next == 0 {
+                let new_page = self.create_page(PageType::RowData)?;
+                self.freelist_mut(page_ix, PageType::RowData).next = new_page as u32;
+                page_ix = new_page;
+            } else {
+                page_ix = next;
+            }
+        }
+    }
+
+    // Deleted rows' slots are reused before the directory grows
+    fn freelist_insert_row_into_page(&mut self, page_ix: usize, record: &[u8]) -> Option<usize> {
+        let row_data = self.freelist(page_ix, PageType::RowData);
+        let slot = row_data.free_slot();
+        if !row_data.fits(slot, record) {
+            return None;
+        }
+        let row_data = self.freelist_mut(page_ix, PageType::RowData);
+        row_data.place(slot, record);
+        row_data.live += 1;
+        Some(slot)
+    }
+
+    // Rewrite a row where it is; false when its page has no room for the new record
+    fn freelist_update_row(&mut self, page_ix: usize, slot: usize, row: &[Entry]) -> Result<bool> {
+        let record = self.overflow_cell(row)?;
+        let row_data = self.freelist_mut(page_ix, PageType::RowData);
+        let (offset, length) = row_data.slot(slot);
+        let old_chain = Self::overflow_chain(&row_data.data[offset as usize..(offset as usize + length as usize)]);
+        let placed = if record.len() <= length as usize {
+            row_data.data[offset as usize..(offset as usize + record.len())].copy_from_slice(&record);
+            row_data.set_slot(slot, offset, record.len() as u16);
+            true
+        } else {
+            row_data.set_slot(slot, SLOT_DEAD, 0);
+            let placed = row_data.place(slot, &record);
+            if !placed {
+                row_data.set_slot(slot, offset, length);
+            }
+            placed
+        };
+        // Whichever chain no longer has a slot pointing at it goes back to the free list
+        self.overflow_free(if placed { old_chain } else { Self::overflow_chain(&record) });
+        Ok(placed)
+    }
+
+    // Compacts a page once half of it is dead; a page left without live rows is recycled,
+    // or emptied for reuse if it starts the chain
+    fn freelist_remove_row(&mut self, first_page: usize, page_ix: usize, slot: usize) {
+        let row_data = self.freelist_mut(page_ix, PageType::RowData);
+        let (offset, length) = row_data.slot(slot);
+        self.overflow_free(Self::overflow_chain(&row_data.data[offset as usize..(offset as usize + length as usize)]));
+        row_data.set_slot(slot, SLOT_DEAD, 0);
+        while row_data.slots > 0 && row_data.slot(row_data.slots as usize - 1).0 == SLOT_DEAD {
+            row_data.slots -= 1;
+        }
+        row_data.live = row_data.live.saturating_sub(1);
+        // The room just made is where the next insert looks first
+        if row_data.live > 0 {
+            if row_data.dead_space() > row_data.data.len() / 2 {
+                row_data.compact();
+            }
+            self.room.insert(first_page, page_ix);
+            return;
+        }
+        if page_ix == first_page {
+            row_data.slots = 0;
+            row_data.cursor = 0;
+            self.room.insert(first_page, page_ix);
+            return;
+        }
+        let next = row_data.next;
+        let mut prev = first_page;
+        while self.freelist(prev, PageType::RowData).next as usize != page_ix {
+            prev = self.freelist(prev, PageType::RowData).next as usize;
+        }
+        self.freelist_mut(prev, PageType::RowData).next = next;
+        self.free_page(page_ix);
+        self.room.insert(first_page, prev);
+    }
+
+    fn freelist_row(&self, page_ix: usize, slot: usize) -> Result<Vec<Vec<u8>>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "row pointer is corrupt");
+        if page_ix >= self.size || self.page_type(page_ix) != Some(PageType::RowData) {
+            return Err(invalid());
+        }
+        let row_data = self.freelist(page_ix, PageType::RowData);
+        if slot >= row_data.slots as usize || row_data.cursor as usize + 4 * row_data.slots as usize > row_data.data.len() {
+            return Err(invalid());
+        }
+        let (offset, length) = row_data.slot(slot);
+        if offset == SLOT_DEAD || offset as usize + length as usize > row_data.cursor as usize {
+            return Err(invalid());
+        }
+        let cell = &row_data.data[offset as usize..(offset as usize + length as usize)];
+        match Self::overflow_chain(cell) {
+            Some(_) => Entry::decode_row(&self.overflow_record(cell)?),
+            None => Entry::decode_row(cell),
+        }
+    }
+
+    // The record itself when it fits in a page, otherwise an overflow cell over a new chain
+    fn overflow_cell(&mut self, row: &[Entry]) -> Result<Vec<u8>> {
+        let record = Entry::encode_row(row);
+        if record.len() + 4 <= PAGE_SIZE - 15 {
+            return Ok(record);
+        }
+        if record.len() > u32::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "row is too large"));
+        }
+        let chain = self.overflow_write(&record[INLINE_PREFIX..])? as u32;
+        let mut cell = Vec::with_capacity(12 + INLINE_PREFIX);
+        cell.extend_from_slice(&OVERFLOW_MARK.to_le_bytes());
+        cell.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        cell.extend_from_slice(&chain.to_le_bytes());
+        cell.extend_from_slice(&record[..INLINE_PREFIX]);
+        Ok(cell)
+    }
+
+    // A new chain of Overflow pages holding the bytes, TRUNK_SPAN to a page
+    fn overflow_write(&mut self, bytes: &[u8]) -> Result<usize> {
+        let mut chain = 0;
+        for chunk in bytes.chunks(TRUNK_SPAN).rev() {
+            let page_ix = self.create_page(PageType::Overflow)?;
+            let page = self.arraylist_mut(page_ix, PageType::Overflow);
+            page.data[..chunk.len()].copy_from_slice(chunk);
+            page.next = chain;
+            chain = page_ix as u32;
+        }
+        Ok(chain as usize)
+    }
+
+    // First Overflow page of an overflow cell, None for a record stored whole
+    fn overflow_chain(cell: &[u8]) -> Option<usize> {
+        let read_u32 = |offset: usize| {
+            let mut array = [0u8; 4];
+            array.copy_from_slice(&cell[offset..(offset + 4)]);
+            u32::from_le_bytes(array)
+        };
+        if cell.len() < 12 || read_u32(0) != OVERFLOW_MARK {
+            return None;
+        }
+        Some(read_u32(8) as usize)
+    }
+
+    // Reassemble a spilled record from its prefix and chain
+    fn overflow_record(&self, cell: &[u8]) -> Result<Vec<u8>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "overflow chain is corrupt");
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&cell[4..8]);
+        let length = u32::from_le_bytes(length) as usize;
+        if length < cell.len() - 12 {
+            return Err(invalid());
+        }
+        self.overflow_read(&cell[12..], Self::overflow_chain(cell).unwrap(), length)
+    }
+
+    // The head followed by what the chain holds, up to length bytes in all
+    fn overflow_read(&self, head: &[u8], chain: usize, length: usize) -> Result<Vec<u8>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "overflow chain is corrupt");
+        let mut record = Vec::with_capacity(cmp::min(length, self.size * PAGE_SIZE));
+        record.extend_from_slice(head);
+        let (mut page_ix, mut hops) = (chain, 0);
+        while record.len() < length {
+            hops += 1;
+            if page_ix == 0 || page_ix >= self.size || hops > self.size || self.page_type(page_ix) != Some(PageType::Overflow) {
+                return Err(invalid());
+            }
+            let page = self.arraylist(page_ix, PageType::Overflow);
+            let take = cmp::min(TRUNK_SPAN, length - record.len());
+            record.extend_from_slice(&page.data[..take]);
+            page_ix = page.next as usize;
+        }
+        if page_ix != 0 {
+            return Err(invalid());
+        }
+        Ok(record)
+    }
+
+    fn overflow_free(&mut self, chain: Option<usize>) {
+        let mut page_ix = chain.unwrap_or(0);
+        while page_ix != 0 {
+            let next = self.arraylist(page_ix, PageType::Overflow).next as usize;
+            self.free_page(page_ix);
+            page_ix = next;
+        }
+    }
+
+    fn create_page(&mut self, pagetype: PageType) -> Result<usize> {
+        let page_ix = self.allocate_page()?;
+        self.set_page_state(page_ix, if pagetype == PageType::RowData { PAGE_AVAIL } else { PAGE_USED });
+        let page = self.page_mut(page_ix);
+        unsafe { ptr::write_bytes(page as *mut Page, 0, 1); }
+        page.typeid = pagetype;
+        Ok(page_ix)
+    }
+
+    fn free_page(&mut self, page_ix: usize) {
+        self.set_page_state(page_ix, PAGE_FREE);
+        let head = self.metadata().free;
+        let page = self.page_mut(page_ix);
+        page.typeid = PageType::Unallocated;
+        page.data[..4].copy_from_slice(&head.to_le_bytes());
+        self.metadata_mut().free = page_ix as u32;
+    }
+
+    // Recycled pages first, then pages never handed out, then a larger file
+    fn allocate_page(&mut self) -> Result<usize> {
+        let head = self.metadata().free as usize;
+        if head != 0 {
+            self.metadata_mut().free = Self::next_free(self.page(head));
+            return Ok(head);
+        }
+        loop {
+            let fresh = self.metadata().fresh as usize;
+            if fresh >= self.size {
+                let max = u32::MAX as usize;
+                if self.size >= max {
+                    return Err(Error::new(ErrorKind::Other, "database is full"));
+                }
+                let size = cmp::min(self.size * 2, max);
+                self.grow(size)?;
+            }
+            self.metadata_mut().fresh = fresh as u32 + 1;
+            // Trunk pages are placed among the fresh ones as the file grows
+            if self.page_state(fresh) == PAGE_FREE {
+                return Ok(fresh);
+            }
+        }
+    }
+
+    fn next_free(page: &Page) -> u32 {
+        let mut next = [0u8; 4];
+        next.copy_from_slice(&page.data[..4]);
+        u32::from_le_bytes(next)
+    }
+
+    // Trunk pages in chain order, starting with page 1
+    fn trunks(&self) -> Vec<usize> {
+        let mut trunks = vec![1];
+        loop {
+            match self.arraylist(*trunks.last().unwrap(), PageType::PageTrunk).next as usize {
+                0 => return trunks,
+                next => trunks.push(next),
+            }
+        }
+    }
+
+    fn page_state(&self, page_ix: usize) -> u8 {
+        let trunk_ix = self.trunks()[page_ix / TRUNK_SPAN];
+        self.arraylist(trunk_ix, PageType::PageTrunk).data[page_ix % TRUNK_SPAN]
+    }
+
+    fn set_page_state(&mut self, page_ix: usize, state: u8) {
+        let trunk_ix = self.trunks()[page_ix / TRUNK_SPAN];
+        self.arraylist_mut(trunk_ix, PageType::PageTrunk).data[page_ix % TRUNK_SPAN] = state;
+    }
+
+    // New pages start free; a trunk page is chained in wherever the trunk no longer covers the file
+    fn grow(&mut self, size: usize) -> Result<()> {
+        self.buffer.resize(size * mem::size_of::<Page>())?;
+        self.size = size;
+        self.metadata_mut().size = size as u32;
+        let mut trunks = self.trunks();
+        while trunks.len() * TRUNK_SPAN < size {
+            let trunk_ix = trunks.len() * TRUNK_SPAN;
+            let page = self.page_mut(trunk_ix);
+            unsafe { ptr::write_bytes(page as *mut Page, 0, 1); }
+            self.arraylist_mut(trunk_ix, PageType::PageTrunk).data[0] = PAGE_USED;
+            self.arraylist_mut(*trunks.last().unwrap(), PageType::PageTrunk).next = trunk_ix as u32;
+            trunks.push(trunk_ix);
+        }
+        Ok(())
+    }
+
+    fn catalog(&self, name: &str) -> Result<Catalog> {
+        match self.tree_lookup(3, name.as_bytes())? {
+            Some((_, (page_ix, slot))) => Catalog::from_row(&self.freelist_row(page_ix, slot)?),
+            None => Err(Error::new(ErrorKind::NotFound, format!("no such table: {}", name))),
+        }
+    }
+
+    fn user_catalog(&self, name: &str) -> Result<Catalog> {
+        if name == ROOT_TABLE {
+            return Err(Error::new(ErrorKind::PermissionDenied, "the catalog is not a user table"));
+        }
+        self.table_catalog(name)
+    }
+
+    fn table_catalog(&self, name: &str) -> Result<Catalog> {
+        let catalog = self.catalog(name)?;
+        if catalog.tabletype != TableType::Table {
+            return Err(Error::new(ErrorKind::NotFound, format!("no such table: {}", name)));
+        }
+        Ok(catalog)
+    }
+
+    // Index names start "<table>.", so only that stretch of the Directory is read; a table whose own
+    // name shares the prefix is skipped by type
+    fn indexes(&self, table: &str) -> Result<Vec<Catalog>> {
+        let prefix = format!("{}.", table);
+        let (low, high) = if prefix.len() > KEY_PREFIX {
+            (bytestring!(prefix).floor(), bytestring!(prefix).ceiling())
+        } else {
+            (bytestring!(prefix), bytestring!(format!("{}/", table)))
+        };
+        let mut indexes = Vec::new();
+        for (name, _) in self.tree_range::<usize>(2, &low, &high) {
+            let catalog = self.catalog(&String::from_utf8_lossy(&self.key_text(&name)?))?;
+            if catalog.tabletype == TableType::Index && catalog.parent == table {
+                indexes.push(catalog);
+            }
+        }
+        Ok(indexes)
+    }
+
+    // Secondary keys end the value with a zero so that every key of one value sorts together
+    fn index_key(value: &Value, primary: &Value) -> Vec<u8> {
+        let mut bytes = value.key_bytes();
+        bytes.push(0);
+        bytes.extend_from_slice(&primary.key_bytes());
+        bytes
+    }
+
+    // The lowest or highest key a value can have; long values widen this to their prefix. Secondary
+    // keys of the value lie between it followed by the zero and it followed by a one, which none equals
+    fn index_bound(value: &Value, secondary: bool, upper: bool) -> ByteString {
+        let mut bytes = value.key_bytes();
+        if secondary {
+            bytes.push(upper as u8);
+        }
+        let key = ByteString::from_bytes(&bytes);
+        if upper { key.ceiling() } else { key.floor() }
+    }
+
+    // The index and key range to scan when the clauses bound the column, if they do
+    fn scan_range(&self, root: usize, column: &str, secondary: bool, clauses: &[Where]) -> Option<(usize, ByteString, ByteString)> {
+        let (mut low, mut high) = (None, None);
+        for clause in clauses {
+            let (clause_low, clause_high) = clause.expr().bounds(column);
+            if let Some(key) = clause_low.map(|value| Self::index_bound(&value, secondary, false)) {
+                if low.is_none_or(|low| key > low) {
+                    low = Some(key);
+                }
+            }
+            if let Some(key) = clause_high.map(|value| Self::index_bound(&value, secondary, true)) {
+                if high.is_none_or(|high| key < high) {
+                    high = Some(key);
+                }
+            }
+        }
+        if low.is_none() && high.is_none() {
+            return None;
+        }
+        Some((root, low.unwrap_or(bytestring!("")), high.unwrap_or(ByteString::max())))
+    }
+
+    fn index_range(&self, root: usize, low: &ByteString, high: &ByteString) -> Result<Vec<(ByteString, (usize, usize))>> {
+        self.in_text_order(self.tree_range(root, low, high))
+    }
+
+    fn index_add(&mut self, index: &Catalog, schema: &Schema, row: &[Value], ptr: (usize, usize)) -> Result<()> {
+        let column = &index.schema.columns()[0].name;
+        let value = &row[schema.position(column).unwrap()];
+        if index.unique && !value.is_null() && !self.index_rows(index, schema, value)?.is_empty() {
+            return Err(ConstraintViolation::Unique { table: index.parent.clone(), column: column.clone() }.into());
+        }
+        let key = Self::index_key(value, &row[schema.primary_index().unwrap()]);
+        self.index_insert(index.root, &key, ptr)
+    }
+
+    fn index_remove(&mut self, index: &Catalog, schema: &Schema, row: &[Value]) -> Result<()> {
+        let value = &row[schema.position(&index.schema.columns()[0].name).unwrap()];
+        let key = Self::index_key(value, &row[schema.primary_index().unwrap()]);
+        self.index_delete(index.root, &key)?;
+        Ok(())
+    }
+
+    fn index_delete(&mut self, root: usize, key: &[u8]) -> Result<Option<(usize, usize)>> {
+        self.tree_remove(root, key)
+    }
+
+    // Rows of the table whose indexed column holds the value
+    fn index_rows(&self, index: &Catalog, schema: &Schema, value: &Value) -> Result<Vec<Vec<Value>>> {
+        let position = schema.position(&index.schema.columns()[0].name).unwrap();
+        let (low, high) = (Self::index_bound(value, true, false), Self::index_bound(value, true, true));
+        let mut rows = Vec::new();
+        for (_, (page_ix, slot)) in self.index_range(index.root, &low, &high)? {
+            let row = schema.decode_row(&self.freelist_row(page_ix, slot)?)?;
+            if row[position] == *value {
+                rows.push(row);
+            }
+        }
+        Ok(rows)
+    }
+
+    fn leaf_for(&self, root: usize, key: &ByteString) -> usize {
+        self.tree_path(root, key).0
+    }
+
+    fn index_insert(&mut self, root: usize, key: &[u8], ptr: (usize, usize)) -> Result<()> {
+        if !self.tree_add(root, key, ptr)? {
+            return Err(Error::new(ErrorKind::AlreadyExists, "key is already in the index"));
+        }
+        Ok(())
+    }
+
+    fn is_leaf(&self, page_ix: usize) -> bool {
+        matches!(self.page_type(page_ix), Some(PageType::IndexLeaf) | Some(PageType::DirectoryLeaf))
+    }
+
+    // The leaf a key belongs in, and the nodes above it from the root down; keys below a node's
+    // first entry, which a parent may file under a lower key, belong to its first child
+    fn tree_path(&self, root: usize, key: &ByteString) -> (usize, Vec<usize>) {
+        let (mut page_ix, mut path) = (root, Vec::new());
+        while !self.is_leaf(page_ix) {
+            let node: &BTree<ByteString, usize> = self.btree(page_ix, self.page_type(page_ix).unwrap());
+            path.push(page_ix);
+            page_ix = match node.range(..=*key).next_back() {
+                Some((_, &child)) => child,
+                None => self.tree_entries::<usize>(page_ix)[0].1,
+            };
+        }
+        (page_ix, path)
+    }
+
+    fn tree_entries<V>(&self, page_ix: usize) -> Vec<(ByteString, V)>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let tree: &BTree<ByteString, V> = self.btree(page_ix, self.page_type(page_ix).unwrap());
+        tree.iter().map(|(&key, &value)| (key, value)).collect()
+    }
+
+    fn tree_find<V>(&self, root: usize, key: &ByteString) -> Option<V>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let leaf = self.tree_path(root, key).0;
+        let tree: &BTree<ByteString, V> = self.btree(leaf, self.page_type(leaf).unwrap());
+        tree.find(key).cloned()
+    }
+
+    // The full text of a key, read back from its chain when long
+    fn key_text(&self, key: &ByteString) -> Result<Vec<u8>> {
+        if !key.is_long() {
+            return Ok(key.as_bytes().to_vec());
+        }
+        self.overflow_read(key.as_bytes(), key.chain(), key.len as usize)
+    }
+
+    // Sorts each run of keys sharing their first KEY_PREFIX bytes, long keys among them, by full text
+    fn in_text_order<V>(&self, mut entries: Vec<(ByteString, V)>) -> Result<Vec<(ByteString, V)>>
+        where V: Copy
+    {
+        let alike = |a: &ByteString, b: &ByteString| {
+            a.len as usize >= KEY_PREFIX && b.len as usize >= KEY_PREFIX && a.data[..KEY_PREFIX] == b.data[..KEY_PREFIX]
+        };
+        let mut start = 0;
+        while start < entries.len() {
+            let mut end = start + 1;
+            while end < entries.len() && alike(&entries[start].0, &entries[end].0) {
+                end += 1;
+            }
+            if entries[start..end].iter().any(|&(key, _)| key.is_long()) {
+                let mut run = entries[start..end].iter()
+                    .map(|&(key, value)| Ok((self.key_text(&key)?, (key, value))))
+                    .collect::<Result<Vec<_>>>()?;
+                run.sort_by(|a, b| a.0.cmp(&b.0));
+                for (slot, (_, entry)) in entries[start..end].iter_mut().zip(run) {
+                    *slot = entry;
+                }
+            }
+            start = end;
+        }
+        Ok(entries)
+    }
+
+    // The stored key with exactly these bytes and its value; long keys alike up to the tiebreak are told apart by text
+    fn tree_lookup<V>(&self, root: usize, bytes: &[u8]) -> Result<Option<(ByteString, V)>>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let key = ByteString::from_bytes(bytes);
+        if !key.is_long() {
+            return Ok(self.tree_find(root, &key).map(|value| (key, value)));
+        }
+        for (other, value) in self.tree_range::<V>(root, &key.with_tiebreak(0), &key.with_tiebreak(u32::MAX)) {
+            if self.key_text(&other)? == bytes {
+                return Ok(Some((other, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    // False when the key is already present; a long key takes the next tiebreak and a chain of its own
+    fn tree_add<V>(&mut self, root: usize, bytes: &[u8], value: V) -> Result<bool>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        if self.tree_lookup::<V>(root, bytes)?.is_some() {
+            return Ok(false);
+        }
+        let mut key = ByteString::from_bytes(bytes);
+        if key.is_long() {
+            let alike = self.tree_range::<V>(root, &key.with_tiebreak(0), &key.with_tiebreak(u32::MAX));
+            let tiebreak = match alike.last() {
+                Some(&(last, _)) => last.tiebreak().checked_add(1)
+                    .ok_or_else(|| Error::new(ErrorKind::Other, "too many keys share a hash"))?,
+                None => 0,
+            };
+            key = key.with_tiebreak(tiebreak).with_chain(self.overflow_write(&bytes[KEY_PREFIX..])?);
+        }
+        self.tree_insert(root, key, value)
+    }
+
+    // Separators copied into the nodes above keep the chain number, but only leaves' keys are ever read back
+    fn tree_remove<V>(&mut self, root: usize, bytes: &[u8]) -> Result<Option<V>>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let key = match self.tree_lookup::<V>(root, bytes)? {
+            Some((key, _)) => key,
+            None => return Ok(None),
+        };
+        let value = self.tree_delete(root, &key);
+        if key.is_long() {
+            self.overflow_free(Some(key.chain()));
+        }
+        Ok(value)
+    }
+
+    // Starts at the leaf holding the low key and follows the leaves' links until one passes the high key
+    fn tree_range<V>(&self, root: usize, low: &ByteString, high: &ByteString) -> Vec<(ByteString, V)>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let mut entries = Vec::new();
+        let mut leaf = self.tree_path(root, low).0;
+        while leaf != 0 {
+            let tree: &BTree<ByteString, V> = self.btree(leaf, self.page_type(leaf).unwrap());
+            entries.extend(tree.range(*low..=*high).map(|(&key, &value)| (key, value)));
+            leaf = self.bplustree(leaf, self.page_type(leaf).unwrap()).cont as usize;
+            if leaf != 0 && self.tree_entries::<V>(leaf).first().is_some_and(|&(first, _)| first > *high) {
+                break;
+            }
+        }
+        entries
+    }
+
+    // False when the key is already present
+    fn tree_insert<V>(&mut self, root: usize, key: ByteString, value: V) -> Result<bool>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let (leaf, mut path) = self.tree_path(root, &key);
+        let tree: &mut BTree<ByteString, V> = self.btree_mut(leaf, self.page_type(leaf).unwrap());
+        if tree.find(&key).is_some() {
+            return Ok(false);
+        }
+        if tree.insert(key, value) {
+            return Ok(true);
+        }
+
+        // Split full pages from the leaf up, filing each new page in its parent under its lowest key
+        let (mut key, mut child) = self.split_node(leaf, key, value)?;
+        loop {
+            let parent = path.pop().unwrap();
+            let node: &mut BTree<ByteString, usize> = self.btree_mut(parent, self.page_type(parent).unwrap());
+            if node.insert(key, child) {
+                return Ok(true);
+            }
+            if path.is_empty() {
+                self.split_root(parent, key, child)?;
+                return Ok(true);
+            }
+            let (next_key, next_child) = self.split_node(parent, key, child)?;
+            key = next_key;
+            child = next_child;
+        }
+    }
+
+    // Moves the upper half of a full page and the new entry to a new page of the same type
+    fn split_node<V>(&mut self, page_ix: usize, key: ByteString, value: V) -> Result<(ByteString, usize)>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let pagetype = self.page_type(page_ix).unwrap();
+        let mut entries = self.tree_entries::<V>(page_ix);
+        let at = entries.iter().position(|&(other, _)| key < other).unwrap_or(entries.len());
+        entries.insert(at, (key, value));
+        let (lower, upper) = entries.split_at(entries.len() / 2);
+        let new_page = self.create_page(pagetype)?;
+        self.fill_node(page_ix, pagetype, lower)?;
+        self.fill_node(new_page, pagetype, upper)?;
+        let cont = self.bplustree(page_ix, pagetype).cont;
+        self.bplustree_mut(new_page, pagetype).cont = cont;
+        self.bplustree_mut(page_ix, pagetype).cont = new_page as u32;
+        Ok((upper[0].0, new_page))
+    }
+
+    // The root keeps its page, so its entries move down into two new nodes beneath it
+    fn split_root(&mut self, root: usize, key: ByteString, child: usize) -> Result<()> {
+        let pagetype = self.page_type(root).unwrap();
+        let mut entries = self.tree_entries::<usize>(root);
+        let at = entries.iter().position(|&(other, _)| key < other).unwrap_or(entries.len());
+        entries.insert(at, (key, child));
+        let (lower, upper) = entries.split_at(entries.len() / 2);
+        let (left, right) = (self.create_page(PageType::IndexRoot)?, self.create_page(PageType::IndexRoot)?);
+        self.fill_node(left, PageType::IndexRoot, lower)?;
+        self.fill_node(right, PageType::IndexRoot, upper)?;
+        self.bplustree_mut(left, PageType::IndexRoot).cont = right as u32;
+        self.fill_node(root, pagetype, &[(bytestring!(""), left), (upper[0].0, right)])
+    }
+
+    fn fill_node<V>(&mut self, page_ix: usize, pagetype: PageType, entries: &[(ByteString, V)]) -> Result<()>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let page = self.bplustree_mut(page_ix, pagetype);
+        let tree: &mut BTree<ByteString, V> = page.create_tree();
+        for &(key, value) in entries {
+            if !tree.insert(key, value) {
+                return Err(Error::new(ErrorKind::Other, "tree node is full"));
+            }
+        }
+        Ok(())
+    }
+
+    // A leaf left empty is unlinked and recycled along with the nodes above it that hold nothing else,
+    // unless that would empty the root
+    fn tree_delete<V>(&mut self, root: usize, key: &ByteString) -> Option<V>
+        where V: Copy + Sized + fmt::Debug + 'static,
+    {
+        let (leaf, path) = self.tree_path(root, key);
+        let leaf_type = self.page_type(leaf).unwrap();
+        let tree: &mut BTree<ByteString, V> = self.btree_mut(leaf, leaf_type);
+        let value = tree.find(key).cloned();
+        tree.delete(key);
+        if !tree.is_empty() {
+            return value;
+        }
+        let (mut branch, mut level) = (leaf, path.len());
+        while level > 0 && self.tree_entries::<usize>(path[level - 1]).len() < 2 {
+            level -= 1;
+            branch = path[level];
+        }
+        if level == 0 {
+            return value;
+        }
+        if let Some(prev) = self.prev_leaf(leaf, &path) {
+            let cont = self.bplustree(leaf, leaf_type).cont;
+            self.bplustree_mut(prev, leaf_type).cont = cont;
+        }
+        let parent = path[level - 1];
+        let siblings = self.tree_entries::<usize>(parent);
+        let at = siblings.iter().position(|&(_, page_ix)| page_ix == branch).unwrap();
+        let node: &mut BTree<ByteString, usize> = self.btree_mut(parent, self.page_type(parent).unwrap());
+        node.delete(&siblings[at].0);
+        if at == 0 {
+            // The next child takes over the lowest key, so keys below it still find a page
+            node.delete(&siblings[1].0);
+            node.insert(siblings[0].0, siblings[1].1);
+        }
+        for &page_ix in path[level..].iter().chain(Some(&leaf)) {
+            self.free_page(page_ix);
+        }
+        value
+    }
+
+    // The leaf before a page, under the nearest ancestor that does not file the page's branch first
+    fn prev_leaf(&self, page_ix: usize, path: &[usize]) -> Option<usize> {
+        let mut child = page_ix;
+        for &node in path.iter().rev() {
+            let entries = self.tree_entries::<usize>(node);
+            let at = entries.iter().position(|&(_, page_ix)| page_ix == child).unwrap();
+            if at > 0 {
+                let mut prev = entries[at - 1].1;
+                while !self.is_leaf(prev) {
+                    prev = self.tree_entries::<usize>(prev).last().unwrap().1;
+                }
+                return Some(prev);
+            }
+            child = node;
+        }
+        None
+    }
+
+    // The leaves of a tree in key order
+    #[cfg(test)]
+    fn leaves(&self, root: usize) -> Vec<usize> {
+        let (mut leaves, mut leaf) = (Vec::new(), self.tree_path(root, &bytestring!("")).0);
+        while leaf != 0 {
+            leaves.push(leaf);
+            leaf = self.bplustree(leaf, self.page_type(leaf).unwrap()).cont as usize;
+        }
+        leaves
+    }
+
+    // Every page of a tree, root first
+    fn tree_pages(&self, root: usize) -> Vec<usize> {
+        let mut pages = vec![root];
+        let mut i = 0;
+        while i < pages.len() {
+            if !self.is_leaf(pages[i]) {
+                let children = self.tree_entries::<usize>(pages[i]);
+                pages.extend(children.into_iter().map(|(_, child)| child));
+            }
+            i += 1;
+        }
+        pages
+    }
+
+    fn try_insert(&mut self, table: &str, row: &[Value]) -> Result<()> {
+        let catalog = self.user_catalog(table)?;
+        let fields = catalog.schema.encode_row(row)?;
+        let key = row[catalog.schema.primary_index().unwrap()].key_bytes();
+        if self.tree_lookup::<(usize, usize)>(catalog.root, &key)?.is_some() {
+            return Err(ConstraintViolation::PrimaryKey { table: table.to_owned() }.into());
+        }
+        let ptr = self.freelist_insert_row(catalog.data, &fields)?;
+        self.index_insert(catalog.root, &key, ptr)?;
+        for index in self.indexes(table)? {
+            self.index_add(&index, &catalog.schema, row, ptr)?;
+        }
+        Ok(())
+    }
+
+    fn try_delete(&mut self, table: &str, key: &Value) -> Result<bool> {
+        let catalog = self.user_catalog(table)?;
+        let row = match self.get(table, key)? {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        let (page_ix, slot) = self.index_delete(catalog.root, &key.key_bytes())?.unwrap();
+        self.freelist_remove_row(catalog.data, page_ix, slot);
+        for index in self.indexes(table)? {
+            self.index_remove(&index, &catalog.schema, &row)?;
+        }
+        Ok(true)
+    }
+
+    fn try_update(&mut self, table: &str, key: &Value, changes: &[(&str, Value)]) -> Result<bool> {
+        let catalog = self.user_catalog(table)?;
+        let old_row = match self.get(table, key)? {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        let mut row = old_row.clone();
+        for &(column, ref value) in changes {
+            match catalog.schema.position(column) {
+                Some(ix) => row[ix] = value.clone(),
+                None => return Err(Error::new(ErrorKind::InvalidInput, format!("no such column: {}", column))),
+            }
+        }
+        let fields = catalog.schema.encode_row(&row)?;
+        let old_key = key.key_bytes();
+        let new_key = row[catalog.schema.primary_index().unwrap()].key_bytes();
+        if new_key != old_key && self.tree_lookup::<(usize, usize)>(catalog.root, &new_key)?.is_some() {
+            return Err(ConstraintViolation::PrimaryKey { table: table.to_owned() }.into());
+        }
+
+        // A row that outgrows its page moves, and the indexes are repointed at its new slot
+        let (stored_key, old_ptr) = self.tree_lookup::<(usize, usize)>(catalog.root, &old_key)?.unwrap();
+        let ptr = if self.freelist_update_row(old_ptr.0, old_ptr.1, &fields)? {
+            old_ptr
+        } else {
+            let ptr = self.freelist_insert_row(catalog.data, &fields)?;
+            self.freelist_remove_row(catalog.data, old_ptr.0, old_ptr.1);
+            ptr
+        };
+        if new_key == old_key {
+            let leaf = self.leaf_for(catalog.root, &stored_key);
+            let index: &mut BTree<ByteString, (usize, usize)> = self.btree_mut(leaf, PageType::IndexLeaf);
+            *index.find_mut(&stored_key).unwrap() = ptr;
+        } else {
+            self.index_delete(catalog.root, &old_key)?;
+            self.index_insert(catalog.root, &new_key, ptr)?;
+        }
+        for index in self.indexes(table)? {
+            self.index_remove(&index, &catalog.schema, &old_row)?;
+            self.index_add(&index, &catalog.schema, &row, ptr)?;
+        }
+        Ok(true)
+    }
+
+    fn try_create_table(&mut self, name: &str, schema: &Schema) -> Result<()> {
+        let invalid = |msg| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if name.is_empty() || name.len() > NAME_SIZE || name.as_bytes().contains(&0) {
+            return invalid("table name must be 1 to 256 bytes without NUL");
+        }
+        if schema.primary_index().is_none() {
+            return invalid("schema has no primary column");
+        }
+        for (i, column) in schema.columns().iter().enumerate() {
+            if column.name.is_empty() || schema.position(&column.name) != Some(i) {
+                return invalid("column names must be non-empty and distinct");
+            }
+        }
+        if self.tree_lookup::<usize>(2, name.as_bytes())?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("table already exists: {}", name)));
+        }
+
+        let root = self.create_index_root()?;
+        let data = self.create_page(PageType::RowData)?;
+        self.register(&Catalog {
+            name: name.to_owned(), tabletype: TableType::Table, schema: schema.clone(), root: root, data: data,
+            parent: String::new(), unique: false,
+        })
+    }
+
+    fn try_drop_table(&mut self, name: &str) -> Result<()> {
+        if name == ROOT_TABLE {
+            return Err(Error::new(ErrorKind::PermissionDenied, "cannot drop the catalog"));
+        }
+        self.user_catalog(name)?;
+        for index in self.indexes(name)? {
+            self.unregister(&index.name)?;
+        }
+        self.unregister(name)
+    }
+
+    fn try_create_index(&mut self, table: &str, column: &str, unique: bool) -> Result<()> {
+        let catalog = self.user_catalog(table)?;
+        let column_type = match catalog.schema.position(column) {
+            Some(ix) => catalog.schema.columns()[ix].column_type,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("no such column: {}", column))),
+        };
+        let name = format!("{}.{}", table, column);
+        if name.len() > NAME_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "index name must be at most 256 bytes"));
+        }
+        if self.tree_lookup::<usize>(2, name.as_bytes())?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("index already exists: {}", name)));
+        }
+
+        let root = self.create_index_root()?;
+        let mut schema = Schema::new();
+        schema.column(column, column_type);
+        let index = Catalog {
+            name: name, tabletype: TableType::Index, schema: schema, root: root, data: 0,
+            parent: table.to_owned(), unique: unique,
+        };
+        self.register(&index)?;
+        for (_, ptr) in self.index_range(catalog.root, &bytestring!(""), &ByteString::max())? {
+            let row = catalog.schema.decode_row(&self.freelist_row(ptr.0, ptr.1)?)?;
+            self.index_add(&index, &catalog.schema, &row, ptr)?;
+        }
+        Ok(())
+    }
+
+    fn try_drop_index(&mut self, table: &str, column: &str) -> Result<()> {
+        let name = format!("{}.{}", table, column);
+        match self.catalog(&name) {
+            Ok(ref index) if index.tabletype == TableType::Index && index.parent == table => self.unregister(&name),
+            _ => Err(Error::new(ErrorKind::NotFound, format!("no such index: {}", name))),
+        }
+    }
+
+    // An IndexRoot page over one empty IndexLeaf
+    fn create_index_root(&mut self) -> Result<usize> {
+        let root = self.create_page(PageType::IndexRoot)?;
+        let leaf = self.create_page(PageType::IndexLeaf)?;
+        let page = self.bplustree_mut(root, PageType::IndexRoot);
+        let root_tree: &mut BTree<ByteString, usize> = page.create_tree();
+        root_tree.insert(bytestring!(""), leaf);
+        let page = self.bplustree_mut(leaf, PageType::IndexLeaf);
+        page.create_tree::<ByteString, (usize, usize)>();
+        Ok(root)
+    }
+
+    // Record a table or index in ozone_root and the Directory
+    fn register(&mut self, catalog: &Catalog) -> Result<()> {
+        let ptr = self.freelist_insert_row(5, &catalog.to_row())?;
+        self.index_insert(3, catalog.name.as_bytes(), ptr)?;
+        self.tree_add(2, catalog.name.as_bytes(), catalog.root)?;
+        Ok(())
+    }
+
+    // Free a table's or index's pages and forget it
+    fn unregister(&mut self, name: &str) -> Result<()> {
+        let catalog = self.catalog(name)?;
+        for page_ix in self.tree_pages(catalog.root) {
+            self.free_page(page_ix);
+        }
+        let mut page_ix = catalog.data;
+        while page_ix != 0 {
+            let row_data = self.freelist(page_ix, PageType::RowData);
+            for slot in 0..row_data.slots as usize {
+                let (offset, length) = row_data.slot(slot);
+                if offset != SLOT_DEAD {
+                    self.overflow_free(Self::overflow_chain(&row_data.data[offset as usize..(offset as usize + length as usize)]));
+                }
+            }
+            let next = row_data.next as usize;
+            self.free_page(page_ix);
+            page_ix = next;
+        }
+        self.room.remove(&catalog.data);
+
+        if let Some((page_ix, slot)) = self.index_delete(3, name.as_bytes())? {
+            self.freelist_remove_row(5, page_ix, slot);
+        }
+        self.tree_remove::<usize>(2, name.as_bytes())?;
+        Ok(())
+    }
+}
+
+// A decoded ozone_root row
+struct Catalog {
+    name: String,
+    tabletype: TableType,
+    schema: Schema, // The indexed column alone for an index
+    root: usize, // IndexRoot page
+    data: usize, // First RowData page, 0 for an index
+    parent: String, // Table an index belongs to, empty for a table
+    unique: bool,
+}
+
+impl Catalog {
+    fn to_row(&self) -> Vec<Entry> {
+        let colnames = self.schema.columns().iter().map(|column| Entry::Data(column.name.as_bytes().to_vec())).collect();
+        let coltypes = self.schema.columns().iter().map(|column| column.column_type as u8).collect();
+        vec![
+            Entry::Data(self.name.as_bytes().to_vec()),
+            Entry::Data(vec![self.tabletype as u8]),
+            Entry::from_u64(self.schema.len() as u64),
+            Entry::Entry(colnames),
+            Entry::Data(coltypes),
+            Entry::from_u64(self.schema.primary_index().unwrap() as u64),
+            Entry::from_u64(self.root as u64),
+            Entry::from_u64(self.data as u64),
+            Entry::Data(self.parent.as_bytes().to_vec()),
+            Entry::Data(vec![self.unique as u8]),
+        ]
+    }
+
+    fn from_row(row: &[Vec<u8>]) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "catalog row is corrupt");
+        if row.len() != ROOT_COLUMNS.len() {
+            return Err(invalid());
+        }
+        let name = String::from_utf8(row[0].clone()).map_err(|_| invalid())?;
+        let tabletype = match row[1][..] {
+            [0] => TableType::Table,
+            [1] => TableType::Index,
+            _ => return Err(invalid()),
+        };
+        let colnum = Entry::to_u64(&row[2])? as usize;
+        let colnames = Entry::decode_row(&row[3])?;
+        let coltypes = &row[4];
+        let primary = Entry::to_u64(&row[5])? as usize;
+        if colnames.len() != colnum || coltypes.len() != colnum || primary >= colnum {
+            return Err(invalid());
+        }
+        let mut schema = Schema::new();
+        for (column, &tag) in colnames.into_iter().zip(coltypes.iter()) {
+            let column_type = ColumnType::from_tag(tag).ok_or_else(invalid)?;
+            schema.column(&String::from_utf8(column).map_err(|_| invalid())?, column_type);
+        }
+        let primary = schema.columns()[primary].name.clone();
+        schema.primary(&primary);
+        Ok(Catalog {
+            name: name,
+            tabletype: tabletype,
+            schema: schema,
+            root: Entry::to_u64(&row[6])? as usize,
+            data: Entry::to_u64(&row[7])? as usize,
+            parent: String::from_utf8(row[8].clone()).map_err(|_| invalid())?,
+            unique: match row[9][..] {
+                [0] => false,
+                [1] => true,
+                _ => return Err(invalid()),
+            },
+        })
+    }
+}
+
+pub enum Entry {
+    Entry(Vec<Entry>),
+    Data(Vec<u8>),
+}
+
+/* Row Layout
+ * ------------
+ * count     u32         Number of fields
+ * sizes     [u32]       Byte length of every field
+ * fields    [u8]        Field bytes, back to back; a nested entry is itself a row
+ *
+ * All integers are little-endian.
+ */
+
+impl Entry {
+    pub fn new() -> Self {
+        Entry::Entry(Vec::new())
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Entry::Data(value.to_le_bytes().to_vec())
+    }
+
+    fn to_u64(bytes: &[u8]) -> Result<u64> {
+        if bytes.len() != 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "expected an 8 byte integer"));
+        }
+        let mut array = [0u8; 8];
+        array.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(array))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match *self {
+            Entry::Data(ref data) => data.clone(),
+            Entry::Entry(ref entries) => Entry::encode_row(entries),
+        }
+    }
+
+    fn encode_row(row: &[Entry]) -> Vec<u8> {
+        let fields = row.iter().map(Entry::encode).collect::<Vec<_>>();
+        let mut record = Vec::new();
+        record.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        for field in fields.iter() {
+            record.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        }
+        for field in fields.iter() {
+            record.extend_from_slice(field);
+        }
+        record
+    }
+
+    fn decode_row(record: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "row is corrupt");
+        let read_u32 = |offset: usize| -> Result<usize> {
+            if offset + 4 > record.len() {
+                return Err(invalid());
+            }
+            let mut array = [0u8; 4];
+            array.copy_from_slice(&record[offset..(offset + 4)]);
+            Ok(u32::from_le_bytes(array) as usize)
+        };
+        let count = read_u32(0)?;
+        let mut cursor = 4 + count.checked_mul(4).ok_or_else(invalid)?;
+        let mut fields = Vec::with_capacity(cmp::min(count, record.len()));
+        for i in 0..count {
+            let size = read_u32(4 + i * 4)?;
+            if cursor + size > record.len() {
+                return Err(invalid());
+            }
+            fields.push(record[cursor..(cursor + size)].to_vec());
+            cursor += size;
+        }
+        Ok(fields)
+    }
+}
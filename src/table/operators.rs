@@ -1,3 +1,256 @@
+use std::cmp::Ordering;
+use std::io::{Result, Error, ErrorKind};
 
-struct Eq {
+use table::schema::Schema;
+use table::value::Value;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/* Expressions use two-valued logic: a comparison, range, list or pattern test
+ * is false when the column is null or holds a value of another type, and
+ * only is_null matches nulls.
+ */
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Compare(String, Comparison, Value),
+    Between(String, Value, Value), // Inclusive at both ends
+    In(String, Vec<Value>),
+    IsNull(String),
+    Prefix(String, Value), // Text or blob starting with the value
+    Like(String, String), // % matches any run of characters, _ exactly one
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Where(Expr);
+
+pub fn eq<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Eq, value.into())
+}
+
+pub fn ne<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Ne, value.into())
+}
+
+pub fn lt<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Lt, value.into())
+}
+
+pub fn le<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Le, value.into())
+}
+
+pub fn gt<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Gt, value.into())
+}
+
+pub fn ge<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Compare(column.to_owned(), Comparison::Ge, value.into())
+}
+
+pub fn between<V: Into<Value>>(column: &str, low: V, high: V) -> Expr {
+    Expr::Between(column.to_owned(), low.into(), high.into())
+}
+
+pub fn in_<V: Into<Value>>(column: &str, values: Vec<V>) -> Expr {
+    Expr::In(column.to_owned(), values.into_iter().map(Into::into).collect())
+}
+
+pub fn is_null(column: &str) -> Expr {
+    Expr::IsNull(column.to_owned())
+}
+
+pub fn prefix<V: Into<Value>>(column: &str, value: V) -> Expr {
+    Expr::Prefix(column.to_owned(), value.into())
+}
+
+pub fn like(column: &str, pattern: &str) -> Expr {
+    Expr::Like(column.to_owned(), pattern.to_owned())
+}
+
+pub fn and(left: Expr, right: Expr) -> Expr {
+    Expr::And(Box::new(left), Box::new(right))
+}
+
+pub fn or(left: Expr, right: Expr) -> Expr {
+    Expr::Or(Box::new(left), Box::new(right))
+}
+
+pub fn not(expr: Expr) -> Expr {
+    Expr::Not(Box::new(expr))
+}
+
+pub fn where_(expr: Expr) -> Where {
+    Where(expr)
+}
+
+impl Expr {
+    // Fails on columns the schema does not have
+    pub fn check(&self, schema: &Schema) -> Result<()> {
+        match *self {
+            Expr::Compare(ref column, _, _) | Expr::Between(ref column, _, _) | Expr::In(ref column, _) |
+            Expr::IsNull(ref column) | Expr::Prefix(ref column, _) | Expr::Like(ref column, _) => {
+                match schema.position(column) {
+                    Some(_) => Ok(()),
+                    None => Err(Error::new(ErrorKind::InvalidInput, format!("no such column: {}", column))),
+                }
+            }
+            Expr::And(ref left, ref right) | Expr::Or(ref left, ref right) => {
+                left.check(schema)?;
+                right.check(schema)
+            }
+            Expr::Not(ref expr) => expr.check(schema),
+        }
+    }
+
+    pub fn eval(&self, schema: &Schema, row: &[Value]) -> bool {
+        let value = |column: &str| &row[schema.position(column).unwrap()];
+        match *self {
+            Expr::Compare(ref column, op, ref operand) => match compare(value(column), operand) {
+                Some(ordering) => match op {
+                    Comparison::Eq => ordering == Ordering::Equal,
+                    Comparison::Ne => ordering != Ordering::Equal,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::Le => ordering != Ordering::Greater,
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::Ge => ordering != Ordering::Less,
+                },
+                None => false,
+            },
+            Expr::Between(ref column, ref low, ref high) => {
+                compare(value(column), low).is_some_and(|o| o != Ordering::Less) &&
+                compare(value(column), high).is_some_and(|o| o != Ordering::Greater)
+            }
+            Expr::In(ref column, ref values) => {
+                values.iter().any(|operand| compare(value(column), operand) == Some(Ordering::Equal))
+            }
+            Expr::IsNull(ref column) => value(column).is_null(),
+            Expr::Prefix(ref column, ref operand) => match (value(column), operand) {
+                (&Value::Text(ref text), &Value::Text(ref prefix)) => text.starts_with(&prefix[..]),
+                (&Value::Blob(ref blob), &Value::Blob(ref prefix)) => blob.starts_with(&prefix[..]),
+                _ => false,
+            },
+            Expr::Like(ref column, ref pattern) => match *value(column) {
+                Value::Text(ref text) => {
+                    like_match(&text.chars().collect::<Vec<_>>(), &pattern.chars().collect::<Vec<_>>())
+                }
+                _ => false,
+            },
+            Expr::And(ref left, ref right) => left.eval(schema, row) && right.eval(schema, row),
+            Expr::Or(ref left, ref right) => left.eval(schema, row) || right.eval(schema, row),
+            Expr::Not(ref expr) => !expr.eval(schema, row),
+        }
+    }
+
+    // Inclusive bounds on a column implied by this expression, used to narrow scans
+    pub fn bounds(&self, column: &str) -> (Option<Value>, Option<Value>) {
+        match *self {
+            Expr::Compare(ref c, op, ref operand) if c == column => match op {
+                Comparison::Eq => (Some(operand.clone()), Some(operand.clone())),
+                Comparison::Gt | Comparison::Ge => (Some(operand.clone()), None),
+                Comparison::Lt | Comparison::Le => (None, Some(operand.clone())),
+                Comparison::Ne => (None, None),
+            },
+            Expr::Between(ref c, ref low, ref high) if c == column => (Some(low.clone()), Some(high.clone())),
+            Expr::And(ref left, ref right) => {
+                let (left_low, left_high) = left.bounds(column);
+                let (right_low, right_high) = right.bounds(column);
+                (tighter(left_low, right_low, Ordering::Greater), tighter(left_high, right_high, Ordering::Less))
+            }
+            _ => (None, None),
+        }
+    }
+}
+
+impl Where {
+    pub fn expr(&self) -> &Expr {
+        &self.0
+    }
+}
+
+// Values of different types never compare
+fn compare(value: &Value, operand: &Value) -> Option<Ordering> {
+    match (value.column_type(), operand.column_type()) {
+        (Some(a), Some(b)) if a == b => Some(value.cmp(operand)),
+        _ => None,
+    }
+}
+
+fn tighter(a: Option<Value>, b: Option<Value>, keep: Ordering) -> Option<Value> {
+    match (a, b) {
+        (Some(a), Some(b)) => if a.cmp(&b) == keep { Some(a) } else { Some(b) },
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// Greedy wildcard matching: on a mismatch, let the last % swallow one more character and retry from
+// there. Earlier %s never need revisiting, so this is O(text * pattern) at worst
+fn like_match(text: &[char], pattern: &[char]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    let mut retry = None; // Pattern position after the last %, and the text position it resumes from
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'%') => {
+                p += 1;
+                retry = Some((p, t));
+            }
+            Some(&c) if c == '_' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match retry {
+                Some((after, from)) => {
+                    p = after;
+                    t = from + 1;
+                    retry = Some((after, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
+}
+
+#[test]
+fn evaluate_expressions() {
+    use table::value::ColumnType;
+    let mut schema = Schema::new();
+    schema.column("id", ColumnType::Int).column("name", ColumnType::Text).column("note", ColumnType::Text);
+    let row = vec![Value::Int(5), Value::from("ozone"), Value::Null];
+
+    assert!(eq("id", 5i64).eval(&schema, &row));
+    assert!(!eq("id", 5u64).eval(&schema, &row));
+    assert!(between("id", 1i64, 5i64).eval(&schema, &row));
+    assert!(in_("id", vec![3i64, 5]).eval(&schema, &row));
+    assert!(is_null("note").eval(&schema, &row));
+    assert!(!ne("note", "x").eval(&schema, &row));
+    assert!(prefix("name", "oz").eval(&schema, &row));
+    assert!(like("name", "o_o%").eval(&schema, &row));
+    assert!(!like("name", "o_o").eval(&schema, &row));
+    assert!(and(gt("id", 4i64), not(lt("id", 5i64))).eval(&schema, &row));
+    assert!(or(eq("id", 1i64), like("name", "%e")).eval(&schema, &row));
+    assert!(like("name", "%%z%").eval(&schema, &row));
+    assert!(!like("name", "%z_").eval(&schema, &row));
+    assert!(like("name", "%").eval(&schema, &row));
+
+    // Backtracking over every % would take exponential time here
+    let row = vec![Value::Int(5), Value::from("a".repeat(2000)), Value::Null];
+    assert!(!like("name", "%a%a%a%a%a%a%a%a%b").eval(&schema, &row));
+    assert!(like("name", "%a%a%a%a%a%a%a%a%").eval(&schema, &row));
+    assert!(eq("rank", 1i64).check(&schema).is_err());
+
+    let (low, high) = and(ge("id", 2i64), and(le("id", 9i64), between("id", 4i64, 20i64))).bounds("id");
+    assert_eq!((Some(Value::Int(4)), Some(Value::Int(9))), (low, high));
 }
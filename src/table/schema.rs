@@ -0,0 +1,139 @@
+use std::{error, fmt};
+use std::io::{Result, Error, ErrorKind};
+
+use table::Entry;
+use table::value::{ColumnType, Value};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Schema {
+    columns: Vec<Column>,
+    primary: Option<String>, // First column when unset
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Schema { columns: Vec::new(), primary: None }
+    }
+
+    pub fn column(&mut self, name: &str, column_type: ColumnType) -> &mut Self {
+        self.columns.push(Column { name: name.to_owned(), column_type: column_type });
+        self
+    }
+
+    pub fn primary(&mut self, name: &str) -> &mut Self {
+        self.primary = Some(name.to_owned());
+        self
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    pub fn primary_index(&self) -> Option<usize> {
+        match self.primary {
+            Some(ref name) => self.position(name),
+            None if self.columns.is_empty() => None,
+            None => Some(0),
+        }
+    }
+
+    pub fn primary_column(&self) -> Option<&str> {
+        self.primary_index().map(|ix| &self.columns[ix].name[..])
+    }
+
+    // Every value must match its column's type; only the primary key may not be null
+    pub fn check(&self, row: &[Value]) -> Result<()> {
+        let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if row.len() != self.columns.len() {
+            return invalid(format!("expected {} values, got {}", self.columns.len(), row.len()));
+        }
+        for (ix, (column, value)) in self.columns.iter().zip(row.iter()).enumerate() {
+            match value.column_type() {
+                None if Some(ix) == self.primary_index() => {
+                    return invalid(format!("primary key {} cannot be null", column.name));
+                }
+                Some(column_type) if column_type != column.column_type => {
+                    return invalid(format!("column {} expects {:?}, got {:?}", column.name, column.column_type, column_type));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode_row(&self, row: &[Value]) -> Result<Vec<Entry>> {
+        self.check(row)?;
+        Ok(row.iter().map(|value| Entry::Data(value.encode())).collect())
+    }
+
+    pub fn decode_row(&self, fields: &[Vec<u8>]) -> Result<Vec<Value>> {
+        let row = fields.iter().map(|field| Value::decode(field)).collect::<Result<Vec<_>>>()?;
+        self.check(&row).map_err(|_| Error::new(ErrorKind::InvalidData, "row does not match its schema"))?;
+        Ok(row)
+    }
+}
+
+// Carried by the AlreadyExists error of a write that would duplicate a key
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConstraintViolation {
+    PrimaryKey { table: String },
+    Unique { table: String, column: String },
+}
+
+impl ConstraintViolation {
+    pub fn of(error: &Error) -> Option<&ConstraintViolation> {
+        error.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl From<ConstraintViolation> for Error {
+    fn from(violation: ConstraintViolation) -> Self {
+        Error::new(ErrorKind::AlreadyExists, violation)
+    }
+}
+
+impl fmt::Display for ConstraintViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConstraintViolation::PrimaryKey { ref table } => write!(f, "a row of {} already has this primary key", table),
+            ConstraintViolation::Unique { ref table, ref column } => write!(f, "a row of {} already has this {}", table, column),
+        }
+    }
+}
+
+impl error::Error for ConstraintViolation {}
+
+#[test]
+fn check_rows_against_schema() {
+    let mut schema = Schema::new();
+    schema.column("id", ColumnType::UInt).column("name", ColumnType::Text);
+    assert!(schema.check(&[Value::UInt(1), Value::from("a")]).is_ok());
+    assert!(schema.check(&[Value::UInt(1), Value::Null]).is_ok());
+    assert!(schema.check(&[Value::Null, Value::from("a")]).is_err());
+    assert!(schema.check(&[Value::Int(1), Value::from("a")]).is_err());
+    assert!(schema.check(&[Value::UInt(1)]).is_err());
+
+    let fields = schema.encode_row(&[Value::UInt(1), Value::from("a")]).unwrap().iter().map(|entry| match *entry {
+        Entry::Data(ref data) => data.clone(),
+        Entry::Entry(_) => unreachable!(),
+    }).collect::<Vec<_>>();
+    assert_eq!(vec![Value::UInt(1), Value::from("a")], schema.decode_row(&fields).unwrap());
+}
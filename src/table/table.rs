@@ -1,9 +1,289 @@
+use std::marker::PhantomData;
+use std::io::{Result, Error, ErrorKind};
+
+use buffer::Buffer;
+use table::{Database, Page};
+use table::operators::Where;
+use table::schema::Schema;
+use table::value::{ColumnType, Value};
+
+// A Rust type stored in one column; Option<T> is a nullable column of T
+pub trait ColumnValue: Sized {
+    fn column_type() -> ColumnType;
+    fn into_value(self) -> Value;
+    fn from_value(value: Value) -> Result<Self>;
+}
+
+// The column types of a typed table and the conversion between its rows and tuples
+pub trait RowType {
+    type Row;
+    fn column_types() -> Vec<ColumnType>;
+    fn into_values(row: Self::Row) -> Vec<Value>;
+    fn from_values(values: Vec<Value>) -> Result<Self::Row>;
+
+    // A schema with these column types under the given names, keyed on the first
+    fn schema(names: &[&str]) -> Schema {
+        let types = Self::column_types();
+        assert_eq!(types.len(), names.len(), "one name per column");
+        let mut schema = Schema::new();
+        for (&name, &column_type) in names.iter().zip(types.iter()) {
+            schema.column(name, column_type);
+        }
+        schema
+    }
+}
+
+// A struct stored as one row of its own table, usually through #[derive(Record)]
+pub trait Record: Sized {
+    fn table_name() -> &'static str;
+    fn schema() -> Schema;
+    fn to_values(&self) -> Vec<Value>;
+    fn from_values(values: Vec<Value>) -> Result<Self>;
+}
 
 pub struct Table0 { }
 
-pub struct Table1<A> { }
+pub struct Table1<A> { marker: PhantomData<A> }
+
+pub struct Table2<A, B> { marker: PhantomData<(A, B)> }
+
+pub struct Table3<A, B, C> { marker: PhantomData<(A, B, C)> }
+
+// A table whose rows are checked against T when it is opened
+pub struct Table<'a, B, T>
+    where B: Buffer<Page> + 'a
+{
+    db: &'a mut Database<B>,
+    name: String,
+    marker: PhantomData<T>,
+}
+
+impl<'a, B, T> Table<'a, B, T>
+    where B: Buffer<Page>,
+          T: RowType
+{
+    pub fn open(db: &'a mut Database<B>, name: &str) -> Result<Self> {
+        let schema = db.describe_table(name)?;
+        let types = schema.columns().iter().map(|column| column.column_type).collect::<Vec<_>>();
+        if types != T::column_types() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("table {} has columns {:?}, not {:?}", name, types, T::column_types())));
+        }
+        Ok(Table { db: db, name: name.to_owned(), marker: PhantomData })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn insert(&mut self, row: T::Row) -> Result<()> {
+        self.db.insert(&self.name, &T::into_values(row))
+    }
+
+    pub fn get<K: ColumnValue>(&self, key: K) -> Result<Option<T::Row>> {
+        match self.db.get(&self.name, &key.into_value())? {
+            Some(values) => T::from_values(values).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Returns false when no row has the key
+    pub fn delete<K: ColumnValue>(&mut self, key: K) -> Result<bool> {
+        self.db.delete(&self.name, &key.into_value())
+    }
+
+    // Rows matching every clause in primary key order
+    pub fn scan(&self, clauses: &[Where]) -> Result<Vec<T::Row>> {
+        self.db.select(&[], &self.name, clauses)?.into_iter().map(T::from_values).collect()
+    }
+}
+
+impl RowType for Table0 {
+    type Row = ();
+
+    fn column_types() -> Vec<ColumnType> {
+        vec![]
+    }
+
+    fn into_values(_: ()) -> Vec<Value> {
+        vec![]
+    }
+
+    fn from_values(_: Vec<Value>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: ColumnValue> RowType for Table1<A> {
+    type Row = (A,);
+
+    fn column_types() -> Vec<ColumnType> {
+        vec![A::column_type()]
+    }
+
+    fn into_values(row: (A,)) -> Vec<Value> {
+        vec![row.0.into_value()]
+    }
+
+    fn from_values(values: Vec<Value>) -> Result<(A,)> {
+        let mut values = values.into_iter();
+        Ok((A::from_value(next(&mut values)?)?,))
+    }
+}
+
+impl<A: ColumnValue, B: ColumnValue> RowType for Table2<A, B> {
+    type Row = (A, B);
+
+    fn column_types() -> Vec<ColumnType> {
+        vec![A::column_type(), B::column_type()]
+    }
+
+    fn into_values(row: (A, B)) -> Vec<Value> {
+        vec![row.0.into_value(), row.1.into_value()]
+    }
+
+    fn from_values(values: Vec<Value>) -> Result<(A, B)> {
+        let mut values = values.into_iter();
+        Ok((A::from_value(next(&mut values)?)?, B::from_value(next(&mut values)?)?))
+    }
+}
+
+impl<A: ColumnValue, B: ColumnValue, C: ColumnValue> RowType for Table3<A, B, C> {
+    type Row = (A, B, C);
+
+    fn column_types() -> Vec<ColumnType> {
+        vec![A::column_type(), B::column_type(), C::column_type()]
+    }
+
+    fn into_values(row: (A, B, C)) -> Vec<Value> {
+        vec![row.0.into_value(), row.1.into_value(), row.2.into_value()]
+    }
+
+    fn from_values(values: Vec<Value>) -> Result<(A, B, C)> {
+        let mut values = values.into_iter();
+        Ok((A::from_value(next(&mut values)?)?, B::from_value(next(&mut values)?)?, C::from_value(next(&mut values)?)?))
+    }
+}
+
+fn next<I: Iterator<Item = Value>>(values: &mut I) -> Result<Value> {
+    values.next().ok_or_else(|| Error::new(ErrorKind::InvalidData, "row has too few values"))
+}
+
+fn mismatch(column_type: ColumnType, value: &Value) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("expected {:?}, got {:?}", column_type, value))
+}
+
+macro_rules! column_value {
+    ($ty:ty, $column_type:ident) => {
+        impl ColumnValue for $ty {
+            fn column_type() -> ColumnType {
+                ColumnType::$column_type
+            }
+
+            fn into_value(self) -> Value {
+                Value::$column_type(self)
+            }
+
+            fn from_value(value: Value) -> Result<Self> {
+                match value {
+                    Value::$column_type(x) => Ok(x),
+                    other => Err(mismatch(ColumnType::$column_type, &other)),
+                }
+            }
+        }
+    }
+}
+
+column_value!(i64, Int);
+column_value!(u64, UInt);
+column_value!(f64, Float);
+column_value!(bool, Bool);
+column_value!(String, Text);
+column_value!(Vec<u8>, Blob);
+
+impl<T: ColumnValue> ColumnValue for Option<T> {
+    fn column_type() -> ColumnType {
+        T::column_type()
+    }
+
+    fn into_value(self) -> Value {
+        self.map_or(Value::Null, ColumnValue::into_value)
+    }
+
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+#[test]
+fn typed_table_rows() {
+    use buffer::AnonymousBuffer;
+    use table::operators::{self, where_};
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("prices", &Table3::<u64, String, Option<f64>>::schema(&["id", "item", "price"])).unwrap();
+    {
+        let mut prices = db.table::<Table3<u64, String, Option<f64>>>("prices").unwrap();
+        prices.insert((1, "tea".to_owned(), Some(2.5))).unwrap();
+        prices.insert((2, "cake".to_owned(), None)).unwrap();
+        assert_eq!(Some((1, "tea".to_owned(), Some(2.5))), prices.get(1u64).unwrap());
+        assert!(prices.get(3u64).unwrap().is_none());
+        let rows = prices.scan(&[where_(operators::is_null("price"))]).unwrap();
+        assert_eq!(vec![2], rows.iter().map(|row| row.0).collect::<Vec<_>>());
+        assert!(prices.delete(2u64).unwrap());
+        assert_eq!(1, prices.scan(&[]).unwrap().len());
+    }
+    assert_eq!(ErrorKind::InvalidInput, db.table::<Table2<u64, String>>("prices").err().unwrap().kind());
+    assert_eq!(ErrorKind::InvalidInput, db.table::<Table3<i64, String, f64>>("prices").err().unwrap().kind());
+    assert_eq!(ErrorKind::NotFound, db.table::<Table1<u64>>("missing").err().unwrap().kind());
+}
+
+#[test]
+fn derived_record_rows() {
+    use buffer::AnonymousBuffer;
+    use Record;
+
+    #[derive(Record, Debug, PartialEq)]
+    #[ozone(table = "people")]
+    struct Person {
+        name: String,
+        #[ozone(primary)]
+        id: u64,
+        email: Option<String>,
+    }
+
+    #[derive(Record)]
+    struct StockPrice {
+        ticker: String,
+        price: f64,
+    }
 
-pub struct Table2<A, B> { }
+    assert_eq!("people", Person::table_name());
+    assert_eq!("stock_price", StockPrice::table_name());
+    assert_eq!(Some("id"), Person::schema().primary_column());
+    assert_eq!(Some("ticker"), StockPrice::schema().primary_column());
 
-pub struct Table3<A, B, C> { }
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table_for::<Person>().unwrap();
+    let ada = Person { name: "Ada".to_owned(), id: 7, email: None };
+    db.insert_record(&ada).unwrap();
+    assert_eq!(Some(ada), db.get_record::<Person, _>(7u64).unwrap());
+    assert_eq!(None, db.get_record::<Person, _>(8u64).unwrap());
+    assert_eq!(ErrorKind::NotFound, db.get_record::<StockPrice, _>("x".to_owned()).err().unwrap().kind());
+}
 
+#[test]
+fn typed_rows_keep_long_text() {
+    use buffer::AnonymousBuffer;
+    let mut db = Database::<AnonymousBuffer<Page>>::new();
+    db.create_table("notes", &Table2::<String, String>::schema(&["title", "body"])).unwrap();
+    let mut notes = db.table::<Table2<String, String>>("notes").unwrap();
+    let title = |tail: &str| format!("{}{}", "t".repeat(200), tail);
+    notes.insert((title("a"), "b".repeat(3000))).unwrap();
+    notes.insert((title("b"), "short".to_owned())).unwrap();
+    assert_eq!(Some((title("a"), "b".repeat(3000))), notes.get(title("a")).unwrap());
+    assert_eq!(Some((title("b"), "short".to_owned())), notes.get(title("b")).unwrap());
+    assert_eq!(None, notes.get(title("c")).unwrap());
+}
@@ -0,0 +1,253 @@
+use std::cmp::Ordering;
+use std::io::{Result, Error, ErrorKind};
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColumnType {
+    Int = 1,        // i64
+    UInt = 2,       // u64
+    Float = 3,      // f64
+    Bool = 4,
+    Text = 5,       // UTF-8
+    Blob = 6,
+    Timestamp = 7,  // Microseconds since the Unix epoch
+}
+
+impl ColumnType {
+    pub fn from_tag(tag: u8) -> Option<ColumnType> {
+        match tag {
+            1 => Some(ColumnType::Int),
+            2 => Some(ColumnType::UInt),
+            3 => Some(ColumnType::Float),
+            4 => Some(ColumnType::Bool),
+            5 => Some(ColumnType::Text),
+            6 => Some(ColumnType::Blob),
+            7 => Some(ColumnType::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+    Blob(Vec<u8>),
+    Timestamp(i64),
+}
+
+/* Value Encoding
+ * ------------
+ * tag       u8    0 for Null, otherwise the ColumnType
+ * payload   [u8]  Little-endian integer or float, one byte bool, raw text or blob
+ */
+
+impl Value {
+    pub fn column_type(&self) -> Option<ColumnType> {
+        match *self {
+            Value::Null => None,
+            Value::Int(_) => Some(ColumnType::Int),
+            Value::UInt(_) => Some(ColumnType::UInt),
+            Value::Float(_) => Some(ColumnType::Float),
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Text(_) => Some(ColumnType::Text),
+            Value::Blob(_) => Some(ColumnType::Blob),
+            Value::Timestamp(_) => Some(ColumnType::Timestamp),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        match *self {
+            Value::Null => true,
+            _ => false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.column_type().map_or(0, |ty| ty as u8)];
+        match *self {
+            Value::Null => {}
+            Value::Int(x) | Value::Timestamp(x) => bytes.extend_from_slice(&x.to_le_bytes()),
+            Value::UInt(x) => bytes.extend_from_slice(&x.to_le_bytes()),
+            Value::Float(x) => bytes.extend_from_slice(&x.to_bits().to_le_bytes()),
+            Value::Bool(x) => bytes.push(x as u8),
+            Value::Text(ref x) => bytes.extend_from_slice(x.as_bytes()),
+            Value::Blob(ref x) => bytes.extend_from_slice(x),
+        }
+        bytes
+    }
+
+    // Bytes that sort like the values themselves. Text and blobs escape 0x00 and
+    // 0x01 as 0x01 0x01 and 0x01 0x02, so zero padding ends them and sorts first.
+    pub fn key_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.column_type().map_or(0, |ty| ty as u8)];
+        match *self {
+            Value::Null => {}
+            Value::Int(x) | Value::Timestamp(x) => bytes.extend_from_slice(&((x as u64) ^ (1 << 63)).to_be_bytes()),
+            Value::UInt(x) => bytes.extend_from_slice(&x.to_be_bytes()),
+            Value::Float(x) => {
+                let bits = x.to_bits();
+                let bits = if bits >> 63 == 1 { !bits } else { bits | (1 << 63) };
+                bytes.extend_from_slice(&bits.to_be_bytes());
+            }
+            Value::Bool(x) => bytes.push(x as u8),
+            Value::Text(ref x) => escape(x.as_bytes(), &mut bytes),
+            Value::Blob(ref x) => escape(x, &mut bytes),
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Value> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "value is corrupt");
+        let word = |payload: &[u8]| -> Result<[u8; 8]> {
+            if payload.len() != 8 {
+                return Err(invalid());
+            }
+            let mut array = [0u8; 8];
+            array.copy_from_slice(payload);
+            Ok(array)
+        };
+        let (&tag, payload) = bytes.split_first().ok_or_else(invalid)?;
+        if tag == 0 {
+            return if payload.is_empty() { Ok(Value::Null) } else { Err(invalid()) };
+        }
+        match ColumnType::from_tag(tag).ok_or_else(invalid)? {
+            ColumnType::Int => Ok(Value::Int(i64::from_le_bytes(word(payload)?))),
+            ColumnType::UInt => Ok(Value::UInt(u64::from_le_bytes(word(payload)?))),
+            ColumnType::Float => Ok(Value::Float(f64::from_bits(u64::from_le_bytes(word(payload)?)))),
+            ColumnType::Bool => match *payload {
+                [0] => Ok(Value::Bool(false)),
+                [1] => Ok(Value::Bool(true)),
+                _ => Err(invalid()),
+            },
+            ColumnType::Text => String::from_utf8(payload.to_vec()).map(Value::Text).map_err(|_| invalid()),
+            ColumnType::Blob => Ok(Value::Blob(payload.to_vec())),
+            ColumnType::Timestamp => Ok(Value::Timestamp(i64::from_le_bytes(word(payload)?))),
+        }
+    }
+}
+
+fn escape(from: &[u8], to: &mut Vec<u8>) {
+    for &byte in from {
+        match byte {
+            0 => to.extend_from_slice(&[1, 1]),
+            1 => to.extend_from_slice(&[1, 2]),
+            _ => to.push(byte),
+        }
+    }
+}
+
+// Null sorts first, then values of different types by type, then by value; floats use the IEEE total order
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (&Value::Int(ref a), &Value::Int(ref b)) => a.cmp(b),
+            (&Value::UInt(ref a), &Value::UInt(ref b)) => a.cmp(b),
+            (&Value::Float(ref a), &Value::Float(ref b)) => a.total_cmp(b),
+            (&Value::Bool(ref a), &Value::Bool(ref b)) => a.cmp(b),
+            (&Value::Text(ref a), &Value::Text(ref b)) => a.cmp(b),
+            (&Value::Blob(ref a), &Value::Blob(ref b)) => a.cmp(b),
+            (&Value::Timestamp(ref a), &Value::Timestamp(ref b)) => a.cmp(b),
+            _ => self.column_type().map(|ty| ty as u8).cmp(&other.column_type().map(|ty| ty as u8)),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl From<i64> for Value {
+    fn from(x: i64) -> Self {
+        Value::Int(x)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(x: u64) -> Self {
+        Value::UInt(x)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(x: bool) -> Self {
+        Value::Bool(x)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(x: &'a str) -> Self {
+        Value::Text(x.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(x: String) -> Self {
+        Value::Text(x)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(x: Vec<u8>) -> Self {
+        Value::Blob(x)
+    }
+}
+
+#[test]
+fn encode_and_decode_values() {
+    let values = vec![
+        Value::Null, Value::Int(-3), Value::UInt(7), Value::Float(1.5), Value::Bool(true),
+        Value::from("text"), Value::Blob(vec![0, 1]), Value::Timestamp(1_000_000),
+    ];
+    for value in values {
+        assert_eq!(value, Value::decode(&value.encode()).unwrap());
+    }
+    assert!(Value::decode(&[9]).is_err());
+    assert!(Value::decode(&[ColumnType::Int as u8, 1, 2]).is_err());
+}
+
+#[test]
+fn order_values() {
+    assert!(Value::Null < Value::Int(i64::MIN));
+    assert!(Value::Int(-1) < Value::Int(1));
+    assert!(Value::Float(-0.0) < Value::Float(0.0));
+    assert!(Value::from("abc") < Value::from("abd"));
+    assert_eq!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+}
+
+#[test]
+fn key_bytes_preserve_order() {
+    let values = vec![
+        Value::Null, Value::Int(-5), Value::Int(0), Value::Int(5), Value::UInt(0), Value::UInt(300),
+        Value::Float(-2.5), Value::Float(-0.0), Value::Float(0.0), Value::Float(1e10),
+        Value::from(""), Value::from("a"), Value::from("a\u{0}"), Value::from("a\u{1}"), Value::from("a\u{2}"), Value::from("b"),
+        Value::Blob(vec![0]), Value::Blob(vec![0, 0]), Value::Timestamp(-1), Value::Timestamp(1),
+    ];
+    for pair in values.windows(2) {
+        let mut a = pair[0].key_bytes();
+        let mut b = pair[1].key_bytes();
+        a.resize(16, 0);
+        b.resize(16, 0);
+        assert!(pair[0] < pair[1] && a < b, "{:?} {:?}", pair[0], pair[1]);
+    }
+}
@@ -0,0 +1,240 @@
+
+use std::io::{Result, Error, ErrorKind, Read, Write, Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+const RECORD_MAGIC: u32 = 0x4f5a574c; // "OZWL"
+const HEADER_SIZE: usize = 20;
+const CHECKPOINT_SIZE: u64 = 4 << 20; // Log bytes tolerated before committed pages are flushed and the log cut
+
+/* Record Layout
+ * ------------
+ * magic     u32   "OZWL"
+ * length    u32   Payload length in bytes
+ * lsn       u64   Log sequence number
+ * checksum  u32   CRC-32 over lsn, length and payload
+ * payload   [u8]
+ *
+ * All integers are little-endian. Replay stops at the first record that is
+ * short or fails its checksum, which is where a crash tore the log.
+ */
+
+pub struct Wal {
+    file: File,
+    pending: Vec<u8>,
+    lsn: u64,
+    logged: u64, // Bytes committed since the last checkpoint
+}
+
+impl Wal {
+    pub fn beside<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let mut wal_path = path.as_ref().as_os_str().to_owned();
+        wal_path.push(".wal");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false) // Whatever is already logged is replayed on open
+            .open(&wal_path)?;
+        let logged = file.metadata()?.len();
+        Ok(Wal { file: file, pending: Vec::new(), lsn: 0, logged: logged })
+    }
+
+    // Queue a record; nothing is durable until the next commit
+    pub fn append(&mut self, payload: &[u8]) -> u64 {
+        self.lsn += 1;
+        let mut checked = Vec::with_capacity(payload.len() + 12);
+        checked.extend_from_slice(&self.lsn.to_le_bytes());
+        checked.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        checked.extend_from_slice(payload);
+
+        self.pending.extend_from_slice(&RECORD_MAGIC.to_le_bytes());
+        self.pending.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.pending.extend_from_slice(&self.lsn.to_le_bytes());
+        self.pending.extend_from_slice(&crc32(&checked).to_le_bytes());
+        self.pending.extend_from_slice(payload);
+        self.lsn
+    }
+
+    // Group commit: every record appended since the last commit shares one fsync
+    pub fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&self.pending)?;
+        self.file.sync_data()?;
+        self.logged += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    pub fn replay<F>(&mut self, mut apply: F) -> Result<usize>
+        where F: FnMut(&[u8]) -> Result<()>
+    {
+        let mut log = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut log)?;
+
+        let mut count = 0;
+        let mut cursor = 0;
+        while log.len() - cursor >= HEADER_SIZE {
+            let magic = read_u32(&log, cursor);
+            let length = read_u32(&log, cursor + 4) as usize;
+            let lsn = read_u64(&log, cursor + 8);
+            let checksum = read_u32(&log, cursor + 16);
+            if magic != RECORD_MAGIC || log.len() - cursor - HEADER_SIZE < length {
+                break;
+            }
+            let payload = &log[(cursor + HEADER_SIZE)..(cursor + HEADER_SIZE + length)];
+            let mut checked = Vec::with_capacity(length + 12);
+            checked.extend_from_slice(&lsn.to_le_bytes());
+            checked.extend_from_slice(&(length as u32).to_le_bytes());
+            checked.extend_from_slice(payload);
+            if crc32(&checked) != checksum {
+                break;
+            }
+            apply(payload)?;
+            self.lsn = lsn;
+            count += 1;
+            cursor += HEADER_SIZE + length;
+        }
+        Ok(count)
+    }
+
+    // Committed records are already durable, so the main file only has to catch up now and then
+    pub fn needs_checkpoint(&self) -> bool {
+        self.logged >= CHECKPOINT_SIZE
+    }
+
+    // Call once the main file holding every committed record has been flushed
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.pending.clear();
+        self.file.set_len(0)?;
+        self.logged = 0;
+        self.file.sync_data()
+    }
+}
+
+/* Page Image Payload
+ * ------------
+ * size      u64   Element/page count of the file after the record
+ * count     u64   Number of images
+ * index     u64   Page index            } repeated
+ * length    u64   Image length in bytes }
+ * image     [u8]  Page after-image      }
+ */
+
+pub fn encode_images(size: usize, images: &[(usize, &[u8])]) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend_from_slice(&(size as u64).to_le_bytes());
+    record.extend_from_slice(&(images.len() as u64).to_le_bytes());
+    for &(index, image) in images {
+        record.extend_from_slice(&(index as u64).to_le_bytes());
+        record.extend_from_slice(&(image.len() as u64).to_le_bytes());
+        record.extend_from_slice(image);
+    }
+    record
+}
+
+/// Page index and after-image pairs, in the order they were logged
+pub type Images = Vec<(usize, Vec<u8>)>;
+
+pub fn decode_images(record: &[u8]) -> Result<(usize, Images)> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "malformed page image record");
+    if record.len() < 16 {
+        return Err(invalid());
+    }
+    let size = read_u64(record, 0) as usize;
+    let count = read_u64(record, 8) as usize;
+    let mut cursor = 16;
+    let mut images = Vec::new();
+    for _ in 0..count {
+        if record.len() - cursor < 16 {
+            return Err(invalid());
+        }
+        let index = read_u64(record, cursor) as usize;
+        let length = read_u64(record, cursor + 8) as usize;
+        cursor += 16;
+        if record.len() - cursor < length {
+            return Err(invalid());
+        }
+        images.push((index, record[cursor..(cursor + length)].to_vec()));
+        cursor += length;
+    }
+    Ok((size, images))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut array = [0u8; 4];
+    array.copy_from_slice(&bytes[offset..(offset + 4)]);
+    u32::from_le_bytes(array)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[offset..(offset + 8)]);
+    u64::from_le_bytes(array)
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB88320u32 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn crc32_check_value() {
+    assert_eq!(0xCBF43926, crc32(b"123456789"));
+}
+
+#[test]
+fn append_commit_and_replay_wal() {
+    {
+        let mut wal = Wal::beside("test_replay_wal.db").unwrap();
+        wal.checkpoint().unwrap();
+        wal.append(b"first");
+        wal.append(b"second");
+        wal.commit().unwrap();
+        wal.append(b"never committed");
+    }
+    let mut wal = Wal::beside("test_replay_wal.db").unwrap();
+    let mut records = Vec::new();
+    assert_eq!(2, wal.replay(|r| { records.push(r.to_vec()); Ok(()) }).unwrap());
+    assert_eq!(vec![b"first".to_vec(), b"second".to_vec()], records);
+    wal.checkpoint().unwrap();
+    assert_eq!(0, wal.replay(|_| Ok(())).unwrap());
+    ::std::fs::remove_file("test_replay_wal.db.wal").unwrap();
+}
+
+#[test]
+fn torn_record_stops_replay_wal() {
+    let mut wal = Wal::beside("test_torn_wal.db").unwrap();
+    wal.checkpoint().unwrap();
+    wal.append(b"intact");
+    wal.append(b"torn at the tail");
+    wal.commit().unwrap();
+    let len = wal.file.metadata().unwrap().len();
+    wal.file.set_len(len - 3).unwrap();
+    let mut records = Vec::new();
+    assert_eq!(1, wal.replay(|r| { records.push(r.to_vec()); Ok(()) }).unwrap());
+    assert_eq!(vec![b"intact".to_vec()], records);
+    ::std::fs::remove_file("test_torn_wal.db.wal").unwrap();
+}
+
+#[test]
+fn encode_and_decode_images() {
+    let record = encode_images(8, &[(0, b"page zero"), (3, b"page three")]);
+    let (size, images) = decode_images(&record).unwrap();
+    assert_eq!(8, size);
+    assert_eq!(vec![(0, b"page zero".to_vec()), (3, b"page three".to_vec())], images);
+    assert!(decode_images(&record[..record.len() - 1]).is_err());
+}